@@ -0,0 +1,292 @@
+use crate::client_cert::ClientCert;
+use crate::http::{under, Method, Request};
+use std::net::IpAddr;
+
+// An IPv4 or IPv6 network, e.g. 10.0.0.0/8 or fd00::/8. A bare address is a
+// network of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Option<Cidr> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= bits)?,
+            None => bits,
+        };
+        Some(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual stack listener show up as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                same_prefix(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                same_prefix(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn same_prefix(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    prefix == 0 || (net ^ ip) >> (bits - prefix) == 0
+}
+
+// Deny wins over allow, and an empty allow list lets everyone else in
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpList {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl IpList {
+    // Unix socket clients have no address, the socket file's permissions are
+    // what keeps them out
+    pub fn permits(&self, ip: Option<IpAddr>) -> bool {
+        let Some(ip) = ip else {
+            return true;
+        };
+        !self.deny.iter().any(|net| net.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+    }
+}
+
+// A path and everything under it, optionally only for some methods. Where
+// several scopes cover a request the one with the longest path wins, then the
+// one naming its method.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scope {
+    pub path: String,
+    // Empty for every method
+    pub methods: Vec<Method>,
+}
+
+impl Scope {
+    pub fn new(path: &str) -> Option<Scope> {
+        if !path.starts_with('/') {
+            return None;
+        }
+        Some(Scope {
+            path: path.trim_end_matches('/').to_owned(),
+            methods: Vec::new(),
+        })
+    }
+
+    pub fn add_method(&mut self, method: &str) -> Option<()> {
+        self.methods.push(served_method(method)?);
+        Some(())
+    }
+
+    pub fn covers(&self, req: &Request) -> bool {
+        under(&req.path, &self.path)
+            && (self.methods.is_empty() || self.methods.contains(&req.method))
+    }
+
+    pub fn most_specific<'a, T>(
+        items: &'a [T],
+        req: &Request,
+        scope: impl Fn(&T) -> &Scope,
+    ) -> Option<&'a T> {
+        items
+            .iter()
+            .filter(|item| scope(item).covers(req))
+            .max_by_key(|item| (scope(item).path.len(), !scope(item).methods.is_empty()))
+    }
+}
+
+// Which addresses, and which client certificates, may use part of the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessRule {
+    pub scope: Scope,
+    pub ips: IpList,
+    // Subject attributes or alternative names, e.g. CN=ci-uploader, one of
+    // which the client's certificate has to have. Empty for no certificate
+    // needed.
+    pub certs: Vec<String>,
+}
+
+impl AccessRule {
+    // From PATH:method=M,allow=CIDR,deny=CIDR,cert=NAME,... where each option
+    // can be repeated
+    pub fn parse(spec: &str) -> Option<AccessRule> {
+        let (path, options) = spec.split_once(':')?;
+        let mut rule = AccessRule {
+            scope: Scope::new(path)?,
+            ips: IpList::default(),
+            certs: Vec::new(),
+        };
+        for option in options.split(',') {
+            let (option, val) = option.split_once('=')?;
+            match option.trim() {
+                "method" => rule.scope.add_method(val.trim())?,
+                "allow" => rule.ips.allow.push(Cidr::parse(val)?),
+                "deny" => rule.ips.deny.push(Cidr::parse(val)?),
+                "cert" if ClientCert::valid_name(val.trim()) => {
+                    rule.certs.push(val.trim().to_owned())
+                }
+                _ => return None,
+            }
+        }
+        Some(rule)
+    }
+
+    // Every rule covering the request has to let it through, so a rule for a
+    // path further down can only narrow what the ones above it allow.
+    // Requests no rule covers are let through.
+    pub fn permits(rules: &[AccessRule], req: &Request) -> bool {
+        rules
+            .iter()
+            .filter(|rule| rule.scope.covers(req))
+            .all(|rule| rule.ips.permits(req.client) && rule.cert_permits(req.client_cert.as_ref()))
+    }
+
+    fn cert_permits(&self, cert: Option<&ClientCert>) -> bool {
+        if self.certs.is_empty() {
+            return true;
+        }
+        cert.is_some_and(|cert| self.certs.iter().any(|name| cert.matches(name)))
+    }
+}
+
+// Only the methods we actually serve can be named in rules
+pub fn served_method(method: &str) -> Option<Method> {
+    match Method::from(Some(method)) {
+        method @ (Method::Get | Method::Post) => Some(method),
+        _ => None,
+    }
+}
+
+// A comma separated list of networks
+pub fn parse_cidrs(s: &str) -> Option<Vec<Cidr>> {
+    s.split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(Cidr::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod access {
+        use crate::access::{AccessRule, Cidr, IpList};
+        use crate::client_cert::ClientCert;
+        use crate::http::Request;
+        use std::io::BufReader;
+        use std::net::IpAddr;
+
+        fn ip(s: &str) -> IpAddr {
+            s.parse().unwrap()
+        }
+
+        #[test]
+        fn matches_networks() {
+            let v4 = Cidr::parse("10.1.0.0/16").unwrap();
+            assert!(v4.contains(ip("10.1.200.3")));
+            assert!(!v4.contains(ip("10.2.0.1")));
+            assert!(v4.contains(ip("::ffff:10.1.0.9")));
+            let v6 = Cidr::parse("fd00::/8").unwrap();
+            assert!(v6.contains(ip("fd12:3456::1")));
+            assert!(!v6.contains(ip("fe80::1")));
+            assert!(!v6.contains(ip("10.1.0.1")));
+            assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("192.0.2.1")));
+            assert!(Cidr::parse("::1").unwrap().contains(ip("::1")));
+            for bad in ["10.0.0.0/33", "fd00::/129", "10.0.0/8", "example.com"] {
+                assert_eq!(None, Cidr::parse(bad), "{}", bad);
+            }
+
+            let list = IpList {
+                allow: vec![Cidr::parse("10.0.0.0/8").unwrap()],
+                deny: vec![Cidr::parse("10.0.0.1").unwrap()],
+            };
+            assert!(list.permits(Some(ip("10.0.0.2"))));
+            assert!(!list.permits(Some(ip("10.0.0.1"))));
+            assert!(!list.permits(Some(ip("192.0.2.1"))));
+            assert!(list.permits(None));
+        }
+
+        #[test]
+        fn needs_every_covering_rule() {
+            let rules: Vec<AccessRule> = [
+                "/:deny=192.0.2.0/24",
+                "/files:method=POST,allow=10.0.0.0/8,allow=fd00::/8",
+                "/files/public:deny=10.0.0.1",
+            ]
+            .iter()
+            .map(|spec| AccessRule::parse(spec).unwrap())
+            .collect();
+            let permits = |req: &[u8], client: &str| {
+                let req = Request::try_from(&mut BufReader::new(req)).unwrap();
+                let req = Request {
+                    client: Some(ip(client)),
+                    ..req
+                };
+                AccessRule::permits(&rules, &req)
+            };
+
+            let upload = b"POST /files/a HTTP/1.1\r\n\r\n";
+            assert!(permits(upload, "10.0.0.1"));
+            assert!(permits(upload, "fd00::1"));
+            assert!(!permits(upload, "127.0.0.1"));
+            assert!(permits(b"GET /files/a HTTP/1.1\r\n\r\n", "127.0.0.1"));
+            assert!(!permits(b"GET /files/a HTTP/1.1\r\n\r\n", "192.0.2.1"));
+            // A rule further down doesn't open up what /files restricts
+            let public = b"POST /files/public/a HTTP/1.1\r\n\r\n";
+            assert!(!permits(public, "127.0.0.1"));
+            assert!(permits(public, "10.0.0.2"));
+            assert!(!permits(public, "10.0.0.1"));
+            assert!(!permits(
+                b"GET /files/public/a HTTP/1.1\r\n\r\n",
+                "192.0.2.1"
+            ));
+
+            assert_eq!(
+                None,
+                AccessRule::parse("/files:method=PUT,allow=10.0.0.0/8")
+            );
+            assert_eq!(None, AccessRule::parse("/files:allow=10.0.0.0/8;deny=::1"));
+        }
+
+        #[test]
+        fn checks_client_certificates() {
+            let rules =
+                [
+                    AccessRule::parse(
+                        "/files:method=POST,cert=CN=ci-uploader,cert=DNS:ci.internal",
+                    )
+                    .unwrap(),
+                ];
+            let permits = |req: &[u8], cert: Option<&str>| {
+                let req = Request::try_from(&mut BufReader::new(req)).unwrap();
+                let req = Request {
+                    client_cert: cert.map(|attr| ClientCert {
+                        subject: attr.to_owned(),
+                        subject_attrs: vec![attr.to_owned()],
+                        sans: Vec::new(),
+                    }),
+                    ..req
+                };
+                AccessRule::permits(&rules, &req)
+            };
+
+            let upload = b"POST /files/a HTTP/1.1\r\n\r\n";
+            assert!(permits(upload, Some("CN=ci-uploader")));
+            assert!(!permits(upload, Some("CN=someone-else")));
+            assert!(!permits(upload, None));
+            assert!(permits(b"GET /files/a HTTP/1.1\r\n\r\n", None));
+
+            assert_eq!(None, AccessRule::parse("/files:cert=ci-uploader"));
+            assert_eq!(None, AccessRule::parse("/files:cert=DNS:"));
+        }
+    }
+}
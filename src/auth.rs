@@ -0,0 +1,410 @@
+use crate::access::Scope;
+use crate::constants::REALM;
+use crate::http::{Headers, Request};
+use crate::token::Token;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::Digest;
+use sha1::Sha1;
+use sha_crypt::{sha256_crypt_b64, sha512_crypt_b64, Sha256Params, Sha512Params};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs;
+use std::path::Path;
+use subtle::ConstantTimeEq;
+
+// Checked for users who aren't in the file, so they take as long to turn
+// away as a wrong password does
+const UNKNOWN_USER: &str = "$2b$10$XpfXYPnqEw2cXNLYmVWhveQMMA7uwUz9L/KBxo0f6JfVrlTlidoOe";
+
+// The SHA-crypt rounds when the hash doesn't say, and the range it may say
+const SHA_CRYPT_ROUNDS: usize = 5000;
+const SHA_CRYPT_MIN_ROUNDS: usize = 1000;
+const SHA_CRYPT_MAX_ROUNDS: usize = 999_999_999;
+
+// The characters SHA-crypt encodes hashes with
+const CRYPT_B64: &[u8; 64] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+// A password hash from an htpasswd file
+#[derive(Clone, PartialEq)]
+enum PasswordHash {
+    // $2a$, $2b$ or $2y$, from htpasswd -B
+    Bcrypt(String),
+    // $5$ or $6$, from htpasswd -2 or -5
+    ShaCrypt {
+        sha512: bool,
+        rounds: usize,
+        salt: String,
+        hash: String,
+    },
+    // {SHA}, from htpasswd -s. There's no salt, it's only here for old files.
+    Sha1(Vec<u8>),
+}
+
+// Hashes stay out of the logs
+impl Debug for PasswordHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bcrypt(_) => write!(f, "Bcrypt"),
+            Self::ShaCrypt { sha512: false, .. } => write!(f, "Sha256Crypt"),
+            Self::ShaCrypt { sha512: true, .. } => write!(f, "Sha512Crypt"),
+            Self::Sha1(_) => write!(f, "Sha1"),
+        }
+    }
+}
+
+impl PasswordHash {
+    fn parse(s: &str) -> Option<PasswordHash> {
+        if ["$2a$", "$2b$", "$2y$"].iter().any(|p| s.starts_with(p)) {
+            s.parse::<bcrypt::HashParts>().ok()?;
+            return Some(Self::Bcrypt(s.to_owned()));
+        }
+        if let Some(digest) = s.strip_prefix("{SHA}") {
+            let digest = STANDARD.decode(digest).ok().filter(|d| d.len() == 20)?;
+            return Some(Self::Sha1(digest));
+        }
+        let (sha512, rest) = match s.strip_prefix("$5$") {
+            Some(rest) => (false, rest),
+            None => (true, s.strip_prefix("$6$")?),
+        };
+        let (rounds, rest) = match rest.strip_prefix("rounds=") {
+            Some(rest) => {
+                let (rounds, rest) = rest.split_once('$')?;
+                let rounds = rounds.parse::<usize>().ok()?;
+                (
+                    rounds.clamp(SHA_CRYPT_MIN_ROUNDS, SHA_CRYPT_MAX_ROUNDS),
+                    rest,
+                )
+            }
+            None => (SHA_CRYPT_ROUNDS, rest),
+        };
+        let (salt, hash) = rest.split_once('$')?;
+        let len = if sha512 { 86 } else { 43 };
+        if salt.len() > 16 || hash.len() != len || !hash.bytes().all(|b| CRYPT_B64.contains(&b)) {
+            return None;
+        }
+        Some(Self::ShaCrypt {
+            sha512,
+            rounds,
+            salt: salt.to_owned(),
+            hash: hash.to_owned(),
+        })
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Self::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Self::ShaCrypt {
+                sha512,
+                rounds,
+                salt,
+                hash,
+            } => {
+                let (password, salt) = (password.as_bytes(), salt.as_bytes());
+                // The rounds were clamped to the allowed range when parsing
+                let encoded = if *sha512 {
+                    Sha512Params::new(*rounds)
+                        .and_then(|params| sha512_crypt_b64(password, salt, &params))
+                } else {
+                    Sha256Params::new(*rounds)
+                        .and_then(|params| sha256_crypt_b64(password, salt, &params))
+                };
+                encoded.is_ok_and(|encoded| encoded.as_bytes().ct_eq(hash.as_bytes()).into())
+            }
+            Self::Sha1(digest) => Sha1::digest(password).as_slice().ct_eq(digest).into(),
+        }
+    }
+}
+
+// Users and their password hashes, from an htpasswd file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Users {
+    hashes: HashMap<String, PasswordHash>,
+}
+
+impl Users {
+    pub fn load(path: &Path) -> std::result::Result<Users, String> {
+        Users::parse(&fs::read_to_string(path).map_err(|e| e.to_string())?)
+    }
+
+    // user:hash lines, as htpasswd writes them. Blank lines and lines
+    // starting with # are skipped.
+    fn parse(contents: &str) -> std::result::Result<Users, String> {
+        let mut hashes = HashMap::new();
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, hash)) = line
+                .split_once(':')
+                .and_then(|(user, hash)| Some((user, PasswordHash::parse(hash)?)))
+            else {
+                return Err(format!(
+                    "line {}: expected user:hash with a bcrypt, SHA-crypt or {{SHA}} hash",
+                    n + 1
+                ));
+            };
+            hashes.insert(user.to_owned(), hash);
+        }
+        Ok(Users { hashes })
+    }
+
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.hashes.get(user) {
+            Some(hash) => hash.verify(password),
+            None => {
+                let _ = bcrypt::verify(password, UNKNOWN_USER);
+                false
+            }
+        }
+    }
+}
+
+// Where logging in is needed. Where several rules cover a request the most
+// specific one wins, the same as for access rules.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthRule {
+    pub scope: Scope,
+    // Sent with the challenge, browsers show it when asking for a login
+    pub realm: String,
+    // Anyone in the users file when empty
+    pub users: Vec<String>,
+    // For leaving part of a protected path open
+    pub anonymous: bool,
+}
+
+// What a request's credentials got it
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    // Nothing asked for them
+    Open,
+    Allowed(String),
+    // Missing or wrong
+    Challenge(Challenge),
+    // Right, but for someone the rule or token doesn't let in
+    Denied(String),
+}
+
+// Sent in WWW-Authenticate with a 401
+#[derive(Debug, PartialEq)]
+pub enum Challenge {
+    // Log in to the realm
+    Basic(String),
+    InvalidToken,
+}
+
+impl Display for Challenge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Basic(realm) => write!(f, "Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+            Self::InvalidToken => write!(f, "Bearer error=\"invalid_token\""),
+        }
+    }
+}
+
+enum Credentials {
+    Basic(String, String),
+    Bearer(String),
+}
+
+impl AuthRule {
+    // From PATH or PATH:method=M,realm=NAME,user=NAME,anonymous=BOOL, where
+    // method and user can be repeated
+    pub fn parse(spec: &str) -> Option<AuthRule> {
+        let (path, options) = spec.split_once(':').unwrap_or((spec, ""));
+        let mut rule = AuthRule {
+            scope: Scope::new(path)?,
+            realm: REALM.to_owned(),
+            users: Vec::new(),
+            anonymous: false,
+        };
+        for option in options.split(',').filter(|o| !o.trim().is_empty()) {
+            let (option, val) = option.split_once('=')?;
+            let val = val.trim();
+            match option.trim() {
+                "method" => rule.scope.add_method(val)?,
+                // Goes out in a quoted string
+                "realm"
+                    if val.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+                        && !val.contains(['"', '\\']) =>
+                {
+                    rule.realm = val.to_owned()
+                }
+                "user" if !val.is_empty() => rule.users.push(val.to_owned()),
+                "anonymous" => rule.anonymous = val.parse().ok()?,
+                _ => return None,
+            }
+        }
+        Some(rule)
+    }
+
+    // The rule the request has to log in for, if any
+    pub fn required<'a>(rules: &'a [AuthRule], req: &Request) -> Option<&'a AuthRule> {
+        Scope::most_specific(rules, req, |rule| &rule.scope).filter(|rule| !rule.anonymous)
+    }
+
+    // Passwords are only checked where a rule asks for them, but a bearer
+    // token is held to its scope wherever it's used
+    pub fn check(rules: &[AuthRule], users: &Users, tokens: &[Token], req: &Request) -> Verdict {
+        let rule = AuthRule::required(rules, req);
+        let user = match (credentials(req), rule) {
+            (Some(Credentials::Bearer(secret)), _) => match Token::find(tokens, &secret) {
+                None => return Verdict::Challenge(Challenge::InvalidToken),
+                Some(token) if !token.permits(req) => return Verdict::Denied(token.name.clone()),
+                Some(token) => token.name.clone(),
+            },
+            (_, None) => return Verdict::Open,
+            (Some(Credentials::Basic(user, password)), Some(_))
+                if users.verify(&user, &password) =>
+            {
+                user
+            }
+            (_, Some(rule)) => return Verdict::Challenge(Challenge::Basic(rule.realm.clone())),
+        };
+        match rule {
+            Some(rule) if !rule.users.is_empty() && !rule.users.contains(&user) => {
+                Verdict::Denied(user)
+            }
+            _ => Verdict::Allowed(user),
+        }
+    }
+}
+
+// From an Authorization: Basic or Bearer header
+fn credentials(req: &Request) -> Option<Credentials> {
+    let (scheme, token) = req
+        .get_header(Headers::Authorization)?
+        .trim()
+        .split_once(' ')?;
+    let token = token.trim();
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Some(Credentials::Bearer(token.to_owned()));
+    }
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(token).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some(Credentials::Basic(user.to_owned(), password.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod auth {
+        use crate::auth::{AuthRule, Challenge, Users, Verdict};
+        use crate::http::Request;
+        use crate::token::Token;
+        use std::io::BufReader;
+
+        #[test]
+        fn verifies_htpasswd_hashes() {
+            // The SHA-crypt hashes are from openssl passwd -5 and -6
+            let bcrypt = bcrypt::hash("bcrypt pass", 4).unwrap();
+            let users = Users::parse(&format!(
+                "# comment\n\
+                 alice:{}\n\
+                 bob:$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5\n\
+                 carol:$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1\n\
+                 dave:$5$rounds=10000$saltstringsaltst$3xv.VbSHBb41AL9AvLeujZkZRBAwqFMz2.opqey6IcA\n\
+                 \n\
+                 erin:{{SHA}}qUqP5cyxm6YcTAhz05Hph5gvu9M=\n",
+                bcrypt
+            ))
+            .unwrap();
+            assert!(users.verify("alice", "bcrypt pass"));
+            assert!(!users.verify("alice", "bcrypt pas"));
+            for user in ["bob", "carol", "dave"] {
+                assert!(users.verify(user, "Hello world!"), "{}", user);
+                assert!(!users.verify(user, "Hello world"), "{}", user);
+            }
+            assert!(users.verify("erin", "test"));
+            assert!(!users.verify("erin", "Test"));
+            assert!(!users.verify("mallory", "test"));
+
+            for bad in [
+                "alice",
+                "alice:$apr1$salt$hash",
+                "alice:{SHA}bm90IGEgZGlnZXN0",
+                "alice:$5$saltstring$tooshort",
+            ] {
+                assert_eq!(
+                    Err("line 1: expected user:hash with a bcrypt, SHA-crypt or {SHA} hash"),
+                    Users::parse(bad)
+                        .as_ref()
+                        .map(|_| ())
+                        .map_err(String::as_str),
+                    "{}",
+                    bad
+                );
+            }
+        }
+
+        #[test]
+        fn checks_credentials() {
+            let users = Users::parse("erin:{SHA}qUqP5cyxm6YcTAhz05Hph5gvu9M=\n").unwrap();
+            let rules: Vec<AuthRule> = [
+                "/files:method=POST,realm=Uploads,user=frank",
+                "/files/inbox:method=POST,user=erin",
+                "/echo",
+                "/echo/public:anonymous=true",
+            ]
+            .iter()
+            .map(|spec| AuthRule::parse(spec).unwrap())
+            .collect();
+            let tokens =
+                [Token::parse("ci:secret=0123456789abcdef,method=GET,path=/echo/*").unwrap()];
+            let check = |req: &str| {
+                let req = Request::try_from(&mut BufReader::new(req.as_bytes())).unwrap();
+                AuthRule::check(&rules, &users, &tokens, &req)
+            };
+            // erin:test
+            let login = "Authorization: basic ZXJpbjp0ZXN0\r\n";
+
+            assert_eq!(Verdict::Open, check("GET /files/a HTTP/1.1\r\n\r\n"));
+            assert_eq!(
+                Verdict::Challenge(Challenge::Basic("Uploads".to_owned())),
+                check("POST /files/a HTTP/1.1\r\n\r\n")
+            );
+            assert_eq!(
+                Verdict::Denied("erin".to_owned()),
+                check(&format!("POST /files/a HTTP/1.1\r\n{}\r\n", login))
+            );
+            assert_eq!(
+                Verdict::Allowed("erin".to_owned()),
+                check(&format!("POST /files/inbox/a HTTP/1.1\r\n{}\r\n", login))
+            );
+            assert_eq!(
+                Verdict::Challenge(Challenge::Basic("http-server-rust".to_owned())),
+                check("GET /echo/a HTTP/1.1\r\nAuthorization: Basic ZXJpbjpURVNU\r\n\r\n")
+            );
+            assert_eq!(Verdict::Open, check("GET /echo/public/a HTTP/1.1\r\n\r\n"));
+
+            // Tokens log in wherever they're allowed, and are turned away
+            // anywhere else, even where no login is needed
+            let bearer = "Authorization: Bearer 0123456789abcdef\r\n";
+            assert_eq!(
+                Verdict::Allowed("ci".to_owned()),
+                check(&format!("GET /echo/a HTTP/1.1\r\n{}\r\n", bearer))
+            );
+            assert_eq!(
+                Verdict::Denied("ci".to_owned()),
+                check(&format!("GET /files/a HTTP/1.1\r\n{}\r\n", bearer))
+            );
+            assert_eq!(
+                Verdict::Challenge(Challenge::InvalidToken),
+                check("GET /files/a HTTP/1.1\r\nAuthorization: Bearer 0123456789abcdeg\r\n\r\n")
+            );
+
+            for bad in [
+                "files",
+                "/files:realm=a\"b",
+                "/files:user=",
+                "/:anonymous=yes",
+            ] {
+                assert_eq!(None, AuthRule::parse(bad), "{}", bad);
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+#[cfg(feature = "tls")]
+use std::net::IpAddr;
+
+// Who a client's certificate says they are, once it's been verified against
+// --tls_client_ca
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientCert {
+    // The whole subject, e.g. CN=ci-uploader, O=Example
+    pub subject: String,
+    // Each attribute of the subject as ATTR=VALUE, e.g. CN=ci-uploader
+    pub subject_attrs: Vec<String>,
+    // Subject alternative names as TYPE:VALUE, e.g. DNS:ci.internal,
+    // email:ci@example.com, URI:spiffe://example/ci or IP:10.0.0.1
+    pub sans: Vec<String>,
+}
+
+const SAN_TYPES: [&str; 4] = ["DNS:", "email:", "URI:", "IP:"];
+
+impl ClientCert {
+    // Whether the certificate has the subject attribute or alternative name
+    // given in the same form, e.g. CN=ci-uploader or DNS:ci.internal
+    pub fn matches(&self, name: &str) -> bool {
+        if SAN_TYPES.iter().any(|san| name.starts_with(san)) {
+            self.sans.iter().any(|san| san == name)
+        } else {
+            self.subject_attrs.iter().any(|attr| attr == name)
+        }
+    }
+
+    // Only names that could ever match are allowed in rules
+    pub fn valid_name(name: &str) -> bool {
+        if let Some(san) = SAN_TYPES.iter().find(|san| name.starts_with(*san)) {
+            return name.len() > san.len();
+        }
+        match name.split_once('=') {
+            Some((attr, val)) => {
+                !attr.is_empty()
+                    && attr.chars().all(|c| c.is_ascii_alphanumeric())
+                    && !val.is_empty()
+            }
+            None => false,
+        }
+    }
+
+    // From the DER the client sent. rustls has already checked it, so this
+    // only fails on something webpki accepts and x509-parser doesn't.
+    #[cfg(feature = "tls")]
+    pub fn from_der(der: &[u8]) -> Option<ClientCert> {
+        use x509_parser::extensions::GeneralName;
+        use x509_parser::objects::{oid2abbrev, oid_registry};
+
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let subject = cert.subject();
+        let subject_attrs = subject
+            .iter_attributes()
+            .filter_map(|attr| {
+                let name = oid2abbrev(attr.attr_type(), oid_registry())
+                    .map(str::to_owned)
+                    .unwrap_or_else(|_| attr.attr_type().to_id_string());
+                Some(format!("{}={}", name, attr.as_str().ok()?))
+            })
+            .collect();
+        let sans = match cert.subject_alternative_name().ok()? {
+            Some(ext) => ext
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(dns) => Some(format!("DNS:{}", dns)),
+                    GeneralName::RFC822Name(email) => Some(format!("email:{}", email)),
+                    GeneralName::URI(uri) => Some(format!("URI:{}", uri)),
+                    GeneralName::IPAddress(ip) => ip_from_bytes(ip).map(|ip| format!("IP:{}", ip)),
+                    _ => None,
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        Some(ClientCert {
+            subject: subject.to_string(),
+            subject_attrs,
+            sans,
+        })
+    }
+}
+
+#[cfg(feature = "tls")]
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod client_cert {
+        use crate::client_cert::ClientCert;
+
+        #[test]
+        fn matches_subject_attributes_and_alternative_names() {
+            let cert = ClientCert {
+                subject: "CN=ci-uploader, O=Example".to_owned(),
+                subject_attrs: vec!["CN=ci-uploader".to_owned(), "O=Example".to_owned()],
+                sans: vec!["DNS:ci.internal".to_owned(), "IP:10.0.0.1".to_owned()],
+            };
+            assert!(cert.matches("CN=ci-uploader"));
+            assert!(cert.matches("O=Example"));
+            assert!(cert.matches("DNS:ci.internal"));
+            assert!(cert.matches("IP:10.0.0.1"));
+            assert!(!cert.matches("CN=ci"));
+            assert!(!cert.matches("DNS:ci-uploader"));
+            // The whole subject isn't one of its attributes
+            assert!(!cert.matches("CN=ci-uploader, O=Example"));
+
+            for good in [
+                "CN=ci-uploader",
+                "OU=builds",
+                "DNS:ci.internal",
+                "URI:spiffe://a/b",
+            ] {
+                assert!(ClientCert::valid_name(good), "{}", good);
+            }
+            for bad in ["ci-uploader", "CN=", "=ci", "DNS:", "C N=x"] {
+                assert!(!ClientCert::valid_name(bad), "{}", bad);
+            }
+        }
+
+        #[cfg(feature = "tls")]
+        #[test]
+        fn reads_names_from_a_certificate() {
+            use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, SanType};
+
+            let mut params = CertificateParams::new(vec!["ci.internal".to_owned()]).unwrap();
+            params.distinguished_name = DistinguishedName::new();
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "ci-uploader");
+            params
+                .distinguished_name
+                .push(DnType::OrganizationName, "Example");
+            params
+                .subject_alt_names
+                .push(SanType::IpAddress("10.0.0.1".parse().unwrap()));
+            let cert = params.self_signed(&KeyPair::generate().unwrap()).unwrap();
+
+            let cert = ClientCert::from_der(cert.der()).unwrap();
+            assert!(cert.subject.contains("CN=ci-uploader"));
+            assert_eq!(vec!["CN=ci-uploader", "O=Example"], cert.subject_attrs);
+            assert_eq!(vec!["DNS:ci.internal", "IP:10.0.0.1"], cert.sans);
+            assert_eq!(None, ClientCert::from_der(b"not a certificate"));
+        }
+    }
+}
@@ -1,57 +1,1381 @@
+#[cfg(feature = "tls")]
+use crate::tls::Tls;
 use crate::{
-    constants::{ADDRESS, TARGET_DIR},
+    access::{parse_cidrs, AccessRule, IpList},
+    auth::{AuthRule, Users},
+    constants::{
+        ADDRESS, BODY_TIMEOUT_SECS, ENV_PREFIX, HEADER_TIMEOUT_SECS, KEEP_ALIVE_TIMEOUT_SECS,
+        MAX_BODY, MAX_HEADERS, MAX_HEADER_BYTES, MAX_PARKED, MAX_REQUEST_LINE, QUEUE_DEPTH,
+        SHUTDOWN_TIMEOUT_SECS, TARGET_DIR, THREADS, WRITE_TIMEOUT_SECS,
+    },
     dir::{Dir, FileSystemAccess},
+    errors::ConfigError,
+    http::{Limits, Method},
+    rate_limit::RateLimit,
+    server::{from_secs, Timeouts, TLS_PREFIX, UNIX_PREFIX},
+    signed_url::{Link, UrlSigner},
+    token::Token,
     Result,
 };
 use lexopt::prelude::*;
+use log::LevelFilter;
+use std::{
+    ffi::OsString,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// Every option can also be set through the environment, the variable name is
+// the option name prefixed with ENV_PREFIX. Command line arguments win over
+// the environment.
+//
+// (variable, option)
+const ENV_VARS: &[(&str, &str)] = &[
+    ("ADDRESS", "listen"),
+    ("LISTEN", "listen"),
+    ("IPV6_ONLY", "ipv6_only"),
+    ("SOCKET_MODE", "socket_mode"),
+    ("SOCKET_ACTIVATION", "socket_activation"),
+    ("DIRECTORY", "target_dir"),
+    ("THREADS", "threads"),
+    ("QUEUE_DEPTH", "queue_depth"),
+    ("SHUTDOWN_TIMEOUT", "shutdown_timeout"),
+    ("READY_FILE", "ready_file"),
+    ("ADMIN", "admin"),
+    ("LOG_LEVEL", "log_level"),
+    ("REACTOR", "reactor"),
+    ("MAX_PARKED", "max_parked"),
+    ("MAX_REQUEST_LINE", "max_request_line"),
+    ("MAX_HEADER_BYTES", "max_header_bytes"),
+    ("MAX_HEADERS", "max_headers"),
+    ("MAX_BODY", "max_body"),
+    ("MOUNT_LIMITS", "mount_limits"),
+    ("HEADER_TIMEOUT", "header_timeout"),
+    ("BODY_TIMEOUT", "body_timeout"),
+    ("WRITE_TIMEOUT", "write_timeout"),
+    ("KEEP_ALIVE_TIMEOUT", "keep_alive_timeout"),
+    ("RATE_LIMIT", "rate_limit"),
+    ("MAX_CONNECTIONS_PER_IP", "max_connections_per_ip"),
+    ("ACCESS", "access"),
+    ("ALLOW_CONNECTIONS", "allow_connections"),
+    ("DENY_CONNECTIONS", "deny_connections"),
+    ("HTPASSWD", "htpasswd"),
+    ("AUTH", "auth"),
+    ("TOKEN", "token"),
+    ("SIGNING_KEY", "signing_key"),
+    ("TLS_CERT", "tls_cert"),
+    ("TLS_KEY", "tls_key"),
+    ("TLS_VERSIONS", "tls_versions"),
+    ("TLS_CIPHERS", "tls_ciphers"),
+    ("TLS_CLIENT_CA", "tls_client_ca"),
+    ("TLS_CLIENT_AUTH", "tls_client_auth"),
+    ("CONFIG", "config"),
+];
+
+// Never shown in errors, so they don't end up in logs
+const SECRET_OPTIONS: &[&str] = &["token", "signing_key"];
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
-    pub address: String,
+    // Every address gets its own listener, all feeding the same pool
+    pub listen: Vec<String>,
+    // Whether IPv6 listeners refuse IPv4 connections. When false a listener on
+    // [::] also takes IPv4 traffic, so it can't share a port with 0.0.0.0.
+    pub ipv6_only: bool,
+    // Permissions for Unix socket files, left to the umask when None
+    pub socket_mode: Option<u32>,
+    // Whether listeners passed in through LISTEN_FDS replace listen. Off
+    // unless asked for, so an application embedding the server doesn't take
+    // over fds it knows nothing about.
+    pub socket_activation: bool,
     pub directory: Dir,
+    pub threads: usize,
+    // Connections allowed to wait for a free worker before we start turning
+    // them away with a 503
+    pub queue_depth: usize,
+    // How long shutdown waits for in-flight requests before cutting them off
+    pub shutdown_timeout: Duration,
+    // Written with the bound address once the server is accepting, handy
+    // when binding port 0
+    pub ready_file: Option<PathBuf>,
+    // Serves POST /admin/upgrade. There's no authentication on it, so only
+    // turn it on where every client is trusted.
+    pub admin: bool,
+    // Waits for requests on a single epoll thread instead of a worker per
+    // connection. Needs the reactor feature.
+    pub reactor: bool,
+    // Connections the reactor holds between requests. New ones past that get
+    // a 503 and kept alive ones are closed, so idle clients can't pile up
+    // without bound.
+    pub max_parked: usize,
+    // Request size limits, with overrides per path
+    pub limits: Limits,
+    // For slow clients, so they can't tie up a worker forever
+    pub timeouts: Timeouts,
+    // Token buckets per path, the longest matching path wins
+    pub rate_limits: Vec<RateLimit>,
+    // Connections each client address may have open at once, 0 for no limit
+    pub max_connections_per_ip: usize,
+    // Which addresses may use which paths, checked for every request
+    pub access: Vec<AccessRule>,
+    // Which addresses may connect at all, checked on accept
+    pub connections: IpList,
+    // Read from the htpasswd file whenever the config is
+    pub users: Users,
+    // Where users have to log in, and which of them may
+    pub auth: Vec<AuthRule>,
+    // Bearer tokens, each only good for some methods and paths
+    pub tokens: Vec<Token>,
+    // Checks signed links to files, and makes them with Command::Sign
+    pub signer: Option<UrlSigner>,
+    // For tls: listeners, needs the tls feature
+    #[cfg(feature = "tls")]
+    pub tls: Tls,
+    // Caps what gets logged, RUST_LOG is left in charge when None
+    pub log_level: Option<LevelFilter>,
+    // Read again on SIGHUP, see Config::reload
+    pub config_file: Option<PathBuf>,
+}
+
+// Only ever built once at startup, so Run being much bigger than the others
+// doesn't matter
+#[allow(clippy::large_enum_variant)]
+pub enum Command {
+    Run(Config),
+    // Print a signed link for the path, with the key from the config
+    Sign(Config, Link),
+    Help,
+    Version,
 }
 
 impl Config {
-    pub fn try_new() -> Result<Config> {
-        let mut parser = lexopt::Parser::from_env();
+    pub fn try_new() -> Result<Command> {
+        Config::load(|key| std::env::var_os(key), std::env::args_os().skip(1))
+    }
+
+    // Reads everything again from the same places try_new did, so a changed
+    // config file gets picked up
+    pub fn reload() -> Result<Config> {
+        match Config::try_new()? {
+            Command::Run(config) => Ok(config),
+            // Would have stopped us from ever starting
+            Command::Sign(..) | Command::Help | Command::Version => {
+                Err(ConfigError::UnexpectedArgument("sign, --help or --version".to_owned()).into())
+            }
+        }
+    }
+
+    // The config file sits under the environment and the command line, which
+    // have to be read first to find out whether there is one
+    pub fn load<F, I>(lookup: F, args: I) -> Result<Command>
+    where
+        F: Fn(&str) -> Option<OsString>,
+        I: IntoIterator,
+        I::Item: Into<OsString>,
+    {
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        match Config::from_env(&lookup)?.parse(args.clone())? {
+            Command::Run(Config {
+                config_file: Some(path),
+                ..
+            })
+            | Command::Sign(
+                Config {
+                    config_file: Some(path),
+                    ..
+                },
+                _,
+            ) => Config::from_file(&path)?.with_env(&lookup)?.parse(args),
+            command => Ok(command),
+        }
+    }
+
+    // option = value lines, with the same names as the long options. Blank
+    // lines and lines starting with # are skipped, listen can be repeated.
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let bad = |msg: String| ConfigError::BadConfigFile(path.display().to_string(), msg);
+        let contents = fs::read_to_string(path).map_err(|e| bad(e.to_string()))?;
         let mut config = Config::default();
-        while let Ok(Some(arg)) = parser.next() {
+        let mut listen = Vec::new();
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((option, val)) = line.split_once('=') else {
+                return Err(bad(format!("line {}: expected option = value", n + 1)).into());
+            };
+            let (option, val) = (option.trim(), val.trim().to_owned());
+            let res = match option {
+                "listen" | "address" => {
+                    listen.push(val);
+                    Ok(())
+                }
+                // One file is plenty
+                "config" => Err(ConfigError::UnknownFlag(option.to_owned())),
+                _ => config.set(option, val),
+            };
+            res.map_err(|e| bad(format!("line {}: {}", n + 1, e)))?;
+        }
+        if !listen.is_empty() {
+            config
+                .set("listen", listen.join(","))
+                .map_err(|e| bad(e.to_string()))?;
+        }
+        Ok(config)
+    }
+
+    // Layers the command line arguments over self, args should not include
+    // the binary name
+    pub fn parse<I>(mut self, args: I) -> Result<Command>
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString>,
+    {
+        let mut parser = lexopt::Parser::from_args(args);
+        // Repeated, and replaces rather than adds to the environment or the
+        // default
+        let mut listen = Vec::new();
+        // Only set for the sign subcommand, with the link's options
+        let mut sign: Option<Option<String>> = None;
+        let (mut method, mut expires_in, mut max_size) = (None, None, None);
+        while let Some(arg) = parser.next().map_err(ConfigError::from)? {
             match arg {
+                Value(cmd) if sign.is_none() && cmd == "sign" => sign = Some(None),
+                Value(path) if matches!(sign, Some(None)) => {
+                    sign = Some(Some(path.string().map_err(ConfigError::from)?))
+                }
+                Long("method") if sign.is_some() => method = Some(string_value(&mut parser)?),
+                Long("expires_in") if sign.is_some() => {
+                    expires_in = Some(string_value(&mut parser)?)
+                }
+                Long("max_size") if sign.is_some() => max_size = Some(string_value(&mut parser)?),
                 Short('t') | Long("target_dir") => {
-                    if let Ok(val) = parser.value() {
-                        if let Ok(parsed_val) = val.parse::<String>() {
-                            let dir = Dir::new(&format!("{}{}", TARGET_DIR, parsed_val));
-                            dir.try_create()?;
-                            config.directory = dir;
-                        }
-                    }
+                    self.set("target_dir", string_value(&mut parser)?)?
+                }
+                Short('l') | Long("listen") | Short('a') | Long("address") => {
+                    let val = string_value(&mut parser)?;
+                    listen.push(parse_address(&val).ok_or(ConfigError::InvalidAddress(val))?)
+                }
+                Long("ipv6_only") => self.set("ipv6_only", string_value(&mut parser)?)?,
+                Long("socket_activation") => {
+                    self.set("socket_activation", string_value(&mut parser)?)?
+                }
+                Long("socket_mode") => self.set("socket_mode", string_value(&mut parser)?)?,
+                Short('n') | Long("threads") => self.set("threads", string_value(&mut parser)?)?,
+                Short('q') | Long("queue_depth") => {
+                    self.set("queue_depth", string_value(&mut parser)?)?
+                }
+                Long("shutdown_timeout") => {
+                    self.set("shutdown_timeout", string_value(&mut parser)?)?
+                }
+                Long("ready_file") => self.set("ready_file", string_value(&mut parser)?)?,
+                Long("admin") => self.set("admin", string_value(&mut parser)?)?,
+                Long("reactor") => self.set("reactor", string_value(&mut parser)?)?,
+                Long("max_parked") => self.set("max_parked", string_value(&mut parser)?)?,
+                Long("max_request_line") => {
+                    self.set("max_request_line", string_value(&mut parser)?)?
                 }
-                Short('a') | Long("address") => {
-                    if let Ok(val) = parser.value() {
-                        if let Ok(parsed_val) = val.parse() {
-                            config.address = parsed_val;
-                        }
-                    }
+                Long("max_header_bytes") => {
+                    self.set("max_header_bytes", string_value(&mut parser)?)?
                 }
-                Short('h') | Long("help") => {
-                    println!("Usage: cargo run -- [-t | --target_dir=TARGET_DIR] [-a | --address=ADDRESS]");
-                    std::process::exit(0);
+                Long("max_headers") => self.set("max_headers", string_value(&mut parser)?)?,
+                Long("max_body") => self.set("max_body", string_value(&mut parser)?)?,
+                Long("mount_limits") => self.set("mount_limits", string_value(&mut parser)?)?,
+                Long("header_timeout") => self.set("header_timeout", string_value(&mut parser)?)?,
+                Long("body_timeout") => self.set("body_timeout", string_value(&mut parser)?)?,
+                Long("write_timeout") => self.set("write_timeout", string_value(&mut parser)?)?,
+                Long("keep_alive_timeout") => {
+                    self.set("keep_alive_timeout", string_value(&mut parser)?)?
                 }
-                _ => {
-                    println!("Error: cargo run -- [-h | --help] for usage");
-                    std::process::exit(1);
+                Long("rate_limit") => self.set("rate_limit", string_value(&mut parser)?)?,
+                Long("max_connections_per_ip") => {
+                    self.set("max_connections_per_ip", string_value(&mut parser)?)?
                 }
+                Long("access") => self.set("access", string_value(&mut parser)?)?,
+                Long("allow_connections") => {
+                    self.set("allow_connections", string_value(&mut parser)?)?
+                }
+                Long("deny_connections") => {
+                    self.set("deny_connections", string_value(&mut parser)?)?
+                }
+                Long("htpasswd") => self.set("htpasswd", string_value(&mut parser)?)?,
+                Long("auth") => self.set("auth", string_value(&mut parser)?)?,
+                Long("token") => self.set("token", string_value(&mut parser)?)?,
+                Long("signing_key") => self.set("signing_key", string_value(&mut parser)?)?,
+                Long("tls_cert") => self.set("tls_cert", string_value(&mut parser)?)?,
+                Long("tls_key") => self.set("tls_key", string_value(&mut parser)?)?,
+                Long("tls_versions") => self.set("tls_versions", string_value(&mut parser)?)?,
+                Long("tls_ciphers") => self.set("tls_ciphers", string_value(&mut parser)?)?,
+                Long("tls_client_ca") => self.set("tls_client_ca", string_value(&mut parser)?)?,
+                Long("tls_client_auth") => {
+                    self.set("tls_client_auth", string_value(&mut parser)?)?
+                }
+                Long("log_level") => self.set("log_level", string_value(&mut parser)?)?,
+                Short('c') | Long("config") => self.set("config", string_value(&mut parser)?)?,
+                Short('h') | Long("help") => return Ok(Command::Help),
+                Short('V') | Long("version") => return Ok(Command::Version),
+                _ => return Err(ConfigError::from(arg.unexpected()).into()),
+            }
+        }
+        if !listen.is_empty() {
+            self.set("listen", listen.join(","))?;
+        }
+        if let Some(path) = sign {
+            let link = signed_link(path, method, expires_in, max_size)?;
+            return Ok(Command::Sign(self, link));
+        }
+        self.directory
+            .try_create()
+            .map_err(|_| ConfigError::BadDirectory(self.directory.path().display().to_string()))?;
+        // Left until everything has been read, the certificate and key can
+        // come from different places
+        #[cfg(feature = "tls")]
+        if self.tls.cert.is_some()
+            || self.tls.key.is_some()
+            || self.tls.client_ca.is_some()
+            || self.listen.iter().any(|a| a.starts_with(TLS_PREFIX))
+        {
+            self.tls.build()?;
+        }
+        Ok(Command::Run(self))
+    }
+
+    pub fn usage() -> String {
+        format!(
+            "\
+Usage: http-server-rust [OPTIONS]
+       http-server-rust sign [--method=METHOD] [--expires_in=SECS] [--max_size=BYTES] [OPTIONS] PATH
+
+Sign prints a link to PATH under /files that works without logging in until it expires,
+signed with --signing_key. METHOD is GET or POST [default: GET], SECS defaults to 3600 and
+BYTES caps the size of an upload [default: none].
+
+Options:
+  -l, --listen=ADDRESS        Address to listen on, repeat for more than one, port 0 picks
+                              a free one, unix:PATH for a Unix socket, tls:ADDRESS for HTTPS
+                              with the tls feature [default: {address}]
+  -a, --address=ADDRESS       Same as --listen
+      --ipv6_only=BOOL        Keep IPv6 listeners from accepting IPv4 too [default: false]
+      --socket_mode=MODE      Octal permissions for Unix socket files [default: umask]
+      --socket_activation=BOOL
+                              Serve the listeners passed in with LISTEN_FDS instead of
+                              --listen, when there are any [default: false]
+  -t, --target_dir=DIR        Directory to serve and save files, relative to {root} [default: {root}]
+  -n, --threads=COUNT         Worker threads [default: available parallelism, {threads} here]
+  -q, --queue_depth=COUNT     Connections that may wait for a worker before getting a 503 [default: {queue_depth}]
+      --shutdown_timeout=SECS Seconds to wait for in-flight requests on shutdown [default: {shutdown_timeout}]
+      --ready_file=PATH       Write the bound address to PATH once accepting [default: none]
+      --admin=BOOL            Serve POST /admin/upgrade, unauthenticated [default: false]
+      --reactor=BOOL          Wait for requests with epoll rather than a worker per connection,
+                              needs the reactor feature [default: false]
+      --max_parked=COUNT      Connections the reactor holds between requests before new
+                              ones get a 503 [default: {max_parked}]
+      --max_request_line=BYTES
+                              Longest request line before a 414 [default: {max_request_line}]
+      --max_header_bytes=BYTES
+                              Most header bytes before a 431 [default: {max_header_bytes}]
+      --max_headers=COUNT     Most headers before a 431 [default: {max_headers}]
+      --max_body=BYTES        Largest Content-Length before a 413 [default: {max_body}]
+      --mount_limits=PATH:OPTION=VALUE,...
+                              Override max_header_bytes, max_headers or max_body for PATH
+                              and everything under it, repeat for more paths [default: none]
+      --header_timeout=SECS   Seconds a client gets to send a whole request head before a 408,
+                              0 for no limit [default: {header_timeout}]
+      --body_timeout=SECS     Seconds a request body may stall for, 0 for no limit [default: {body_timeout}]
+      --write_timeout=SECS    Seconds a response may stall for, 0 for no limit [default: {write_timeout}]
+      --keep_alive_timeout=SECS
+                              Seconds an idle persistent connection is kept open, 0 for no
+                              limit [default: {keep_alive_timeout}]
+      --rate_limit=PATH:rate=N[,burst=N][,by=ip|user]
+                              Let each client make N requests a second to PATH and everything
+                              under it, bursting to burst, before a 429. Repeat for more
+                              paths [default: none]
+      --max_connections_per_ip=COUNT
+                              Connections each client address may have open, 0 for no
+                              limit [default: 0]
+      --access=PATH:OPTION=VALUE,...
+                              Only let addresses matching allow=CIDR and not deny=CIDR, and
+                              with a client certificate matching cert=NAME if given, use PATH
+                              and everything under it, for method=M or every method. Each
+                              option can be repeated, and so can --access [default: none]
+      --allow_connections=CIDR,...
+                              Close connections from anywhere else on accept [default: any]
+      --deny_connections=CIDR,...
+                              Close connections from these networks on accept [default: none]
+      --htpasswd=PATH         Users and password hashes for Basic auth, bcrypt, SHA-crypt or
+                              {{SHA}} [default: none]
+      --auth=PATH[:OPTION=VALUE,...]
+                              Make clients log in to use PATH and everything under it, for
+                              method=M or every method. user=NAME lets in only the users named,
+                              realm=NAME sets the realm and anonymous=true leaves the path open.
+                              method and user can be repeated, and so can --auth [default: none]
+      --token=NAME:secret=SECRET,path=GLOB,...
+                              A bearer token of at least 16 characters, only good for the
+                              path globs and method=M given. method and path can be repeated,
+                              and so can --token [default: none]
+      --signing_key=KEY       Key of at least 32 characters for signed links [default: none]
+      --tls_cert=PATH         PEM certificate chain for tls: listeners, re-read on SIGHUP,
+                              needs the tls feature [default: none]
+      --tls_key=PATH          PEM private key for --tls_cert [default: none]
+      --tls_versions=VERSION,...
+                              TLS versions to accept, 1.2 and 1.3 [default: both]
+      --tls_ciphers=SUITE,... Cipher suites to accept by IANA name, e.g.
+                              TLS13_AES_256_GCM_SHA384 [default: rustls' safe defaults]
+      --tls_client_ca=PATH    PEM bundle of CAs to verify client certificates against, asking
+                              clients for one [default: none]
+      --tls_client_auth=required|optional
+                              Whether clients must send a certificate when --tls_client_ca is
+                              set [default: required]
+      --log_level=LEVEL       off, error, warn, info, debug or trace [default: from RUST_LOG]
+  -c, --config=PATH           Read options from PATH, one option = value per line. Re-read
+                              on SIGHUP [default: none]
+  -h, --help                  Print this help and exit
+  -V, --version               Print the version and exit
+
+Environment:
+  {prefix}LISTEN          Same as --listen, comma separated
+  {prefix}ADDRESS         Same as --address
+  {prefix}IPV6_ONLY       Same as --ipv6_only
+  {prefix}SOCKET_MODE     Same as --socket_mode
+  {prefix}SOCKET_ACTIVATION Same as --socket_activation
+  {prefix}DIRECTORY       Same as --target_dir
+  {prefix}THREADS         Same as --threads
+  {prefix}QUEUE_DEPTH     Same as --queue_depth
+  {prefix}SHUTDOWN_TIMEOUT Same as --shutdown_timeout
+  {prefix}READY_FILE      Same as --ready_file
+  {prefix}ADMIN           Same as --admin
+  {prefix}REACTOR         Same as --reactor
+  {prefix}MAX_PARKED      Same as --max_parked
+  {prefix}MAX_REQUEST_LINE Same as --max_request_line
+  {prefix}MAX_HEADER_BYTES Same as --max_header_bytes
+  {prefix}MAX_HEADERS     Same as --max_headers
+  {prefix}MAX_BODY        Same as --max_body
+  {prefix}MOUNT_LIMITS    Same as --mount_limits, semicolon separated
+  {prefix}HEADER_TIMEOUT  Same as --header_timeout
+  {prefix}BODY_TIMEOUT    Same as --body_timeout
+  {prefix}WRITE_TIMEOUT   Same as --write_timeout
+  {prefix}KEEP_ALIVE_TIMEOUT Same as --keep_alive_timeout
+  {prefix}RATE_LIMIT      Same as --rate_limit, semicolon separated
+  {prefix}MAX_CONNECTIONS_PER_IP Same as --max_connections_per_ip
+  {prefix}ACCESS          Same as --access, semicolon separated
+  {prefix}ALLOW_CONNECTIONS Same as --allow_connections
+  {prefix}DENY_CONNECTIONS Same as --deny_connections
+  {prefix}HTPASSWD        Same as --htpasswd
+  {prefix}AUTH            Same as --auth, semicolon separated
+  {prefix}TOKEN           Same as --token, semicolon separated
+  {prefix}SIGNING_KEY     Same as --signing_key
+  {prefix}TLS_CERT        Same as --tls_cert
+  {prefix}TLS_KEY         Same as --tls_key
+  {prefix}TLS_VERSIONS    Same as --tls_versions
+  {prefix}TLS_CIPHERS     Same as --tls_ciphers
+  {prefix}TLS_CLIENT_CA   Same as --tls_client_ca
+  {prefix}TLS_CLIENT_AUTH Same as --tls_client_auth
+  {prefix}LOG_LEVEL       Same as --log_level
+  {prefix}CONFIG          Same as --config
+
+Command line options take precedence over the environment, which takes precedence over
+the config file.
+",
+            address = ADDRESS,
+            root = TARGET_DIR,
+            prefix = ENV_PREFIX,
+            threads = default_threads(),
+            queue_depth = QUEUE_DEPTH,
+            max_parked = MAX_PARKED,
+            shutdown_timeout = SHUTDOWN_TIMEOUT_SECS,
+            max_request_line = MAX_REQUEST_LINE,
+            max_header_bytes = MAX_HEADER_BYTES,
+            max_headers = MAX_HEADERS,
+            max_body = MAX_BODY,
+            header_timeout = HEADER_TIMEOUT_SECS,
+            body_timeout = BODY_TIMEOUT_SECS,
+            write_timeout = WRITE_TIMEOUT_SECS,
+            keep_alive_timeout = KEEP_ALIVE_TIMEOUT_SECS,
+        )
+    }
+
+    pub fn version() -> String {
+        format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+    }
+
+    // Takes a lookup rather than reading the environment directly so that the
+    // tests don't have to mutate the process environment
+    pub fn from_env<F>(lookup: F) -> Result<Config>
+    where
+        F: Fn(&str) -> Option<OsString>,
+    {
+        Config::default().with_env(lookup)
+    }
+
+    fn with_env<F>(self, lookup: F) -> Result<Config>
+    where
+        F: Fn(&str) -> Option<OsString>,
+    {
+        let mut config = self;
+        for (name, option) in ENV_VARS {
+            let key = format!("{}{}", ENV_PREFIX, name);
+            if let Some(val) = lookup(&key) {
+                let val = val.into_string().map_err(|v| {
+                    let shown = match SECRET_OPTIONS.contains(option) {
+                        true => "...".to_owned(),
+                        false => v.to_string_lossy().into(),
+                    };
+                    ConfigError::InvalidEnv(key.clone(), shown)
+                })?;
+                // What set shows of the value, which leaves out secrets
+                config.set(option, val.clone()).map_err(|e| match e {
+                    ConfigError::InvalidOption(_, shown) => ConfigError::InvalidEnv(key, shown),
+                    _ => ConfigError::InvalidEnv(key, val),
+                })?;
             }
         }
         Ok(config)
     }
+
+    // Shared by the command line and the environment so both accept exactly
+    // the same values
+    fn set(&mut self, option: &str, val: String) -> std::result::Result<(), ConfigError> {
+        match option {
+            "target_dir" => self.directory = Dir::new(&format!("{}{}", TARGET_DIR, val)),
+            "listen" => {
+                self.listen = val
+                    .split(',')
+                    .map(|a| {
+                        parse_address(a.trim())
+                            .ok_or(ConfigError::InvalidAddress(a.trim().to_owned()))
+                    })
+                    .collect::<std::result::Result<Vec<_>, _>>()?
+            }
+            "socket_mode" => {
+                self.socket_mode = u32::from_str_radix(&val, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .map(Some)
+                    .ok_or_else(|| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "ipv6_only" => {
+                self.ipv6_only = val
+                    .parse::<bool>()
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "socket_activation" => {
+                self.socket_activation = val
+                    .parse::<bool>()
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "threads" => {
+                self.threads = val
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "queue_depth" => {
+                self.queue_depth = val
+                    .parse::<usize>()
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "ready_file" => self.ready_file = Some(PathBuf::from(val)),
+            "reactor" => {
+                self.reactor = val
+                    .parse::<bool>()
+                    .ok()
+                    .filter(|on| !on || cfg!(feature = "reactor"))
+                    .ok_or_else(|| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "max_parked" => {
+                self.max_parked = val
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "config" => self.config_file = Some(PathBuf::from(val)),
+            "log_level" => {
+                self.log_level = val
+                    .parse::<LevelFilter>()
+                    .map(Some)
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "admin" => {
+                self.admin = val
+                    .parse::<bool>()
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "max_request_line" => {
+                self.limits.request_line = val
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "max_header_bytes" => {
+                self.limits.header_bytes = val
+                    .parse::<usize>()
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "max_headers" => {
+                self.limits.headers = val
+                    .parse::<usize>()
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "max_body" => {
+                self.limits.body = val
+                    .parse::<u64>()
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            // Adds to rather than replaces the mounts already set, several can
+            // be given at once separated by semicolons
+            "mount_limits" => {
+                for spec in val.split(';').filter(|spec| !spec.trim().is_empty()) {
+                    self.limits
+                        .set_mount(spec.trim())
+                        .ok_or_else(|| ConfigError::InvalidOption(option.to_owned(), val.clone()))?
+                }
+            }
+            "header_timeout" => {
+                self.timeouts.header = val
+                    .parse::<u64>()
+                    .map(from_secs)
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "body_timeout" => {
+                self.timeouts.body = val
+                    .parse::<u64>()
+                    .map(from_secs)
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "write_timeout" => {
+                self.timeouts.write = val
+                    .parse::<u64>()
+                    .map(from_secs)
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "keep_alive_timeout" => {
+                self.timeouts.keep_alive = val
+                    .parse::<u64>()
+                    .map(from_secs)
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            // Like mount_limits, a path that already has a limit gets the new one
+            "rate_limit" => {
+                for spec in val.split(';').filter(|spec| !spec.trim().is_empty()) {
+                    let limit = RateLimit::parse(spec.trim()).ok_or_else(|| {
+                        ConfigError::InvalidOption(option.to_owned(), val.clone())
+                    })?;
+                    self.rate_limits.retain(|l| l.path != limit.path);
+                    self.rate_limits.push(limit);
+                }
+            }
+            // A rule for a scope that already has one replaces it
+            "access" => {
+                for spec in val.split(';').filter(|spec| !spec.trim().is_empty()) {
+                    let rule = AccessRule::parse(spec.trim()).ok_or_else(|| {
+                        ConfigError::InvalidOption(option.to_owned(), val.clone())
+                    })?;
+                    self.access.retain(|r| r.scope != rule.scope);
+                    self.access.push(rule);
+                }
+            }
+            "htpasswd" => {
+                self.users =
+                    Users::load(Path::new(&val)).map_err(|e| ConfigError::BadConfigFile(val, e))?
+            }
+            // A rule for a scope that already has one replaces it
+            "auth" => {
+                for spec in val.split(';').filter(|spec| !spec.trim().is_empty()) {
+                    let rule = AuthRule::parse(spec.trim()).ok_or_else(|| {
+                        ConfigError::InvalidOption(option.to_owned(), val.clone())
+                    })?;
+                    self.auth.retain(|r| r.scope != rule.scope);
+                    self.auth.push(rule);
+                }
+            }
+            // A token with a name that's already taken replaces it. Errors
+            // only show the name, so secrets don't end up in logs.
+            "token" => {
+                for spec in val.split(';').filter(|spec| !spec.trim().is_empty()) {
+                    let token = Token::parse(spec.trim()).ok_or_else(|| {
+                        let name = spec.split(':').next().unwrap_or_default().trim();
+                        ConfigError::InvalidOption(option.to_owned(), format!("{}:...", name))
+                    })?;
+                    self.tokens.retain(|t| t.name != token.name);
+                    self.tokens.push(token);
+                }
+            }
+            // Not shown in errors, so it doesn't end up in logs
+            "signing_key" => {
+                self.signer = Some(UrlSigner::new(&val).ok_or_else(|| {
+                    ConfigError::InvalidOption(option.to_owned(), "...".to_owned())
+                })?)
+            }
+            #[cfg(feature = "tls")]
+            "tls_cert" => self.tls.cert = Some(PathBuf::from(val)),
+            #[cfg(feature = "tls")]
+            "tls_key" => self.tls.key = Some(PathBuf::from(val)),
+            #[cfg(feature = "tls")]
+            "tls_versions" => self
+                .tls
+                .set_versions(&val)
+                .ok_or_else(|| ConfigError::InvalidOption(option.to_owned(), val))?,
+            #[cfg(feature = "tls")]
+            "tls_ciphers" => self
+                .tls
+                .set_ciphers(&val)
+                .ok_or_else(|| ConfigError::InvalidOption(option.to_owned(), val))?,
+            #[cfg(feature = "tls")]
+            "tls_client_ca" => self.tls.client_ca = Some(PathBuf::from(val)),
+            #[cfg(feature = "tls")]
+            "tls_client_auth" => self
+                .tls
+                .set_client_auth(&val)
+                .ok_or_else(|| ConfigError::InvalidOption(option.to_owned(), val))?,
+            // Known, but there's nothing to use them without the tls feature
+            #[cfg(not(feature = "tls"))]
+            "tls_cert" | "tls_key" | "tls_versions" | "tls_ciphers" | "tls_client_ca"
+            | "tls_client_auth" => return Err(ConfigError::InvalidOption(option.to_owned(), val)),
+            "allow_connections" => {
+                self.connections.allow = parse_cidrs(&val)
+                    .ok_or_else(|| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "deny_connections" => {
+                self.connections.deny = parse_cidrs(&val)
+                    .ok_or_else(|| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "max_connections_per_ip" => {
+                self.max_connections_per_ip = val
+                    .parse::<usize>()
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            "shutdown_timeout" => {
+                self.shutdown_timeout = val
+                    .parse::<u64>()
+                    .map(Duration::from_secs)
+                    .map_err(|_| ConfigError::InvalidOption(option.to_owned(), val))?
+            }
+            _ => return Err(ConfigError::UnknownFlag(option.to_owned())),
+        }
+        Ok(())
+    }
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
-            address: ADDRESS.to_owned(),
+            listen: vec![ADDRESS.to_owned()],
+            ipv6_only: false,
+            socket_mode: None,
+            socket_activation: false,
             directory: Dir::default(),
+            threads: default_threads(),
+            queue_depth: QUEUE_DEPTH,
+            shutdown_timeout: Duration::from_secs(SHUTDOWN_TIMEOUT_SECS),
+            ready_file: None,
+            admin: false,
+            reactor: false,
+            max_parked: MAX_PARKED,
+            limits: Limits::default(),
+            timeouts: Timeouts::default(),
+            rate_limits: Vec::new(),
+            max_connections_per_ip: 0,
+            access: Vec::new(),
+            connections: IpList::default(),
+            users: Users::default(),
+            auth: Vec::new(),
+            tokens: Vec::new(),
+            signer: None,
+            #[cfg(feature = "tls")]
+            tls: Tls::default(),
+            log_level: None,
+            config_file: None,
+        }
+    }
+}
+
+fn default_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(THREADS)
+}
+
+// The link the sign subcommand was asked for
+fn signed_link(
+    path: Option<String>,
+    method: Option<String>,
+    expires_in: Option<String>,
+    max_size: Option<String>,
+) -> std::result::Result<Link, ConfigError> {
+    let path = path.ok_or_else(|| ConfigError::MissingValue("sign".to_owned()))?;
+    let mut link =
+        Link::new(&path).ok_or_else(|| ConfigError::InvalidOption("sign".to_owned(), path))?;
+    if let Some(method) = method {
+        link.set_method(&method)
+            .ok_or(ConfigError::InvalidOption("method".to_owned(), method))?;
+    }
+    if let Some(secs) = expires_in {
+        link.expires_in = secs
+            .parse::<u64>()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .ok_or(ConfigError::InvalidOption("expires_in".to_owned(), secs))?;
+    }
+    // Only uploads have a body to limit
+    if let Some(bytes) = max_size {
+        if link.method != Method::Post {
+            return Err(ConfigError::InvalidOption("max_size".to_owned(), bytes));
+        }
+        link.max_size = Some(
+            bytes
+                .parse::<u64>()
+                .map_err(|_| ConfigError::InvalidOption("max_size".to_owned(), bytes))?,
+        );
+    }
+    Ok(link)
+}
+
+fn string_value(parser: &mut lexopt::Parser) -> Result<String> {
+    let val = parser.value().map_err(ConfigError::from)?;
+    Ok(val.string().map_err(ConfigError::from)?)
+}
+
+fn parse_address(s: &str) -> Option<String> {
+    if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+        return (!path.is_empty()).then(|| s.to_owned());
+    }
+    let addr = match s.strip_prefix(TLS_PREFIX) {
+        Some(addr) if cfg!(feature = "tls") => addr,
+        Some(_) => return None,
+        None => s,
+    };
+    // Only the syntax is checked, names are resolved when the listener is
+    // bound, as they may not resolve yet when the config is read
+    valid_host_port(addr).then(|| s.to_owned())
+}
+
+// HOST:PORT where HOST is an IP address, with [] around IPv6 ones, or a name
+fn valid_host_port(addr: &str) -> bool {
+    if addr.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    match addr.rsplit_once(':') {
+        Some((host, port)) => {
+            port.parse::<u16>().is_ok()
+                && !host.is_empty()
+                && host.split('.').all(|label| {
+                    !label.is_empty()
+                        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                })
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod args {
+        use crate::access::Cidr;
+        use crate::config::{Command, Config};
+        use crate::constants::ADDRESS;
+        use crate::errors::{AppError, ConfigError};
+        use crate::http::Method;
+        use crate::server::Timeouts;
+        use std::time::Duration;
+
+        fn parse(args: &[&str]) -> crate::Result<Command> {
+            Config::default().parse(args.iter().copied())
+        }
+
+        fn parse_err(args: &[&str]) -> ConfigError {
+            match parse(args) {
+                Err(AppError::Config(e)) => e,
+                _ => panic!("expected a config error for {:?}", args),
+            }
+        }
+
+        #[test]
+        fn parses_options() {
+            let Ok(Command::Run(config)) = parse(&["-a", "0.0.0.0:8080", "--target_dir="]) else {
+                panic!("expected a config");
+            };
+            assert_eq!(vec!["0.0.0.0:8080"], config.listen);
+        }
+
+        #[test]
+        fn uses_defaults_without_args() {
+            let Ok(Command::Run(config)) = parse(&[]) else {
+                panic!("expected a config");
+            };
+            assert_eq!(vec![ADDRESS], config.listen);
+        }
+
+        #[test]
+        fn parses_multiple_listeners() {
+            let Ok(Command::Run(config)) = parse(&[
+                "--listen=127.0.0.1:4221",
+                "-l",
+                "[::1]:4221",
+                "--listen=0.0.0.0:8080",
+                "--ipv6_only=true",
+                "--socket_activation=true",
+            ]) else {
+                panic!("expected a config");
+            };
+            assert!(config.socket_activation);
+            assert_eq!(
+                vec!["127.0.0.1:4221", "[::1]:4221", "0.0.0.0:8080"],
+                config.listen
+            );
+            assert!(config.ipv6_only);
+
+            let Ok(Command::Run(config)) =
+                parse(&["--listen=unix:/run/http.sock", "--socket_mode=660"])
+            else {
+                panic!("expected a config");
+            };
+            assert_eq!(vec!["unix:/run/http.sock"], config.listen);
+            assert_eq!(Some(0o660), config.socket_mode);
+            assert_eq!(
+                ConfigError::InvalidAddress("unix:".to_owned()),
+                parse_err(&["--listen=unix:"])
+            );
+        }
+
+        #[test]
+        fn parses_limits() {
+            let Ok(Command::Run(config)) = parse(&[
+                "--max_body=100",
+                "--max_headers=10",
+                "--mount_limits=/files:max_body=1000,max_header_bytes=2000",
+                "--mount_limits=/echo:max_body=0",
+            ]) else {
+                panic!("expected a config");
+            };
+            assert_eq!(100, config.limits.body);
+            assert_eq!(10, config.limits.for_path("/files/a").headers);
+            assert_eq!(1000, config.limits.for_path("/files/a").body);
+            assert_eq!(2000, config.limits.for_path("/files/a").header_bytes);
+            assert_eq!(0, config.limits.for_path("/echo/a").body);
+            assert_eq!(
+                ConfigError::InvalidOption(
+                    "mount_limits".to_owned(),
+                    "files:max_body=1".to_owned()
+                ),
+                parse_err(&["--mount_limits=files:max_body=1"])
+            );
+            assert_eq!(
+                ConfigError::InvalidOption("max_request_line".to_owned(), "0".to_owned()),
+                parse_err(&["--max_request_line=0"])
+            );
+        }
+
+        #[test]
+        fn parses_timeouts() {
+            let Ok(Command::Run(config)) = parse(&["--header_timeout=5", "--keep_alive_timeout=0"])
+            else {
+                panic!("expected a config");
+            };
+            assert_eq!(Some(Duration::from_secs(5)), config.timeouts.header);
+            assert_eq!(None, config.timeouts.keep_alive);
+            assert_eq!(Timeouts::default().body, config.timeouts.body);
+            assert_eq!(
+                ConfigError::InvalidOption("write_timeout".to_owned(), "soon".to_owned()),
+                parse_err(&["--write_timeout=soon"])
+            );
+        }
+
+        #[test]
+        fn parses_rate_limits() {
+            let Ok(Command::Run(config)) = parse(&[
+                "--rate_limit=/:rate=10",
+                "--rate_limit=/files:rate=1,burst=5,by=user;/echo:rate=2",
+                "--rate_limit=/:rate=20",
+                "--max_connections_per_ip=4",
+            ]) else {
+                panic!("expected a config");
+            };
+            let paths: Vec<_> = config
+                .rate_limits
+                .iter()
+                .map(|l| (l.path.as_str(), l.rate))
+                .collect();
+            assert_eq!(vec![("/files", 1.0), ("/echo", 2.0), ("", 20.0)], paths);
+            assert_eq!(4, config.max_connections_per_ip);
+            assert_eq!(
+                ConfigError::InvalidOption("rate_limit".to_owned(), "/:rate=fast".to_owned()),
+                parse_err(&["--rate_limit=/:rate=fast"])
+            );
+        }
+
+        #[test]
+        fn parses_access_rules() {
+            let Ok(Command::Run(config)) = parse(&[
+                "--access=/files:method=POST,allow=10.0.0.0/8",
+                "--access=/:deny=192.0.2.0/24;/files:method=POST,allow=fd00::/8",
+                "--deny_connections=198.51.100.0/24,2001:db8::/32",
+            ]) else {
+                panic!("expected a config");
+            };
+            assert_eq!(2, config.access.len());
+            assert_eq!("/files", config.access[1].scope.path);
+            assert_eq!(
+                vec![Cidr::parse("fd00::/8").unwrap()],
+                config.access[1].ips.allow
+            );
+            assert_eq!(2, config.connections.deny.len());
+            assert!(config.connections.allow.is_empty());
+            assert_eq!(
+                ConfigError::InvalidOption(
+                    "allow_connections".to_owned(),
+                    "10.0.0.0/40".to_owned()
+                ),
+                parse_err(&["--allow_connections=10.0.0.0/40"])
+            );
+        }
+
+        #[test]
+        fn parses_tokens() {
+            let Ok(Command::Run(config)) = parse(&[
+                "--token=ci:secret=0123456789abcdef,method=POST,path=/files/build-*",
+                "--token=reader:secret=fedcba9876543210,path=/files/**;ci:secret=0123456789abcdeg,path=/",
+            ]) else {
+                panic!("expected a config");
+            };
+            assert_eq!(2, config.tokens.len());
+            assert_eq!(vec!["/".to_owned()], config.tokens[1].paths);
+            // Without the secret
+            assert_eq!(
+                ConfigError::InvalidOption("token".to_owned(), "ci:...".to_owned()),
+                parse_err(&["--token=ci:secret=short,path=/"])
+            );
+        }
+
+        #[test]
+        fn parses_the_sign_subcommand() {
+            let Ok(Command::Sign(config, link)) = parse(&[
+                "sign",
+                "--signing_key=0123456789abcdef0123456789abcdef",
+                "--method=POST",
+                "--expires_in=60",
+                "--max_size=1024",
+                "/files/inbox",
+            ]) else {
+                panic!("expected a link to sign");
+            };
+            assert!(config.signer.is_some());
+            assert_eq!(
+                (Method::Post, Duration::from_secs(60), Some(1024)),
+                (link.method, link.expires_in, link.max_size)
+            );
+            assert_eq!(
+                ConfigError::MissingValue("sign".to_owned()),
+                parse_err(&["sign"])
+            );
+            assert_eq!(
+                ConfigError::InvalidOption("max_size".to_owned(), "1".to_owned()),
+                parse_err(&["sign", "--max_size=1", "/files/a"])
+            );
+            // Only for sign
+            assert!(parse(&["--method=POST"]).is_err());
+            // Without the key
+            assert_eq!(
+                ConfigError::InvalidOption("signing_key".to_owned(), "...".to_owned()),
+                parse_err(&["--signing_key=short"])
+            );
+        }
+
+        #[test]
+        fn needs_a_certificate_for_tls() {
+            let args = ["--listen=tls:127.0.0.1:0", "--tls_versions=1.3"];
+            #[cfg(feature = "tls")]
+            assert_eq!(
+                ConfigError::MissingValue("tls_cert".to_owned()),
+                parse_err(&args)
+            );
+            #[cfg(not(feature = "tls"))]
+            assert_eq!(
+                ConfigError::InvalidAddress("tls:127.0.0.1:0".to_owned()),
+                parse_err(&args)
+            );
+            assert!(parse(&["--tls_versions=1.0"]).is_err());
+            assert!(parse(&["--tls_client_auth=sometimes"]).is_err());
+            // Asking for client certificates is no use without one of our own
+            #[cfg(feature = "tls")]
+            assert_eq!(
+                ConfigError::MissingValue("tls_cert".to_owned()),
+                parse_err(&[
+                    "--tls_client_ca=/etc/ssl/ca.pem",
+                    "--tls_client_auth=optional"
+                ])
+            );
+        }
+
+        #[test]
+        fn handles_help_and_version() {
+            assert!(matches!(parse(&["--help"]), Ok(Command::Help)));
+            assert!(parse(&["-a", "nowhere", "-V"]).is_err());
+            assert!(matches!(parse(&["-V"]), Ok(Command::Version)));
+        }
+
+        #[test]
+        fn rejects_unknown_flags() {
+            assert_eq!(
+                ConfigError::UnknownFlag("--port".to_owned()),
+                parse_err(&["--port", "80"])
+            );
+            assert_eq!(
+                ConfigError::UnexpectedArgument("extra".to_owned()),
+                parse_err(&["extra"])
+            );
+        }
+
+        #[test]
+        fn parses_pool_options() {
+            let Ok(Command::Run(config)) = parse(&["--threads=3", "-q", "0", "--max_parked=100"])
+            else {
+                panic!("expected a config");
+            };
+            assert_eq!(3, config.threads);
+            assert_eq!(0, config.queue_depth);
+            assert_eq!(100, config.max_parked);
+            assert_eq!(
+                ConfigError::InvalidOption("threads".to_owned(), "0".to_owned()),
+                parse_err(&["--threads", "0"])
+            );
+            assert_eq!(
+                ConfigError::InvalidOption("max_parked".to_owned(), "0".to_owned()),
+                parse_err(&["--max_parked=0"])
+            );
+        }
+
+        #[test]
+        fn rejects_missing_values() {
+            assert_eq!(
+                ConfigError::MissingValue("--address".to_owned()),
+                parse_err(&["--address"])
+            );
+        }
+
+        #[test]
+        fn rejects_invalid_address() {
+            assert_eq!(
+                ConfigError::InvalidAddress("localhost".to_owned()),
+                parse_err(&["--address=localhost"])
+            );
+            for bad in [
+                "localhost:http",
+                "localhost:65536",
+                ":80",
+                "::1:80",
+                "a..b:80",
+                "a b:80",
+            ] {
+                assert!(parse(&["--listen", bad]).is_err(), "{}", bad);
+            }
+            // Names are left for the listener to resolve
+            for good in ["[::1]:0", "0.0.0.0:80", "not-resolvable-yet.invalid:8080"] {
+                assert!(parse(&["--listen", good]).is_ok(), "{}", good);
+            }
+        }
+
+        #[test]
+        fn rejects_bad_directory() {
+            assert_eq!(
+                ConfigError::BadDirectory("/tmp/does/not/exist".to_owned()),
+                parse_err(&["-t", "/does/not/exist"])
+            );
+        }
+    }
+
+    mod env {
+        use crate::config::Config;
+        use crate::constants::{ADDRESS, QUEUE_DEPTH};
+        use crate::errors::{AppError, ConfigError};
+        use std::{collections::HashMap, ffi::OsString};
+
+        fn lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<OsString> {
+            let vars: HashMap<String, OsString> = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), OsString::from(v)))
+                .collect();
+            move |key| vars.get(key).cloned()
+        }
+
+        #[test]
+        fn uses_defaults_without_env() {
+            let config = Config::from_env(lookup(&[])).unwrap();
+            assert_eq!(vec![ADDRESS], config.listen);
+            assert_eq!(QUEUE_DEPTH, config.queue_depth);
+        }
+
+        #[test]
+        fn reads_prefixed_vars() {
+            let config = Config::from_env(lookup(&[
+                ("HTTP_SERVER_ADDRESS", "0.0.0.0:8080"),
+                ("HTTP_SERVER_THREADS", "2"),
+            ]))
+            .unwrap();
+            assert_eq!(vec!["0.0.0.0:8080"], config.listen);
+            assert_eq!(2, config.threads);
+
+            let config = Config::from_env(lookup(&[(
+                "HTTP_SERVER_LISTEN",
+                "127.0.0.1:4221, [::1]:4221",
+            )]))
+            .unwrap();
+            assert_eq!(vec!["127.0.0.1:4221", "[::1]:4221"], config.listen);
+        }
+
+        #[test]
+        fn rejects_unparseable_values() {
+            assert_eq!(
+                AppError::Config(ConfigError::InvalidEnv(
+                    "HTTP_SERVER_THREADS".to_owned(),
+                    "lots".to_owned()
+                )),
+                Config::from_env(lookup(&[("HTTP_SERVER_THREADS", "lots")])).unwrap_err()
+            );
+            assert_eq!(
+                AppError::Config(ConfigError::InvalidEnv(
+                    "HTTP_SERVER_ADDRESS".to_owned(),
+                    "nowhere".to_owned()
+                )),
+                Config::from_env(lookup(&[("HTTP_SERVER_ADDRESS", "nowhere")])).unwrap_err()
+            );
+            // Only the token's name, never its secret
+            let err = Config::from_env(lookup(&[(
+                "HTTP_SERVER_TOKEN",
+                "ci:secret=hunter2hunter2,path=files",
+            )]))
+            .unwrap_err();
+            assert_eq!(
+                AppError::Config(ConfigError::InvalidEnv(
+                    "HTTP_SERVER_TOKEN".to_owned(),
+                    "ci:...".to_owned()
+                )),
+                err
+            );
+            assert!(!err.to_string().contains("hunter2"));
+            // Nor a signing key, which would let anyone forge links
+            let err =
+                Config::from_env(lookup(&[("HTTP_SERVER_SIGNING_KEY", "short-key")])).unwrap_err();
+            assert_eq!(
+                AppError::Config(ConfigError::InvalidEnv(
+                    "HTTP_SERVER_SIGNING_KEY".to_owned(),
+                    "...".to_owned()
+                )),
+                err
+            );
+            assert!(!err.to_string().contains("short-key"));
+        }
+    }
+
+    mod file {
+        use crate::config::{Command, Config};
+        use crate::errors::{AppError, ConfigError};
+        use log::LevelFilter;
+        use std::{ffi::OsString, path::PathBuf};
+
+        fn write(name: &str, contents: &str) -> PathBuf {
+            let path =
+                std::env::temp_dir().join(format!("http-config-{}-{}", std::process::id(), name));
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+
+        #[test]
+        fn reads_options_from_a_file() {
+            let path = write(
+                "options",
+                "# comments and blank lines are skipped\n\
+                 \n\
+                 listen = 127.0.0.1:8080\n\
+                 listen = [::1]:8080\n\
+                 threads=3\n\
+                 log_level = debug\n",
+            );
+            let config = Config::from_file(&path).unwrap();
+            assert_eq!(vec!["127.0.0.1:8080", "[::1]:8080"], config.listen);
+            assert_eq!(3, config.threads);
+            assert_eq!(Some(LevelFilter::Debug), config.log_level);
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn layers_env_and_args_over_the_file() {
+            let path = write("layers", "threads = 3\nqueue_depth = 5\nadmin = true\n");
+            let env = |key: &str| (key == "HTTP_SERVER_QUEUE_DEPTH").then(|| OsString::from("6"));
+            let args = [
+                format!("--config={}", path.display()),
+                "-n".into(),
+                "4".into(),
+            ];
+            let Ok(Command::Run(config)) = Config::load(env, args) else {
+                panic!("expected a config");
+            };
+            assert_eq!(4, config.threads);
+            assert_eq!(6, config.queue_depth);
+            assert!(config.admin);
+            assert_eq!(Some(path.clone()), config.config_file);
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn reads_users_for_auth() {
+            let users = write("users", "erin:{SHA}qUqP5cyxm6YcTAhz05Hph5gvu9M=\n");
+            let path = write(
+                "auth",
+                &format!(
+                    "htpasswd = {}\n\
+                     auth = /files:method=POST,user=erin;/files/public:anonymous=true\n\
+                     auth = /files:method=POST,realm=Uploads\n",
+                    users.display()
+                ),
+            );
+            let config = Config::from_file(&path).unwrap();
+            assert!(config.users.verify("erin", "test"));
+            assert_eq!(2, config.auth.len());
+            assert!(config.auth[0].anonymous);
+            assert_eq!("Uploads", config.auth[1].realm);
+            assert!(config.auth[1].users.is_empty());
+
+            std::fs::write(&users, "erin:$apr1$salt$hash\n").unwrap();
+            let expected = ConfigError::BadConfigFile(
+                users.display().to_string(),
+                "line 1: expected user:hash with a bcrypt, SHA-crypt or {SHA} hash".to_owned(),
+            );
+            let Err(AppError::Config(ConfigError::BadConfigFile(_, msg))) =
+                Config::from_file(&path)
+            else {
+                panic!("expected a bad users file");
+            };
+            assert_eq!(format!("line 1: {}", expected), msg);
+            let _ = std::fs::remove_file(&users);
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn rejects_bad_lines() {
+            let path = write("bad", "threads = 2\nthreads = none\n");
+            let expected = ConfigError::BadConfigFile(
+                path.display().to_string(),
+                "line 2: invalid value for '--threads': \"none\"".to_owned(),
+            );
+            assert_eq!(
+                AppError::Config(expected),
+                Config::from_file(&path).unwrap_err()
+            );
+
+            std::fs::write(&path, "no equals sign\n").unwrap();
+            assert!(Config::from_file(&path).is_err());
+            std::fs::write(&path, "config = /etc/other\n").unwrap();
+            assert!(Config::from_file(&path).is_err());
+            let _ = std::fs::remove_file(&path);
+            assert!(Config::from_file(&path).is_err());
         }
     }
 }
@@ -1,7 +1,7 @@
 use crate::{constants::TARGET_DIR, Result};
 use std::{
     fs::{create_dir, read, write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 pub trait FileSystemAccess {
@@ -22,6 +22,9 @@ impl Dir {
             path: PathBuf::from(p),
         }
     }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 }
 
 impl Default for Dir {
@@ -1,13 +1,14 @@
 use std::error::Error;
 use std::fmt::{Debug, Display, Error as FmtErr, Formatter};
-use std::io::Error as IOError;
+use std::io::{Error as IOError, ErrorKind};
 use std::num::ParseIntError;
 use std::sync::{mpsc, PoisonError};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ServerError {
     Internal,
     NotImplemented,
+    ServiceUnavailable,
 }
 
 impl Error for ServerError {}
@@ -17,14 +18,21 @@ impl Display for ServerError {
         match self {
             Self::Internal => write!(f, "500 Internal Server Error"),
             Self::NotImplemented => write!(f, "501 Not Implemented"),
+            Self::ServiceUnavailable => write!(f, "503 Service Unavailable"),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ClientError {
     NotFound,
     BadRequest,
+    Forbidden,
+    RequestTimeout,
+    ContentTooLarge,
+    UriTooLong,
+    TooManyRequests,
+    HeadersTooLarge,
 }
 
 impl Error for ClientError {}
@@ -34,21 +42,84 @@ impl Display for ClientError {
         match self {
             Self::NotFound => write!(f, "404 Not Found"),
             Self::BadRequest => write!(f, "400 Bad Request"),
+            Self::Forbidden => write!(f, "403 Forbidden"),
+            Self::RequestTimeout => write!(f, "408 Request Timeout"),
+            Self::ContentTooLarge => write!(f, "413 Content Too Large"),
+            Self::UriTooLong => write!(f, "414 URI Too Long"),
+            Self::TooManyRequests => write!(f, "429 Too Many Requests"),
+            Self::HeadersTooLarge => write!(f, "431 Request Header Fields Too Large"),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    UnknownFlag(String),
+    UnexpectedArgument(String),
+    MissingValue(String),
+    InvalidValue(String),
+    // (option, value)
+    InvalidOption(String, String),
+    InvalidAddress(String),
+    BadDirectory(String),
+    // (path, problem)
+    BadConfigFile(String, String),
+    // (variable, value)
+    InvalidEnv(String, String),
+    // The certificate, key, versions and ciphers don't go together
+    Tls(String),
+}
+
+impl Error for ConfigError {}
+
+impl From<lexopt::Error> for ConfigError {
+    fn from(error: lexopt::Error) -> Self {
+        match error {
+            lexopt::Error::UnexpectedOption(flag) => Self::UnknownFlag(flag),
+            lexopt::Error::UnexpectedArgument(arg) => {
+                Self::UnexpectedArgument(arg.to_string_lossy().into())
+            }
+            lexopt::Error::MissingValue { option } => {
+                Self::MissingValue(option.unwrap_or_default())
+            }
+            e => Self::InvalidValue(e.to_string()),
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::result::Result<(), FmtErr> {
+        match self {
+            Self::UnknownFlag(flag) => write!(f, "unknown option '{}'", flag),
+            Self::UnexpectedArgument(arg) => write!(f, "unexpected argument {:?}", arg),
+            Self::MissingValue(flag) => write!(f, "missing value for '{}'", flag),
+            Self::InvalidValue(msg) => write!(f, "invalid value: {}", msg),
+            Self::InvalidOption(opt, val) => write!(f, "invalid value for '--{}': {:?}", opt, val),
+            Self::InvalidAddress(val) => write!(f, "invalid address: {:?}", val),
+            Self::BadDirectory(val) => write!(f, "unable to use directory: {:?}", val),
+            Self::BadConfigFile(path, msg) => write!(f, "unable to use {}: {}", path, msg),
+            Self::InvalidEnv(var, val) => write!(f, "invalid value for {}: {:?}", var, val),
+            Self::Tls(msg) => write!(f, "unable to set up TLS: {}", msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum AppError {
     Client(ClientError),
     Server(ServerError),
+    Config(ConfigError),
 }
 
 impl Error for AppError {}
 
 impl Display for AppError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::result::Result<(), FmtErr> {
-        write!(f, "{:?}", self)
+        match self {
+            Self::Client(e) => write!(f, "{}", e),
+            Self::Server(e) => write!(f, "{}", e),
+            Self::Config(e) => write!(f, "{}", e),
+        }
     }
 }
 
@@ -64,9 +135,19 @@ impl From<ServerError> for AppError {
     }
 }
 
+impl From<ConfigError> for AppError {
+    fn from(error: ConfigError) -> Self {
+        Self::Config(error)
+    }
+}
+
 impl From<IOError> for AppError {
-    fn from(_error: IOError) -> Self {
-        Self::Server(ServerError::Internal)
+    fn from(error: IOError) -> Self {
+        match error.kind() {
+            // One of the connection timeouts ran out, the client is too slow
+            ErrorKind::TimedOut => Self::Client(ClientError::RequestTimeout),
+            _ => Self::Server(ServerError::Internal),
+        }
     }
 }
 
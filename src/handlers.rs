@@ -1,71 +1,51 @@
 // TODO: there is a lot of boilerplate here, is that a code smell? Or is it
 // an opportunity to hide some of this behind a macro?
 use crate::{
+    constants::RETRY_AFTER_SECS,
     dir::FileSystemAccess,
     errors::AppError,
     http::{ClientError, Headers, Method, MimeType, Request, Response, ServerError, StatusCode},
     Result,
 };
-use std::io::Write;
-
+// Handlers build the response and leave writing it to the router, which is
+// the one that knows whether the connection is going to be kept open
 #[derive(Debug)]
-pub struct HandlerArg<'a, T>
-where
-    T: Write,
-{
+pub struct HandlerArg<'a> {
     pub req: &'a Request,
-    pub stream: &'a mut T,
 }
 
-impl<'a, T> HandlerArg<'a, T>
-where
-    T: Write,
-{
-    pub fn new(req: &'a Request, stream: &'a mut T) -> HandlerArg<'a, T> {
-        HandlerArg { req, stream }
+impl<'a> HandlerArg<'a> {
+    pub fn new(req: &'a Request) -> HandlerArg<'a> {
+        HandlerArg { req }
     }
 }
 
 #[derive(Debug)]
-pub struct FileHandlerArg<'a, T, U>
+pub struct FileHandlerArg<'a, U>
 where
-    T: Write,
     U: FileSystemAccess,
 {
     pub req: &'a Request,
-    pub stream: &'a mut T,
     pub target_dir: &'a U,
 }
 
-impl<'a, T, U> FileHandlerArg<'a, T, U>
+impl<'a, U> FileHandlerArg<'a, U>
 where
-    T: Write,
     U: FileSystemAccess,
 {
-    pub fn new(req: &'a Request, stream: &'a mut T, target_dir: &'a U) -> FileHandlerArg<'a, T, U> {
-        FileHandlerArg {
-            req,
-            stream,
-            target_dir,
-        }
+    pub fn new(req: &'a Request, target_dir: &'a U) -> FileHandlerArg<'a, U> {
+        FileHandlerArg { req, target_dir }
     }
 }
 
 #[derive(Debug)]
-pub struct ErrorHandlerArg<'a, T>
-where
-    T: Write,
-{
-    pub stream: &'a mut T,
+pub struct ErrorHandlerArg {
     pub err: AppError,
 }
 
-impl<'a, T> ErrorHandlerArg<'a, T>
-where
-    T: Write,
-{
-    pub fn new(stream: &'a mut T, err: AppError) -> ErrorHandlerArg<'a, T> {
-        ErrorHandlerArg { stream, err }
+impl ErrorHandlerArg {
+    pub fn new(err: AppError) -> ErrorHandlerArg {
+        ErrorHandlerArg { err }
     }
 }
 
@@ -73,130 +53,102 @@ pub struct EchoHandler;
 pub struct EmptyHandler;
 pub struct FileHandler;
 pub struct UserAgentHandler;
-pub struct NotFoundHandler;
 pub struct ErrorHandler;
 
 pub trait Handler {
-    fn handle<T>(r: HandlerArg<T>) -> Result<()>
-    where
-        T: Write;
+    fn handle(r: HandlerArg) -> Result<Response>;
 }
 
 impl Handler for EchoHandler {
-    fn handle<T>(r: HandlerArg<T>) -> Result<()>
-    where
-        T: Write,
-    {
+    fn handle(r: HandlerArg) -> Result<Response> {
         let body = r.req.body.as_slice();
-        let resp = Response::builder()
+        Response::builder()
             .body(Some(body.to_owned()))
             .encoding(r.req.get_header(Headers::AcceptEncoding))
             .mime_type(MimeType::PlainText)
-            .build()?;
-        r.stream.write_all(&resp.as_bytes())?;
-        Ok(())
+            .build()
     }
 }
 
 impl Handler for EmptyHandler {
-    fn handle<T>(r: HandlerArg<T>) -> Result<()>
-    where
-        T: Write,
-    {
-        let resp = Response::ok()?;
-        r.stream.write_all(&resp.as_bytes())?;
-        Ok(())
+    fn handle(_r: HandlerArg) -> Result<Response> {
+        Response::ok()
     }
 }
 
 impl Handler for UserAgentHandler {
-    fn handle<T>(r: HandlerArg<T>) -> Result<()>
-    where
-        T: Write,
-    {
+    fn handle(r: HandlerArg) -> Result<Response> {
         let b = r
             .req
             .get_header(Headers::UserAgent)
             .map(|b| b.as_bytes().to_owned());
-        let resp = Response::builder()
+        Response::builder()
             .body(b)
             .encoding(r.req.get_header(Headers::ContentEncoding))
             .mime_type(MimeType::PlainText)
-            .build()?;
-        r.stream.write_all(&resp.as_bytes())?;
-        Ok(())
-    }
-}
-
-impl Handler for NotFoundHandler {
-    fn handle<T>(r: HandlerArg<T>) -> Result<()>
-    where
-        T: Write,
-    {
-        let resp = Response::not_found()?;
-        r.stream.write_all(&resp.as_bytes())?;
-        Ok(())
+            .build()
     }
 }
 
 impl FileHandler {
-    pub fn handle<T, U>(r: FileHandlerArg<T, U>) -> Result<()>
+    pub fn handle<U>(r: FileHandlerArg<U>) -> Result<Response>
     where
-        T: Write,
         U: FileSystemAccess,
     {
         let src = &r.req.path_parts[1];
         match r.req.method {
             Method::Get => {
                 if let Ok(body) = r.target_dir.try_read(src) {
-                    let resp = Response::builder()
+                    Response::builder()
                         .status_code(StatusCode::Ok)
                         .body(Some(body))
                         .encoding(r.req.get_header(Headers::AcceptEncoding))
                         .mime_type(MimeType::OctetStream)
-                        .build()?;
-                    r.stream.write_all(&resp.as_bytes())?;
+                        .build()
                 } else {
-                    return Err(ClientError::NotFound.into());
+                    Err(ClientError::NotFound.into())
                 }
             }
             Method::Post => {
                 r.target_dir.try_write(src, &r.req.body)?;
-                let resp = Response::created()?;
-                r.stream.write_all(&resp.as_bytes())?;
+                Response::created()
             }
-            _ => return Err(ServerError::Internal.into()),
+            _ => Err(ServerError::Internal.into()),
         }
-        Ok(())
     }
 }
 
 impl ErrorHandler {
-    pub fn handle<T>(a: ErrorHandlerArg<T>) -> Result<()>
-    where
-        T: Write,
-    {
+    pub fn handle(a: ErrorHandlerArg) -> Result<Response> {
         match a.err {
-            AppError::Client(ClientError::BadRequest) => {
-                let resp = Response::client_error()?;
-                a.stream.write_all(&resp.as_bytes())?;
-            }
-            AppError::Client(ClientError::NotFound) => {
-                let resp = Response::not_found()?;
-                a.stream.write_all(&resp.as_bytes())?;
-            }
-            AppError::Server(ServerError::NotImplemented) => {
-                let resp = Response::builder()
-                    .status_code(crate::http::StatusCode::NotImplemented)
-                    .build()?;
-                a.stream.write_all(&resp.as_bytes())?;
+            AppError::Client(ClientError::BadRequest) => Response::client_error(),
+            AppError::Client(ClientError::NotFound) => Response::not_found(),
+            AppError::Client(ClientError::Forbidden) => Response::builder()
+                .status_code(StatusCode::Forbidden)
+                .build(),
+            AppError::Client(ClientError::RequestTimeout) => Response::builder()
+                .status_code(StatusCode::RequestTimeout)
+                .build(),
+            AppError::Client(ClientError::ContentTooLarge) => Response::builder()
+                .status_code(StatusCode::ContentTooLarge)
+                .build(),
+            AppError::Client(ClientError::UriTooLong) => Response::builder()
+                .status_code(StatusCode::UriTooLong)
+                .build(),
+            AppError::Client(ClientError::TooManyRequests) => {
+                Response::too_many_requests(RETRY_AFTER_SECS)
             }
-            _ => {
-                let resp = Response::server_error()?;
-                a.stream.write_all(&resp.as_bytes())?;
+            AppError::Client(ClientError::HeadersTooLarge) => Response::builder()
+                .status_code(StatusCode::HeadersTooLarge)
+                .build(),
+            AppError::Server(ServerError::ServiceUnavailable) => {
+                Response::service_unavailable(RETRY_AFTER_SECS)
             }
+            AppError::Server(ServerError::NotImplemented) => Response::builder()
+                .status_code(crate::http::StatusCode::NotImplemented)
+                .build(),
+            _ => Response::server_error(),
         }
-        Ok(())
     }
 }
 
@@ -215,15 +167,15 @@ mod tests {
             // TODO: this is sort of pointless, just write out the request?
             let req = Request::try_from(&mut buf).unwrap();
             print!("{:?}", req);
-            let mut stream = Vec::new();
-            let arg = HandlerArg::new(&req, &mut stream);
-            let _ = EchoHandler::handle(arg);
+            let arg = HandlerArg::new(&req);
+            let resp = EchoHandler::handle(arg).unwrap();
             let expected = Response::builder()
                 .status_code(StatusCode::Ok)
                 .body(Some(String::from("hello").into_bytes()))
                 .mime_type(MimeType::PlainText)
-                .build().unwrap();
-            assert_eq!(&expected.as_bytes(), &stream);
+                .build()
+                .unwrap();
+            assert_eq!(&expected.as_bytes(), &resp.as_bytes());
         }
 
         #[test]
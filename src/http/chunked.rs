@@ -0,0 +1,201 @@
+use crate::errors::ClientError;
+use crate::Result;
+
+// Longest chunk size line we read, extensions included
+const MAX_CHUNK_LINE: u64 = 1024;
+
+// What the reader should read next for a chunked body
+#[derive(Debug, PartialEq)]
+pub enum Next {
+    // A line, of at most this many bytes
+    Line(u64),
+    // Exactly this many bytes of data
+    Data(u64),
+    Done,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Size,
+    Data(u64),
+    // The CRLF after the data
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+// Decodes a chunked body. Like RequestHead it is fed a line (or a chunk of
+// data) at a time, so the blocking and async readers only differ in how they
+// read. Anything it doesn't understand is a 400, guessing where the body ends
+// is how requests get smuggled.
+#[derive(Debug)]
+pub struct Chunked {
+    state: State,
+    // What's left of the body limit
+    body_room: u64,
+    // What's left of the header limit, for the trailers
+    trailer_room: usize,
+}
+
+impl Chunked {
+    pub fn new(body_room: u64, trailer_room: usize) -> Self {
+        Self {
+            state: State::Size,
+            body_room,
+            trailer_room,
+        }
+    }
+
+    pub fn next(&self) -> Next {
+        match self.state {
+            State::Size | State::DataEnd => Next::Line(MAX_CHUNK_LINE),
+            State::Data(len) => Next::Data(len),
+            State::Trailers => Next::Line(self.trailer_room as u64),
+            State::Done => Next::Done,
+        }
+    }
+
+    pub fn line(&mut self, line: &str) -> Result<()> {
+        // The whole line has to be there, including a proper line ending
+        let Some(content) = line.strip_suffix("\r\n") else {
+            return Err(match self.state {
+                State::Trailers if line.len() >= self.trailer_room => {
+                    ClientError::HeadersTooLarge.into()
+                }
+                _ => ClientError::BadRequest.into(),
+            });
+        };
+        if content.contains('\r') {
+            return Err(ClientError::BadRequest.into());
+        }
+        self.state = match self.state {
+            State::Size => match chunk_size(content)? {
+                0 => State::Trailers,
+                len if len > self.body_room => return Err(ClientError::ContentTooLarge.into()),
+                len => {
+                    self.body_room -= len;
+                    State::Data(len)
+                }
+            },
+            State::DataEnd if content.is_empty() => State::Size,
+            State::Trailers if content.is_empty() => State::Done,
+            // Read and thrown away, nothing here uses them
+            State::Trailers if valid_field(content) => {
+                self.trailer_room -= line.len();
+                State::Trailers
+            }
+            _ => return Err(ClientError::BadRequest.into()),
+        };
+        Ok(())
+    }
+
+    // Called with how much of the data the reader got, which is short if the
+    // client went away
+    pub fn data(&mut self, read: u64) -> Result<()> {
+        match self.state {
+            State::Data(len) if read == len => {
+                self.state = State::DataEnd;
+                Ok(())
+            }
+            _ => Err(ClientError::BadRequest.into()),
+        }
+    }
+}
+
+// Hex digits, optionally followed by extensions we ignore. No signs, 0x or
+// spaces before the digits, which other parsers might read differently.
+fn chunk_size(line: &str) -> Result<u64> {
+    let size = match line.split_once(';') {
+        Some((size, _)) => size.trim_end_matches([' ', '\t']),
+        None => line,
+    };
+    if size.is_empty() || size.len() > 16 || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ClientError::BadRequest.into());
+    }
+    Ok(u64::from_str_radix(size, 16)?)
+}
+
+// A name: value line, with nothing between the name and the colon
+fn valid_field(line: &str) -> bool {
+    match line.split_once(':') {
+        Some((name, _)) => is_token(name),
+        None => false,
+    }
+}
+
+// https://www.rfc-editor.org/rfc/rfc9110#name-tokens
+pub fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod chunked {
+        use crate::errors::{AppError, ClientError};
+        use crate::http::chunked::{Chunked, Next};
+
+        // Feeds a whole body through, returning the data
+        fn decode(mut body: &[u8], limit: u64) -> Result<Vec<u8>, AppError> {
+            let mut chunks = Chunked::new(limit, 100);
+            let mut data = Vec::new();
+            loop {
+                match chunks.next() {
+                    Next::Line(room) => {
+                        let end = body
+                            .iter()
+                            .take(room as usize)
+                            .position(|&b| b == b'\n')
+                            .map_or(body.len().min(room as usize), |i| i + 1);
+                        let (line, rest) = body.split_at(end);
+                        chunks.line(std::str::from_utf8(line).unwrap())?;
+                        body = rest;
+                    }
+                    Next::Data(len) => {
+                        let (read, rest) = body.split_at((len as usize).min(body.len()));
+                        data.extend_from_slice(read);
+                        chunks.data(read.len() as u64)?;
+                        body = rest;
+                    }
+                    Next::Done => return Ok(data),
+                }
+            }
+        }
+
+        #[test]
+        fn decodes_chunks() {
+            let body = b"5\r\nhello\r\n6;name=value\r\n world\r\n0\r\nX-Trailer: a\r\n\r\n";
+            assert_eq!(b"hello world".to_vec(), decode(body, 100).unwrap());
+            assert_eq!(b"".to_vec(), decode(b"0\r\n\r\n", 100).unwrap());
+        }
+
+        #[test]
+        fn rejects_bad_chunks() {
+            let bad: [&[u8]; 9] = [
+                b"+5\r\nhello\r\n0\r\n\r\n",
+                b"0x5\r\nhello\r\n0\r\n\r\n",
+                b" 5\r\nhello\r\n0\r\n\r\n",
+                b"5\nhello\n0\n\n",
+                b"5\r\nhelloX\r\n0\r\n\r\n",
+                b"5\r\nhel",
+                b"fffffffffffffffff\r\n",
+                b"0\r\nX-Trailer : a\r\n\r\n",
+                b"5\r\nhello\r\n0\r\n",
+            ];
+            for body in bad {
+                assert_eq!(
+                    AppError::Client(ClientError::BadRequest),
+                    decode(body, 100).unwrap_err(),
+                    "{:?}",
+                    String::from_utf8_lossy(body)
+                );
+            }
+            assert_eq!(
+                AppError::Client(ClientError::ContentTooLarge),
+                decode(b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n", 10).unwrap_err()
+            );
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use crate::constants::{MAX_BODY, MAX_HEADERS, MAX_HEADER_BYTES, MAX_REQUEST_LINE};
+
+// How much of a request we are prepared to read into memory. Anything over
+// is turned away while parsing, before it has been buffered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    // Including the line ending. Checked before the path is known, so it
+    // can't be set per mount.
+    pub request_line: usize,
+    // Every header line together, including the blank one ending the head
+    pub header_bytes: usize,
+    pub headers: usize,
+    pub body: u64,
+    // Overrides for everything under a path, the longest matching path wins
+    pub mounts: Vec<MountLimits>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MountLimits {
+    pub path: String,
+    pub header_bytes: Option<usize>,
+    pub headers: Option<usize>,
+    pub body: Option<u64>,
+}
+
+// What applies to a single request once its path is known
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RouteLimits {
+    pub header_bytes: usize,
+    pub headers: usize,
+    pub body: u64,
+}
+
+impl Limits {
+    pub fn for_path(&self, path: &str) -> RouteLimits {
+        let mount = self
+            .mounts
+            .iter()
+            .filter(|m| under(path, &m.path))
+            .max_by_key(|m| m.path.len());
+        RouteLimits {
+            header_bytes: mount
+                .and_then(|m| m.header_bytes)
+                .unwrap_or(self.header_bytes),
+            headers: mount.and_then(|m| m.headers).unwrap_or(self.headers),
+            body: mount.and_then(|m| m.body).unwrap_or(self.body),
+        }
+    }
+
+    // The biggest request head any path allows, for code that has to buffer
+    // one before it can be parsed
+    pub fn max_head(&self) -> usize {
+        let header_bytes = self
+            .mounts
+            .iter()
+            .filter_map(|m| m.header_bytes)
+            .fold(self.header_bytes, usize::max);
+        self.request_line + header_bytes
+    }
+
+    // Adds or updates the limits for a mount, from PATH:option=value,...
+    // using the same option names as the global limits
+    pub fn set_mount(&mut self, spec: &str) -> Option<()> {
+        let (path, options) = spec.split_once(':')?;
+        if !path.starts_with('/') {
+            return None;
+        }
+        let path = path.trim_end_matches('/');
+        let mut mount = match self.mounts.iter().find(|m| m.path == path) {
+            Some(mount) => mount.clone(),
+            None => MountLimits {
+                path: path.to_owned(),
+                ..MountLimits::default()
+            },
+        };
+        for option in options.split(',') {
+            let (option, val) = option.split_once('=')?;
+            match option.trim() {
+                "max_header_bytes" => mount.header_bytes = Some(val.trim().parse().ok()?),
+                "max_headers" => mount.headers = Some(val.trim().parse().ok()?),
+                "max_body" => mount.body = Some(val.trim().parse().ok()?),
+                _ => return None,
+            }
+        }
+        self.mounts.retain(|m| m.path != path);
+        self.mounts.push(mount);
+        Some(())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            request_line: MAX_REQUEST_LINE,
+            header_bytes: MAX_HEADER_BYTES,
+            headers: MAX_HEADERS,
+            body: MAX_BODY,
+            mounts: Vec::new(),
+        }
+    }
+}
+
+// Whether path is mount or something below it, so /files covers /files/a but
+// not /filesystem. A mount of / ends up empty and covers everything.
+pub(crate) fn under(path: &str, mount: &str) -> bool {
+    match path.strip_prefix(mount) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || mount.is_empty(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod limits {
+        use crate::http::Limits;
+
+        #[test]
+        fn picks_the_longest_matching_mount() {
+            let mut limits = Limits {
+                body: 10,
+                ..Limits::default()
+            };
+            assert!(limits.set_mount("/files:max_body=100").is_some());
+            assert!(limits.set_mount("/files/big/:max_body=1000").is_some());
+            assert!(limits.set_mount("/files:max_headers=5").is_some());
+            assert_eq!(10, limits.for_path("/echo/abc").body);
+            assert_eq!(10, limits.for_path("/filesystem").body);
+            assert_eq!(100, limits.for_path("/files").body);
+            assert_eq!(100, limits.for_path("/files/a").body);
+            assert_eq!(5, limits.for_path("/files/a").headers);
+            assert_eq!(1000, limits.for_path("/files/big/a").body);
+
+            assert!(limits.set_mount("/:max_body=1").is_some());
+            assert_eq!(1, limits.for_path("/echo/abc").body);
+        }
+
+        #[test]
+        fn rejects_bad_mounts() {
+            let mut limits = Limits::default();
+            assert!(limits.set_mount("files:max_body=1").is_none());
+            assert!(limits.set_mount("/files").is_none());
+            assert!(limits.set_mount("/files:max_body=lots").is_none());
+            assert!(limits.set_mount("/files:request_line=1").is_none());
+            assert!(limits.mounts.is_empty());
+        }
+    }
+}
@@ -1,13 +1,17 @@
+mod chunked;
+mod limits;
 mod request;
 mod response;
 
 use std::fmt::Display;
 
 pub use crate::errors::{ClientError, ServerError};
-pub use request::Request;
+pub(crate) use limits::under;
+pub use limits::{Limits, MountLimits, RouteLimits};
+pub use request::{Request, RequestHead};
 pub use response::Response;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Method {
     Get,
     Post,
@@ -28,6 +32,17 @@ impl From<Option<&str>> for Method {
     }
 }
 
+// Methods we don't know are only ever shown in logs
+impl Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Get => write!(f, "GET"),
+            Self::Post => write!(f, "POST"),
+            Self::Unknown | Self::Unsupported => write!(f, "-"),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Encoding {
     Gzip,
@@ -47,10 +62,19 @@ impl From<&str> for Encoding {
 pub enum StatusCode {
     Ok,
     Created,
+    Accepted,
+    Unauthorized,
+    Forbidden,
     NotFound,
     ServerError,
     ClientError,
+    RequestTimeout,
+    ContentTooLarge,
+    UriTooLong,
+    TooManyRequests,
+    HeadersTooLarge,
     NotImplemented,
+    ServiceUnavailable,
 }
 
 impl Display for StatusCode {
@@ -58,10 +82,19 @@ impl Display for StatusCode {
         match self {
             Self::Ok => write!(f, "200 OK"),
             Self::Created => write!(f, "201 Created"),
+            Self::Accepted => write!(f, "202 Accepted"),
             Self::ClientError => write!(f, "400 Bad Request"),
+            Self::Unauthorized => write!(f, "401 Unauthorized"),
+            Self::Forbidden => write!(f, "403 Forbidden"),
             Self::NotFound => write!(f, "404 Not Found"),
+            Self::RequestTimeout => write!(f, "408 Request Timeout"),
+            Self::ContentTooLarge => write!(f, "413 Content Too Large"),
+            Self::UriTooLong => write!(f, "414 URI Too Long"),
+            Self::TooManyRequests => write!(f, "429 Too Many Requests"),
+            Self::HeadersTooLarge => write!(f, "431 Request Header Fields Too Large"),
             Self::ServerError => write!(f, "500 Internal Server Error"),
             Self::NotImplemented => write!(f, "501 Not Implemented"),
+            Self::ServiceUnavailable => write!(f, "503 Service Unavailable"),
         }
     }
 }
@@ -70,24 +103,42 @@ impl Display for StatusCode {
 // I would have to implement TryFrom and then account for the Error. I am on the fence about
 // this...
 // TODO: implement TryFrom?
-#[derive(Debug, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub enum Headers {
     UserAgent,
     ContentLength,
     ContentEncoding,
     AcceptEncoding,
     ContentType,
+    RetryAfter,
+    Connection,
+    TransferEncoding,
+    RateLimitLimit,
+    RateLimitRemaining,
+    RateLimitReset,
+    Authorization,
+    WwwAuthenticate,
     Unknown,
 }
 
+// Header names are case insensitive. Missing a content-length because it
+// wasn't capitalised would leave its body to be read as the next request.
 impl From<&str> for Headers {
     fn from(value: &str) -> Self {
-        match value {
-            "User-Agent" => Self::UserAgent,
-            "Content-Length" => Self::ContentLength,
-            "Content-Encoding" => Self::ContentEncoding,
-            "Accept-Encoding" => Self::AcceptEncoding,
-            "Content-Type" => Self::ContentType,
+        match value.to_ascii_lowercase().as_str() {
+            "user-agent" => Self::UserAgent,
+            "content-length" => Self::ContentLength,
+            "content-encoding" => Self::ContentEncoding,
+            "accept-encoding" => Self::AcceptEncoding,
+            "content-type" => Self::ContentType,
+            "retry-after" => Self::RetryAfter,
+            "connection" => Self::Connection,
+            "transfer-encoding" => Self::TransferEncoding,
+            "ratelimit-limit" => Self::RateLimitLimit,
+            "ratelimit-remaining" => Self::RateLimitRemaining,
+            "ratelimit-reset" => Self::RateLimitReset,
+            "authorization" => Self::Authorization,
+            "www-authenticate" => Self::WwwAuthenticate,
             _ => Self::Unknown,
         }
     }
@@ -114,6 +165,14 @@ impl Display for Headers {
             Self::ContentEncoding => write!(f, "Content-Encoding"),
             Self::AcceptEncoding => write!(f, "Accept-Encoding"),
             Self::ContentType => write!(f, "Content-Type"),
+            Self::RetryAfter => write!(f, "Retry-After"),
+            Self::Connection => write!(f, "Connection"),
+            Self::TransferEncoding => write!(f, "Transfer-Encoding"),
+            Self::RateLimitLimit => write!(f, "RateLimit-Limit"),
+            Self::RateLimitRemaining => write!(f, "RateLimit-Remaining"),
+            Self::RateLimitReset => write!(f, "RateLimit-Reset"),
+            Self::Authorization => write!(f, "Authorization"),
+            Self::WwwAuthenticate => write!(f, "WWW-Authenticate"),
             Self::Unknown => write!(f, ""),
         }
     }
@@ -1,15 +1,18 @@
 use std::{
     collections::HashMap,
     io::{BufRead, BufReader, Read},
+    net::IpAddr,
 };
 
 use crate::{
-    errors::{AppError, ClientError},
+    client_cert::ClientCert,
+    errors::{AppError, ClientError, ServerError},
     router::Route,
     Result,
 };
 
-use super::{Headers, Method};
+use super::chunked::{is_token, Chunked, Next};
+use super::{Headers, Limits, Method, RouteLimits};
 
 fn get_path_parts(s: &str) -> Vec<String> {
     s.split("/")
@@ -18,15 +21,39 @@ fn get_path_parts(s: &str) -> Vec<String> {
         .collect()
 }
 
+// The path everything from routing to the access rules works from. Empty
+// segments are dropped, so //files/a is /files/a for the rules as well as the
+// handlers, and . and .. aren't allowed at all.
+fn canonical_path(path: &str) -> Option<String> {
+    if !path.starts_with('/') {
+        return None;
+    }
+    let parts = get_path_parts(path);
+    if parts.iter().any(|part| part == "." || part == "..") {
+        return None;
+    }
+    Some(format!("/{}", parts.join("/")))
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Request {
     pub method: Method,
     pub route: Route,
     // https://steveklabnik.com/writing/when-should-i-use-string-vs-str/
     pub path: String,
+    // Everything after the ?, if there was one
+    pub query: Option<String>,
     pub headers: HashMap<Headers, String>,
     pub body: Vec<u8>,
     pub path_parts: Vec<String>,
+    // Whether the client is happy for the connection to be reused
+    pub keep_alive: bool,
+    // Filled in by the server, None for Unix socket clients
+    pub client: Option<IpAddr>,
+    // Who the client authenticated as, if anyone
+    pub user: Option<String>,
+    // Filled in by the server for TLS clients that sent a certificate
+    pub client_cert: Option<ClientCert>,
 }
 
 impl Request {
@@ -42,16 +69,211 @@ impl<R: Read> TryFrom<&mut BufReader<R>> for Request {
     where
         R: Read,
     {
+        Request::read(buf, &Limits::default())
+    }
+}
+
+impl Request {
+    // Reads a request, giving up as soon as it goes over one of the limits
+    // rather than after it has all been buffered
+    pub fn read<R: Read>(buf: &mut BufReader<R>, limits: &Limits) -> Result<Self> {
+        Request::read_head(buf, limits)?.read_body(buf)
+    }
+
+    // The first half of read, for callers that want to treat the head and
+    // the body differently, e.g. with their own timeouts
+    pub fn read_head<R: Read>(buf: &mut BufReader<R>, limits: &Limits) -> Result<RequestHead> {
         let mut start_line = String::new();
-        let _ = buf.read_line(&mut start_line)?;
+        let _ = buf
+            .by_ref()
+            .take(limits.request_line as u64)
+            .read_line(&mut start_line)?;
+        let mut head = RequestHead::from_start_line(&start_line, limits)?;
+        loop {
+            let mut header_line = String::new();
+            let _ = buf
+                .by_ref()
+                .take(head.header_room())
+                .read_line(&mut header_line)?;
+            if !head.add_header(&header_line)? {
+                break;
+            }
+        }
+        Ok(head)
+    }
+
+    // The same as read_head, for tokio streams
+    #[cfg(feature = "tokio")]
+    pub async fn read_head_async<R>(buf: &mut R, limits: &Limits) -> Result<RequestHead>
+    where
+        R: tokio::io::AsyncBufRead + Unpin,
+    {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+        let mut start_line = String::new();
+        let _ = (&mut *buf)
+            .take(limits.request_line as u64)
+            .read_line(&mut start_line)
+            .await?;
+        let mut head = RequestHead::from_start_line(&start_line, limits)?;
+        loop {
+            let mut header_line = String::new();
+            let _ = (&mut *buf)
+                .take(head.header_room())
+                .read_line(&mut header_line)
+                .await?;
+            if !head.add_header(&header_line)? {
+                break;
+            }
+        }
+        Ok(head)
+    }
+}
+
+// A line read through a take that stopped before the line ending was too long
+// for what was left
+fn cut_off(line: &str, room: usize) -> bool {
+    !line.ends_with('\n') && line.len() >= room
+}
+
+// Everything up to the body. Parsed a line at a time so that the blocking and
+// async readers only differ in how they get the lines.
+#[derive(Debug)]
+pub struct RequestHead {
+    method: Method,
+    route: Route,
+    path: String,
+    query: Option<String>,
+    version: String,
+    path_parts: Vec<String>,
+    headers: HashMap<Headers, String>,
+    limits: RouteLimits,
+    // Header bytes still allowed
+    header_room: usize,
+    // Repeated headers are joined in the map, so they are counted here
+    header_count: usize,
+    // Worked out once the whole head is in
+    framing: Framing,
+}
+
+// How the end of the body is found
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Framing {
+    None,
+    Length(u64),
+    Chunked,
+}
+
+impl RequestHead {
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    // Lowers the body limit for this request below what its mount allows,
+    // e.g. to a signed link's max_size. Like the mount limit, a Content-Length
+    // over it is turned away before any of the body is read, and a chunked
+    // body as soon as it runs over.
+    pub fn limit_body(&mut self, max: u64) -> Result<()> {
+        self.limits.body = self.limits.body.min(max);
+        match self.framing {
+            Framing::Length(len) if len > self.limits.body => {
+                Err(ClientError::ContentTooLarge.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn read_body<R: Read>(self, buf: &mut BufReader<R>) -> Result<Request> {
+        let mut body_buf: Vec<u8> = vec![];
+        match self.framing {
+            Framing::None => {}
+            Framing::Length(len) => {
+                buf.take(len).read_to_end(&mut body_buf)?;
+            }
+            Framing::Chunked => {
+                let mut chunks = self.chunked();
+                loop {
+                    match chunks.next() {
+                        Next::Line(room) => {
+                            let mut line = String::new();
+                            buf.take(room).read_line(&mut line)?;
+                            chunks.line(&line)?;
+                        }
+                        Next::Data(len) => {
+                            let read = buf.take(len).read_to_end(&mut body_buf)?;
+                            chunks.data(read as u64)?;
+                        }
+                        Next::Done => break,
+                    }
+                }
+            }
+        }
+        Ok(self.into_request(body_buf))
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn read_body_async<R>(self, buf: &mut R) -> Result<Request>
+    where
+        R: tokio::io::AsyncBufRead + Unpin,
+    {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+        let mut body_buf: Vec<u8> = vec![];
+        match self.framing {
+            Framing::None => {}
+            Framing::Length(len) => {
+                (&mut *buf).take(len).read_to_end(&mut body_buf).await?;
+            }
+            Framing::Chunked => {
+                let mut chunks = self.chunked();
+                loop {
+                    match chunks.next() {
+                        Next::Line(room) => {
+                            let mut line = String::new();
+                            (&mut *buf).take(room).read_line(&mut line).await?;
+                            chunks.line(&line)?;
+                        }
+                        Next::Data(len) => {
+                            let read = (&mut *buf).take(len).read_to_end(&mut body_buf).await?;
+                            chunks.data(read as u64)?;
+                        }
+                        Next::Done => break,
+                    }
+                }
+            }
+        }
+        Ok(self.into_request(body_buf))
+    }
+
+    fn from_start_line(start_line: &str, limits: &Limits) -> Result<Self> {
+        if cut_off(start_line, limits.request_line) {
+            return Err(ClientError::UriTooLong.into());
+        }
         let mut start_parts = start_line.split_whitespace();
         let method = Method::from(start_parts.next());
-        let path = match start_parts.next() {
-            Some(s) => s.to_owned(),
+        let (path, query) = match start_parts.next() {
+            Some(s) => match s.split_once('?') {
+                Some((path, query)) => (path, Some(query.to_owned())),
+                None => (s, None),
+            },
             None => {
                 return Err(ClientError::BadRequest.into());
             }
         };
+        let path = canonical_path(path).ok_or(ClientError::BadRequest)?;
+        let version = start_parts.next().unwrap_or_default().to_owned();
         let path_parts = get_path_parts(path.as_str());
 
         let route = if path_parts.is_empty() {
@@ -60,51 +282,188 @@ impl<R: Read> TryFrom<&mut BufReader<R>> for Request {
             Route::from(&path_parts[0])
         };
 
-        let mut headers = HashMap::new();
+        let limits = limits.for_path(&path);
+        Ok(Self {
+            method,
+            route,
+            path,
+            query,
+            version,
+            path_parts,
+            headers: HashMap::new(),
+            limits,
+            header_room: limits.header_bytes,
+            header_count: 0,
+            framing: Framing::None,
+        })
+    }
 
-        loop {
-            let mut header_line = String::new();
-            let _ = buf.read_line(&mut header_line)?;
-            let trimmed_header_line = header_line.trim();
-            if trimmed_header_line.is_empty() {
-                // I think we have reached the body at this point
-                break;
-            }
-            let key_value = trimmed_header_line
-                .split_terminator(":")
-                .collect::<Vec<&str>>();
-            let key = Headers::from(key_value[0]);
-            let raw_value = key_value[1].trim();
-            let concat_parts = raw_value.replace(", ", ",");
-            headers
-                .entry(key)
-                // in-place mutation
-                .and_modify(|val| {
-                    *val = format!("{},{}", val, concat_parts);
-                })
-                .or_insert(concat_parts.to_owned());
+    // How much the next header line may read
+    fn header_room(&self) -> u64 {
+        self.header_room as u64
+    }
+
+    // Returns false once the blank line ending the head has been reached
+    fn add_header(&mut self, header_line: &str) -> Result<bool> {
+        if cut_off(header_line, self.header_room) {
+            return Err(ClientError::HeadersTooLarge.into());
         }
+        self.header_room -= header_line.len();
+        // A folded line continues the one before it, which some parsers
+        // would join and others wouldn't
+        if header_line.starts_with([' ', '\t']) {
+            return Err(ClientError::BadRequest.into());
+        }
+        let trimmed_header_line = header_line.trim();
+        if trimmed_header_line.is_empty() {
+            // I think we have reached the body at this point
+            self.framing = self.framing()?;
+            return Ok(false);
+        }
+        // Same for a bare CR, some would end the line there
+        if trimmed_header_line.contains('\r') {
+            return Err(ClientError::BadRequest.into());
+        }
+        self.header_count += 1;
+        if self.header_count > self.limits.headers {
+            return Err(ClientError::HeadersTooLarge.into());
+        }
+        // A header line without a colon used to panic the worker
+        let Some((raw_key, raw_value)) = trimmed_header_line.split_once(':') else {
+            return Err(ClientError::BadRequest.into());
+        };
+        // No space is allowed before the colon, so "Content-Length : 5" can't
+        // be taken as Content-Length by anyone
+        if !is_token(raw_key) {
+            return Err(ClientError::BadRequest.into());
+        }
+        let key = Headers::from(raw_key);
+        // Even if they agree, there's no good reason to send two
+        if key == Headers::ContentLength && self.headers.contains_key(&key) {
+            return Err(ClientError::BadRequest.into());
+        }
+        let raw_value = raw_value.trim();
+        let concat_parts = raw_value.replace(", ", ",");
+        self.headers
+            .entry(key)
+            // in-place mutation
+            .and_modify(|val| {
+                *val = format!("{},{}", val, concat_parts);
+            })
+            .or_insert(concat_parts.to_owned());
+        Ok(true)
+    }
 
-        let mut body_buf: Vec<u8> = vec![];
+    // Where the body ends, following
+    // https://www.rfc-editor.org/rfc/rfc9112#name-message-body-length
+    // Anything ambiguous is rejected rather than guessed at, a proxy in front
+    // of us might guess differently and hide a second request in the body.
+    fn framing(&self) -> Result<Framing> {
+        let length = self.headers.get(&Headers::ContentLength);
+        let Some(codings) = self.headers.get(&Headers::TransferEncoding) else {
+            // If there's no content length, do not attempt to parse the body.
+            // It has to be read even if we don't use it, otherwise it would
+            // be taken for the start of the next request on a persistent
+            // connection
+            return match length {
+                Some(len) => self.content_length(len).map(Framing::Length),
+                None => Ok(Framing::None),
+            };
+        };
+        // HTTP/1.0 has no transfer codings, so whoever sent one is confused
+        if length.is_some() || self.version == "HTTP/1.0" {
+            return Err(ClientError::BadRequest.into());
+        }
+        let codings: Vec<String> = codings
+            .split(',')
+            .map(|coding| coding.trim().to_ascii_lowercase())
+            .collect();
+        if !codings.iter().all(|coding| is_token(coding)) {
+            return Err(ClientError::BadRequest.into());
+        }
+        // chunked has to be there exactly once, at the end, or there's no
+        // telling where the body ends
+        if codings.iter().position(|coding| coding == "chunked") != Some(codings.len() - 1) {
+            return Err(ClientError::BadRequest.into());
+        }
+        // A valid chain, but we only decode chunked
+        if codings.len() > 1 {
+            return Err(ServerError::NotImplemented.into());
+        }
+        Ok(Framing::Chunked)
+    }
 
-        if route == Route::Echo && path_parts.len() > 1 {
-            body_buf.extend(path_parts[1].as_bytes());
-        } else {
-            // If there's no content length, do not attempt to parse the body
-            if let Some(len) = headers.get(&Headers::ContentLength) {
-                let len = len.parse::<u64>()?;
-                buf.take(len).read_to_end(&mut body_buf)?;
-            }
+    fn content_length(&self, len: &str) -> Result<u64> {
+        // Only digits, parse would take a + sign
+        if len.is_empty() || !len.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ClientError::BadRequest.into());
         }
+        let len = len
+            .parse::<u64>()
+            .map_err(|_| AppError::from(ClientError::BadRequest))?;
+        // Turned away before any of it is read
+        if len > self.limits.body {
+            return Err(ClientError::ContentTooLarge.into());
+        }
+        Ok(len)
+    }
 
-        Ok(Self {
-            route,
-            path,
-            method,
-            headers,
+    // The trailers share what's left of the header limit
+    fn chunked(&self) -> Chunked {
+        Chunked::new(self.limits.body, self.header_room)
+    }
+
+    // The request as far as it goes without its body, so that it can be
+    // turned away before any of the body is read
+    pub fn preview(&self) -> Request {
+        Request {
+            route: self.route.clone(),
+            path: self.path.clone(),
+            query: self.query.clone(),
+            method: self.method.clone(),
+            headers: self.headers.clone(),
+            body: Vec::new(),
+            path_parts: self.path_parts.clone(),
+            keep_alive: self.keep_alive(),
+            client: None,
+            user: None,
+            client_cert: None,
+        }
+    }
+
+    // HTTP/1.1 connections are persistent unless the client says otherwise,
+    // HTTP/1.0 ones have to ask for it
+    fn keep_alive(&self) -> bool {
+        let connection = self
+            .headers
+            .get(&Headers::Connection)
+            .map(|c| c.to_ascii_lowercase());
+        match connection.as_deref() {
+            Some("close") => false,
+            Some("keep-alive") => true,
+            _ => self.version != "HTTP/1.0",
+        }
+    }
+
+    fn into_request(self, mut body_buf: Vec<u8>) -> Request {
+        if self.route == Route::Echo && self.path_parts.len() > 1 {
+            body_buf = self.path_parts[1].as_bytes().to_vec();
+        }
+        let keep_alive = self.keep_alive();
+
+        Request {
+            route: self.route,
+            path: self.path,
+            query: self.query,
+            method: self.method,
+            headers: self.headers,
             body: body_buf,
-            path_parts,
-        })
+            path_parts: self.path_parts,
+            keep_alive,
+            client: None,
+            user: None,
+            client_cert: None,
+        }
     }
 }
 
@@ -112,8 +471,9 @@ impl<R: Read> TryFrom<&mut BufReader<R>> for Request {
 mod tests {
 
     mod request {
-        use crate::errors::{AppError, ClientError};
+        use crate::errors::{AppError, ClientError, ServerError};
         use crate::http::request::{Method::Get, Request};
+        use crate::http::Limits;
         use crate::router::Route::Echo;
         use std::{collections::HashMap, io::BufReader};
 
@@ -126,11 +486,23 @@ mod tests {
                 method: Get,
                 route: Echo,
                 path: "/echo/abc".to_owned(),
+                query: None,
                 path_parts: vec!["echo".to_owned(), "abc".to_owned()],
                 body: b"abc".to_vec(),
                 headers: HashMap::new(),
+                keep_alive: true,
+                client: None,
+                user: None,
+                client_cert: None,
             };
             assert_eq!(expected, Request::try_from(&mut req_buf).unwrap());
+
+            let req = b"GET /echo/abc?x=1&y HTTP/1.1\r\n\r\n";
+            let req = Request::try_from(&mut BufReader::new(req.as_slice())).unwrap();
+            assert_eq!(
+                ("/echo/abc", Some("x=1&y"), b"abc".as_slice()),
+                (req.path.as_str(), req.query.as_deref(), req.body.as_slice())
+            );
         }
 
         #[test]
@@ -143,5 +515,220 @@ mod tests {
                 Request::try_from(&mut req_buf).unwrap_err()
             );
         }
+
+        #[test]
+        fn canonicalises_paths() {
+            let path = |target: &str| {
+                let req = format!("GET {} HTTP/1.1\r\n\r\n", target);
+                Request::try_from(&mut BufReader::new(req.as_bytes()))
+                    .map(|req| (req.path, req.path_parts))
+            };
+            let files_a = (
+                "/files/a".to_owned(),
+                vec!["files".to_owned(), "a".to_owned()],
+            );
+            for target in [
+                "/files/a",
+                "//files/a",
+                "/files//a",
+                "/files/a/",
+                "//files///a//",
+            ] {
+                assert_eq!(Ok(files_a.clone()), path(target), "{}", target);
+            }
+            assert_eq!(Ok(("/".to_owned(), Vec::new())), path("//"));
+            for bad in [
+                "/files/../a",
+                "/files/./a",
+                "/..",
+                "files/a",
+                "*",
+                "http://host/a",
+            ] {
+                assert_eq!(
+                    Err(AppError::Client(ClientError::BadRequest)),
+                    path(bad),
+                    "{}",
+                    bad
+                );
+            }
+        }
+
+        #[test]
+        fn handles_connection_header() {
+            let cases: [(&[u8], bool); 4] = [
+                (b"GET / HTTP/1.1\r\n\r\n", true),
+                (b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n", false),
+                (b"GET / HTTP/1.0\r\n\r\n", false),
+                (b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n", true),
+            ];
+            for (req, keep_alive) in cases {
+                let mut req_buf = BufReader::new(req);
+                assert_eq!(
+                    keep_alive,
+                    Request::try_from(&mut req_buf).unwrap().keep_alive
+                );
+            }
+        }
+
+        #[test]
+        fn handles_malformed_header() {
+            let req = b"GET / HTTP/1.1\r\nno-colon-here\r\n\r\n";
+            let mut req_slice = req.as_slice();
+            let mut req_buf = BufReader::new(&mut req_slice);
+            assert_eq!(
+                AppError::Client(ClientError::BadRequest),
+                Request::try_from(&mut req_buf).unwrap_err()
+            );
+        }
+
+        #[test]
+        fn enforces_limits() {
+            let mut limits = Limits {
+                request_line: 26,
+                header_bytes: 30,
+                headers: 2,
+                body: 4,
+                ..Limits::default()
+            };
+            limits.set_mount("/files:max_body=10").unwrap();
+            let read = |req: &[u8]| Request::read(&mut BufReader::new(req), &limits);
+
+            assert!(read(b"GET /echo/abc HTTP/1.1\r\n\r\n").is_ok());
+            let cases: [(&[u8], ClientError); 5] = [
+                (
+                    b"GET /echo/abcdefghij HTTP/1.1\r\n\r\n",
+                    ClientError::UriTooLong,
+                ),
+                (
+                    b"GET / HTTP/1.1\r\nUser-Agent: abcdefghijklmnopqrstuvwxyz\r\n\r\n",
+                    ClientError::HeadersTooLarge,
+                ),
+                (
+                    b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n",
+                    ClientError::HeadersTooLarge,
+                ),
+                (
+                    b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde",
+                    ClientError::ContentTooLarge,
+                ),
+                (
+                    b"POST /files/a HTTP/1.1\r\nContent-Length: 11\r\n\r\n",
+                    ClientError::ContentTooLarge,
+                ),
+            ];
+            for (req, err) in cases {
+                assert_eq!(AppError::Client(err), read(req).unwrap_err());
+            }
+            // The blank line ending the head counts too
+            assert!(read(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n").is_ok());
+            let req = read(b"POST /files/a HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789");
+            assert_eq!(10, req.unwrap().body.len());
+        }
+
+        #[test]
+        fn reads_chunked_bodies() {
+            let req = b"POST /files/a HTTP/1.1\r\ntransfer-encoding: Chunked\r\n\r\n\
+                        5\r\nhello\r\n6\r\n world\r\n0\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+            let mut req_buf = BufReader::new(req.as_slice());
+            let req = Request::try_from(&mut req_buf).unwrap();
+            assert_eq!(b"hello world".to_vec(), req.body);
+            // Lower case names count, or the body would be the next request
+            let req = b"POST /files/a HTTP/1.1\r\ncontent-length: 3\r\n\r\nabc";
+            let req = Request::try_from(&mut BufReader::new(req.as_slice())).unwrap();
+            assert_eq!(b"abc".to_vec(), req.body);
+        }
+
+        #[test]
+        fn rejects_smuggling_payloads() {
+            let cases: [(&[u8], AppError); 14] = [
+                // CL.CL
+                (
+                    b"POST / HTTP/1.1\r\nContent-Length: 4\r\nContent-Length: 40\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                (
+                    b"POST / HTTP/1.1\r\nContent-Length: 4\r\nContent-Length: 4\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                (
+                    b"POST / HTTP/1.1\r\nContent-Length: 4, 4\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                (
+                    b"POST / HTTP/1.1\r\nContent-Length: +4\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                (
+                    b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                // CL.TE and TE.CL
+                (
+                    b"POST / HTTP/1.1\r\nContent-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n\
+                      0\r\n\r\nG",
+                    ClientError::BadRequest.into(),
+                ),
+                (
+                    b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n\
+                      5c\r\nGPOST / HTTP/1.1\r\n\r\n0\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                // TE.TE, obfuscated so one side misses it
+                (
+                    b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: x\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                (
+                    b"POST / HTTP/1.1\r\nTransfer-Encoding : chunked\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                (
+                    b"POST / HTTP/1.1\r\nTransfer-Encoding:\r\n chunked\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                (
+                    b"POST / HTTP/1.1\r\nX: a\rTransfer-Encoding: chunked\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                (
+                    b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, chunked\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                (
+                    b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+                    ClientError::BadRequest.into(),
+                ),
+                // Valid, but not something we decode
+                (
+                    b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+                    ServerError::NotImplemented.into(),
+                ),
+            ];
+            for (req, err) in cases {
+                let mut req_buf = BufReader::new(req);
+                assert_eq!(
+                    err,
+                    Request::try_from(&mut req_buf).unwrap_err(),
+                    "{:?}",
+                    String::from_utf8_lossy(req)
+                );
+            }
+        }
+
+        #[cfg(feature = "tokio")]
+        #[tokio::test]
+        async fn reads_async_requests() {
+            let req = b"POST /files/a HTTP/1.0\r\nContent-Length: 3\r\n\r\nabcdef";
+            let mut req_buf = tokio::io::BufReader::new(req.as_slice());
+            let req = Request::read_head_async(&mut req_buf, &Limits::default())
+                .await
+                .unwrap()
+                .read_body_async(&mut req_buf)
+                .await
+                .unwrap();
+            assert_eq!(b"abc".to_vec(), req.body);
+            assert!(!req.keep_alive);
+        }
     }
 }
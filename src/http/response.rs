@@ -9,6 +9,7 @@ pub struct Response {
     body: Option<Vec<u8>>,
     mime_type: Option<MimeType>,
     encoding: Option<Vec<Encoding>>,
+    headers: Vec<(Headers, String)>,
 }
 
 impl Response {
@@ -46,6 +47,11 @@ impl Response {
             .status_code(StatusCode::Created)
             .build()
     }
+    pub fn accepted() -> Result<Response> {
+        ResponseBuilder::new()
+            .status_code(StatusCode::Accepted)
+            .build()
+    }
     pub fn client_error() -> Result<Response> {
         ResponseBuilder::new()
             .status_code(StatusCode::ClientError)
@@ -56,28 +62,71 @@ impl Response {
             .status_code(StatusCode::ServerError)
             .build()
     }
+    pub fn service_unavailable(retry_after: u64) -> Result<Response> {
+        ResponseBuilder::new()
+            .status_code(StatusCode::ServiceUnavailable)
+            .header(Headers::RetryAfter, retry_after.to_string())
+            .build()
+    }
+    pub fn too_many_requests(retry_after: u64) -> Result<Response> {
+        ResponseBuilder::new()
+            .status_code(StatusCode::TooManyRequests)
+            .header(Headers::RetryAfter, retry_after.to_string())
+            .build()
+    }
+    // Challenge is what goes in WWW-Authenticate
+    pub fn unauthorized(challenge: String) -> Result<Response> {
+        ResponseBuilder::new()
+            .status_code(StatusCode::Unauthorized)
+            .header(Headers::WwwAuthenticate, challenge)
+            .build()
+    }
+    // For headers added once the handler is done with the response
+    pub fn header(mut self, header: Headers, value: String) -> Self {
+        self.headers.push((header, value));
+        self
+    }
+    // Tells the client that we are going to close the connection after this
+    pub fn close(mut self) -> Self {
+        self.headers.push((Headers::Connection, "close".to_owned()));
+        self
+    }
     pub fn as_bytes(&self) -> Vec<u8> {
         let empty: Vec<u8> = Vec::new();
         let content = self.body.as_ref().unwrap_or(&empty);
+        let mut head = format!("{} {}\r\n", HTTP_VERSION, self.status_code);
         if !content.is_empty() {
-            let mut response = format!(
-                "{} {}\r\n{}: {content_type}\r\n{}: {content_length}\r\n\r\n",
-                HTTP_VERSION,
-                self.status_code,
+            head.push_str(&format!(
+                "{}: {}\r\n",
                 Headers::ContentType,
-                Headers::ContentLength,
-                content_type = self.mime_type.as_ref().unwrap_or(&MimeType::Unknown),
-                content_length = content.len(),
-            )
-            .as_bytes()
-            .to_vec();
-            response.extend_from_slice(content);
-            response
-        } else {
-            format!("{} {}\r\n", HTTP_VERSION, self.status_code)
-                .as_bytes()
-                .to_vec()
+                self.mime_type.as_ref().unwrap_or(&MimeType::Unknown),
+            ));
         }
+        // Always sent, even when it's zero, otherwise a client on a persistent
+        // connection can't tell where the response ends
+        head.push_str(&format!(
+            "{}: {}\r\n",
+            Headers::ContentLength,
+            content.len()
+        ));
+        for (header, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", header, value));
+        }
+        head.push_str("\r\n");
+        let mut response = head.into_bytes();
+        response.extend_from_slice(content);
+        response
+    }
+    #[cfg(feature = "tokio")]
+    pub async fn write_async<W>(&self, w: &mut W) -> Result<()>
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::AsyncWriteExt;
+
+        w.write_all(&self.as_bytes()).await?;
+        w.flush().await?;
+        Ok(())
     }
 }
 
@@ -87,6 +136,7 @@ pub struct ResponseBuilder {
     body: Option<Vec<u8>>,
     mime_type: Option<MimeType>,
     encoding: Option<Vec<Encoding>>,
+    headers: Vec<(Headers, String)>,
 }
 
 impl ResponseBuilder {
@@ -106,6 +156,10 @@ impl ResponseBuilder {
         self.body = body;
         self
     }
+    pub fn header(mut self, header: Headers, value: String) -> Self {
+        self.headers.push((header, value));
+        self
+    }
     pub fn encoding(mut self, encoding: Option<&String>) -> Self {
         if let Some(encoding_string) = encoding {
             self.encoding = Some(
@@ -123,6 +177,7 @@ impl ResponseBuilder {
             body: self.body,
             mime_type: self.mime_type,
             encoding: self.encoding,
+            headers: self.headers,
         };
         response.validate()?;
         Ok(response)
@@ -1,16 +1,54 @@
+mod access;
+mod auth;
+mod client_cert;
 mod config;
 mod dir;
 mod errors;
 mod handlers;
 mod http;
+mod rate_limit;
 mod router;
 mod server;
+mod signed_url;
+#[cfg(feature = "tls")]
+mod tls;
+mod token;
 
 pub(crate) mod constants {
     pub const TARGET_DIR: &str = "/tmp";
     pub const ADDRESS: &str = "127.0.0.1:4221";
+    // Only used when the available parallelism can't be determined
+    pub const THREADS: usize = 8;
+    pub const QUEUE_DEPTH: usize = 64;
+    pub const MAX_PARKED: usize = 10_000;
+    pub const RETRY_AFTER_SECS: u64 = 1;
+    pub const SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+    pub const ENV_PREFIX: &str = "HTTP_SERVER_";
     pub const HTTP_VERSION: &str = "HTTP/1.1";
+    pub const MAX_REQUEST_LINE: usize = 8 * 1024;
+    pub const MAX_HEADER_BYTES: usize = 32 * 1024;
+    pub const MAX_HEADERS: usize = 100;
+    pub const MAX_BODY: u64 = 16 * 1024 * 1024;
+    pub const HEADER_TIMEOUT_SECS: u64 = 10;
+    pub const BODY_TIMEOUT_SECS: u64 = 30;
+    pub const WRITE_TIMEOUT_SECS: u64 = 30;
+    pub const KEEP_ALIVE_TIMEOUT_SECS: u64 = 30;
+    // For Basic auth challenges that don't name their own
+    pub const REALM: &str = "http-server-rust";
 }
 
 // Re-exports for main.rs
-pub use {config::Config, errors::Result, server::Server};
+#[cfg(feature = "tokio")]
+pub use server::AsyncServer;
+pub use {
+    access::{AccessRule, Cidr, IpList, Scope},
+    auth::{AuthRule, Challenge, Users, Verdict},
+    client_cert::ClientCert,
+    config::{Command, Config},
+    errors::Result,
+    http::{Limits, MountLimits},
+    rate_limit::{RateKey, RateLimit},
+    server::{ListenAddr, Server, ShutdownHandle, Stats, Timeouts},
+    signed_url::{Link, Signed, UrlSigner},
+    token::Token,
+};
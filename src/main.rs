@@ -1,10 +1,64 @@
 #![warn(clippy::style, clippy::complexity, clippy::perf, clippy::correctness)]
 
-use http_server_rust::{Config, Result, Server};
+use http_server_rust::{Command, Config, Server};
+use log::{error, LevelFilter};
+use std::process::ExitCode;
+use std::time::SystemTime;
 
-fn main() -> Result<()> {
-    env_logger::init();
-    let config = Config::try_new()?;
-    let server = Server::try_new(&config)?;
-    server.start()
+fn main() -> ExitCode {
+    let config = match Config::try_new() {
+        Ok(Command::Run(config)) => config,
+        Ok(Command::Help) => {
+            print!("{}", Config::usage());
+            return ExitCode::SUCCESS;
+        }
+        Ok(Command::Version) => {
+            println!("{}", Config::version());
+            return ExitCode::SUCCESS;
+        }
+        Ok(Command::Sign(config, link)) => {
+            let Some(signer) = config.signer else {
+                eprintln!("Error: signing needs --signing_key");
+                return ExitCode::from(2);
+            };
+            println!("{}", signer.sign(&link, SystemTime::now()));
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            eprintln!("Try --help for usage");
+            return ExitCode::from(2);
+        }
+    };
+    init_logger(config.log_level);
+    let run = || -> http_server_rust::Result<()> {
+        let server = Server::try_new(&config)?;
+        let handle = server.shutdown_handle();
+        handle.shutdown_on_signals()?;
+        handle.upgrade_on_signal()?;
+        handle.reload_on_signal(Config::reload)?;
+        server.start()
+    };
+    if let Err(e) = run() {
+        error!("Server error: {}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+// env_logger is set up to let everything through, so that --log_level can be
+// raised on reload, and the level is capped with log::set_max_level instead.
+// RUST_LOG works as before, although whatever it filters out stays filtered.
+fn init_logger(level: Option<LevelFilter>) {
+    let logger = env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .parse_default_env()
+        .build();
+    let default = match std::env::var_os("RUST_LOG") {
+        Some(_) => logger.filter(),
+        None => LevelFilter::Error,
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level.unwrap_or(default));
+    }
 }
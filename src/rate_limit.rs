@@ -0,0 +1,306 @@
+use crate::http::{under, Headers, Request, Response};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+
+// The most clients remembered for one limit. Once there are this many, the
+// ones whose buckets have filled back up are forgotten, since they'd start from
+// a full bucket anyway, and then the least recently seen until there's room
+// for EVICT more. That way a full map is only swept every EVICT new clients.
+const MAX_CLIENTS: usize = 10_000;
+const EVICT: usize = MAX_CLIENTS / 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateKey {
+    Ip,
+    // Falls back to the IP for requests that haven't authenticated
+    User,
+}
+
+// A token bucket for everything under a path, the longest matching path wins.
+// Each client gets burst requests straight away, refilled at rate a second.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    pub path: String,
+    pub rate: f64,
+    pub burst: u32,
+    pub by: RateKey,
+}
+
+impl RateLimit {
+    // From PATH:rate=N,burst=N,by=ip|user. burst defaults to a second's worth
+    // and by to ip.
+    pub fn parse(spec: &str) -> Option<RateLimit> {
+        let (path, options) = spec.split_once(':')?;
+        if !path.starts_with('/') {
+            return None;
+        }
+        let (mut rate, mut burst, mut by) = (None, None, RateKey::Ip);
+        for option in options.split(',') {
+            let (option, val) = option.split_once('=')?;
+            match (option.trim(), val.trim()) {
+                ("rate", val) => rate = Some(val.parse::<f64>().ok()?),
+                ("burst", val) => burst = Some(val.parse::<u32>().ok()?),
+                ("by", "ip") => by = RateKey::Ip,
+                ("by", "user") => by = RateKey::User,
+                _ => return None,
+            }
+        }
+        let rate = rate.filter(|rate| rate.is_finite() && *rate > 0.0)?;
+        let burst = burst.unwrap_or(rate.ceil() as u32);
+        if burst == 0 {
+            return None;
+        }
+        Some(RateLimit {
+            path: path.trim_end_matches('/').to_owned(),
+            rate,
+            burst,
+            by,
+        })
+    }
+}
+
+// Where a client stands after a request, for the RateLimit-* headers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    // Seconds until the bucket is full again
+    pub reset: u64,
+    // Seconds until the next request would be let through, only set when
+    // this one wasn't
+    pub retry_after: Option<u64>,
+}
+
+impl Quota {
+    pub fn headers(&self, resp: Response) -> Response {
+        resp.header(Headers::RateLimitLimit, self.limit.to_string())
+            .header(Headers::RateLimitRemaining, self.remaining.to_string())
+            .header(Headers::RateLimitReset, self.reset.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    User(String),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// The buckets for every client of one limit
+#[derive(Debug)]
+struct Limited {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<Client, Bucket>>,
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: Vec<Limited>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &[RateLimit]) -> Self {
+        Self {
+            limits: limits
+                .iter()
+                .map(|limit| Limited {
+                    limit: limit.clone(),
+                    buckets: Mutex::new(HashMap::new()),
+                })
+                .collect(),
+        }
+    }
+
+    // Takes a token for the request. None if no limit applies to it, otherwise
+    // the quota left, with retry_after set if it has to be turned away.
+    pub fn check(&self, req: &Request) -> Option<Quota> {
+        self.check_at(&req.path, req.client, req.user.as_deref(), Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        path: &str,
+        ip: Option<IpAddr>,
+        user: Option<&str>,
+        now: Instant,
+    ) -> Option<Quota> {
+        let limited = self
+            .limits
+            .iter()
+            .filter(|l| under(path, &l.limit.path))
+            .max_by_key(|l| l.limit.path.len())?;
+        let limit = &limited.limit;
+        // Unix socket clients have no address to tell them apart
+        let client = match (limit.by, user) {
+            (RateKey::User, Some(user)) => Client::User(user.to_owned()),
+            _ => Client::Ip(network(ip?)),
+        };
+        let burst = f64::from(limit.burst);
+        let mut buckets = limited
+            .buckets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+            evict(&mut buckets, limit, now);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = burst.min(bucket.tokens + elapsed(bucket, now) * limit.rate);
+        bucket.updated = now;
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(((1.0 - bucket.tokens) / limit.rate).ceil() as u64)
+        };
+        Some(Quota {
+            limit: limit.burst,
+            remaining: bucket.tokens as u32,
+            reset: ((burst - bucket.tokens) / limit.rate).ceil() as u64,
+            retry_after,
+        })
+    }
+}
+
+fn evict(buckets: &mut HashMap<Client, Bucket>, limit: &RateLimit, now: Instant) {
+    let burst = f64::from(limit.burst);
+    buckets.retain(|_, b| b.tokens + elapsed(b, now) * limit.rate < burst);
+    let Some(over) = buckets.len().checked_sub(MAX_CLIENTS - EVICT) else {
+        return;
+    };
+    if over == 0 {
+        return;
+    }
+    let mut seen: Vec<Instant> = buckets.values().map(|b| b.updated).collect();
+    let (_, cutoff, _) = seen.select_nth_unstable(over - 1);
+    let cutoff = *cutoff;
+    buckets.retain(|_, b| b.updated > cutoff);
+}
+
+// IPv6 clients usually get a whole /64 each, so that's what gets a bucket.
+// Otherwise one client could make up a fresh address for every request.
+pub(crate) fn network(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (u128::MAX << 64))),
+        ip => ip,
+    }
+}
+
+fn elapsed(bucket: &Bucket, now: Instant) -> f64 {
+    now.saturating_duration_since(bucket.updated).as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod rate_limit {
+        use crate::rate_limit::{RateKey, RateLimit, RateLimiter, MAX_CLIENTS};
+        use std::net::{IpAddr, Ipv4Addr};
+        use std::time::{Duration, Instant};
+
+        const A: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        const B: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+
+        #[test]
+        fn parses_limits() {
+            assert_eq!(
+                Some(RateLimit {
+                    path: "/files".to_owned(),
+                    rate: 0.5,
+                    burst: 1,
+                    by: RateKey::User,
+                }),
+                RateLimit::parse("/files/:rate=0.5,by=user")
+            );
+            assert_eq!(
+                Some(10),
+                RateLimit::parse("/:rate=2,burst=10").map(|l| l.burst)
+            );
+            for bad in [
+                "files:rate=1",
+                "/:burst=1",
+                "/:rate=0",
+                "/:rate=1,by=cookie",
+            ] {
+                assert_eq!(None, RateLimit::parse(bad), "{}", bad);
+            }
+        }
+
+        #[test]
+        fn refills_buckets_per_client() {
+            let limiter = RateLimiter::new(&[
+                RateLimit::parse("/:rate=1,burst=2").unwrap(),
+                RateLimit::parse("/files:rate=10,burst=1,by=user").unwrap(),
+            ]);
+            let start = Instant::now();
+            let check = |ip, secs| limiter.check_at("/echo/a", ip, None, start + secs);
+
+            let quota = check(A, Duration::ZERO).unwrap();
+            assert_eq!(
+                (2, 1, 1, None),
+                (quota.limit, quota.remaining, quota.reset, quota.retry_after)
+            );
+            assert_eq!(None, check(A, Duration::ZERO).unwrap().retry_after);
+            assert_eq!(Some(1), check(A, Duration::ZERO).unwrap().retry_after);
+            // Someone else's bucket is their own
+            assert_eq!(None, check(B, Duration::ZERO).unwrap().retry_after);
+            assert_eq!(None, check(A, Duration::from_secs(1)).unwrap().retry_after);
+            assert_eq!(
+                Some(1),
+                check(A, Duration::from_secs(1)).unwrap().retry_after
+            );
+
+            // Users get a bucket each wherever they connect from, everyone
+            // else is counted by address
+            let files = |ip, user| limiter.check_at("/files/a", ip, user, start);
+            assert_eq!(None, files(A, Some("alice")).unwrap().retry_after);
+            assert_eq!(None, files(B, Some("bob")).unwrap().retry_after);
+            assert!(files(B, Some("alice")).unwrap().retry_after.is_some());
+            assert_eq!(None, files(A, None).unwrap().retry_after);
+            assert_eq!(None, limiter.check_at("/files/a", None, None, start));
+        }
+
+        #[test]
+        fn buckets_ipv6_networks_and_caps_the_clients() {
+            let limiter = RateLimiter::new(&[RateLimit::parse("/:rate=1,burst=1").unwrap()]);
+            let start = Instant::now();
+            let check = |ip: &str, secs| {
+                limiter
+                    .check_at("/", Some(ip.parse().unwrap()), None, start + secs)
+                    .unwrap()
+                    .retry_after
+            };
+
+            // Anywhere in the same /64 shares a bucket
+            assert_eq!(None, check("2001:db8::1", Duration::ZERO));
+            assert_eq!(Some(1), check("2001:db8::ffff:2", Duration::ZERO));
+            assert_eq!(None, check("2001:db8:0:1::1", Duration::ZERO));
+            // IPv4 clients mapped into IPv6 are still told apart
+            assert_eq!(None, check("::ffff:10.0.0.1", Duration::ZERO));
+            assert_eq!(None, check("::ffff:10.0.0.2", Duration::ZERO));
+            assert_eq!(Some(1), check("10.0.0.1", Duration::ZERO));
+
+            // None of these have refilled, so the earliest seen make room
+            for i in 0..MAX_CLIENTS as u32 {
+                let ip = std::net::Ipv4Addr::from(0x0b00_0000 + i).to_string();
+                check(&ip, Duration::from_millis(1 + u64::from(i) / 100));
+            }
+            let later = Duration::from_millis(1 + MAX_CLIENTS as u64 / 100);
+            assert!(limiter.limits[0].buckets.lock().unwrap().len() <= MAX_CLIENTS);
+            // Forgotten, so back to a full bucket
+            assert_eq!(None, check("2001:db8::1", later));
+            // Still remembered
+            let last = std::net::Ipv4Addr::from(0x0b00_0000 + MAX_CLIENTS as u32 - 1);
+            assert_eq!(Some(1), check(&last.to_string(), later));
+        }
+    }
+}
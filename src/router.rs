@@ -1,17 +1,29 @@
 use crate::{
+    access::AccessRule,
+    auth::{AuthRule, Users, Verdict},
+    client_cert::ClientCert,
     dir::FileSystemAccess,
+    errors::AppError,
     handlers::*,
-    http::{ClientError, Method, Request, ServerError},
+    http::{ClientError, Headers, Limits, Method, Request, RequestHead, Response, ServerError},
+    rate_limit::{Quota, RateLimit, RateLimiter},
+    server::ShutdownHandle,
+    signed_url::{Signed, UrlSigner},
+    token::Token,
     Result,
 };
+use log::{debug, info};
 use std::io::{BufReader, Read, Write};
+use std::net::IpAddr;
+use std::time::SystemTime;
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Route {
     Empty,
     Echo,
     UserAgent,
     Files,
+    Admin,
     Unknown,
 }
 
@@ -21,6 +33,7 @@ impl From<&str> for Route {
             "echo" => Self::Echo,
             "user-agent" => Self::UserAgent,
             "files" => Self::Files,
+            "admin" => Self::Admin,
             "/" => Self::Empty,
             _ => Self::Unknown,
         }
@@ -33,6 +46,7 @@ enum Operation {
     GetFileContents,
     PostFileContents,
     GetEmpty,
+    PostUpgrade,
     Unsupported,
     Unknown,
 }
@@ -45,6 +59,9 @@ impl From<&Request> for Operation {
             (Method::Post, Route::Files) => Self::PostFileContents,
             (Method::Get, Route::UserAgent) => Self::GetUserAgent,
             (Method::Get, Route::Empty) => Self::GetEmpty,
+            (Method::Post, Route::Admin) if value.path_parts[1..] == ["upgrade"] => {
+                Self::PostUpgrade
+            }
             (Method::Unsupported, _) => Self::Unsupported,
             (Method::Get, Route::Unknown)
             | (Method::Post, Route::Unknown)
@@ -54,18 +71,72 @@ impl From<&Request> for Operation {
     }
 }
 
+// The admin endpoints aren't authenticated, so they are only for clients on
+// the same machine: Unix socket clients, who have no address, and loopback
+fn may_control(client: Option<IpAddr>) -> bool {
+    client.map_or(true, |ip| ip.to_canonical().is_loopback())
+}
+
+// Tells the client where it stands, when the path is rate limited
+fn with_quota(quota: Option<Quota>, resp: Response) -> Response {
+    match quota {
+        Some(quota) => quota.headers(resp),
+        None => resp,
+    }
+}
+
 impl From<&String> for Route {
     fn from(s: &String) -> Self {
         Route::from(s.as_str())
     }
 }
 
+// What the server knows about a connection beyond its bytes, passed to route
+// alongside it so any Read + Write can still be routed
+pub trait ConnInfo {
+    // Lets the router tell the connection when it has moved on from the head
+    // of a request to the body, so the server can time the two differently
+    fn reading_body(&self);
+
+    // The certificate the client verified with, only known once some of the
+    // request has been read
+    fn client_cert(&self) -> Option<ClientCert> {
+        None
+    }
+}
+
+// What check_head made of a request
+#[derive(Debug)]
+pub enum Admission {
+    // Let in, as the user who logged in if any, with where that left the
+    // client's quota
+    Admitted {
+        user: Option<String>,
+        quota: Option<Quota>,
+    },
+    // Turned away with the response, before any of the body is needed
+    Refused(Response),
+}
+
 #[derive(Debug)]
 pub struct Router<T>
 where
     T: FileSystemAccess,
 {
     dir: T,
+    // Only set when the admin endpoints are enabled
+    control: Option<ShutdownHandle>,
+    limits: Limits,
+    // Which addresses may use which paths
+    access: Vec<AccessRule>,
+    // Where logging in is needed, and who can
+    auth: Vec<AuthRule>,
+    users: Users,
+    tokens: Vec<Token>,
+    // Signed links stand in for a login when there's a key to check them
+    signer: Option<UrlSigner>,
+    // Buckets start full again whenever the router is replaced on reload
+    rate_limiter: RateLimiter,
 }
 
 impl<T> Router<T>
@@ -76,37 +147,298 @@ where
     where
         T: FileSystemAccess,
     {
-        Router { dir }
+        Router {
+            dir,
+            control: None,
+            limits: Limits::default(),
+            access: Vec::new(),
+            auth: Vec::new(),
+            users: Users::default(),
+            tokens: Vec::new(),
+            signer: None,
+            rate_limiter: RateLimiter::default(),
+        }
+    }
+
+    // Turns on the /admin endpoints, which act on the server through handle
+    pub fn with_control(mut self, handle: ShutdownHandle) -> Self {
+        self.control = Some(handle);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_access(mut self, access: &[AccessRule]) -> Self {
+        self.access = access.to_vec();
+        self
+    }
+
+    pub fn with_auth(mut self, auth: &[AuthRule], users: &Users) -> Self {
+        self.auth = auth.to_vec();
+        self.users = users.clone();
+        self
+    }
+
+    pub fn with_tokens(mut self, tokens: &[Token]) -> Self {
+        self.tokens = tokens.to_vec();
+        self
+    }
+
+    pub fn with_signer(mut self, signer: Option<UrlSigner>) -> Self {
+        self.signer = signer;
+        self
+    }
+
+    pub fn with_rate_limits(mut self, rate_limits: &[RateLimit]) -> Self {
+        self.rate_limiter = RateLimiter::new(rate_limits);
+        self
+    }
+
+    #[cfg(any(feature = "reactor", feature = "tokio"))]
+    pub fn limits(&self) -> &Limits {
+        &self.limits
     }
 
-    pub fn route<'a, U>(&self, stream: &'a U) -> Result<()>
+    // Handles a single request from the connection, returning whether the
+    // connection can be used for another one. Pass close when the server wants
+    // the connection closed regardless of what the client asked for, and info
+    // when the server has more to say about the connection.
+    pub fn route<S>(
+        &self,
+        conn: &mut BufReader<S>,
+        close: bool,
+        client: Option<IpAddr>,
+        info: Option<&dyn ConnInfo>,
+    ) -> Result<bool>
     where
-        &'a U: Write + Read,
+        S: Read + Write,
     {
-        let mut s = stream;
+        let client_cert = info.and_then(ConnInfo::client_cert);
+        let req = Request::read_head(conn, &self.limits).and_then(|mut head| {
+            match self.check_head(&mut head, client, client_cert.clone())? {
+                Admission::Refused(refused) => Ok(Err(refused)),
+                Admission::Admitted { user, quota } => {
+                    if let Some(info) = info {
+                        info.reading_body();
+                    }
+                    head.read_body(conn)
+                        .map(|req| Ok((Request { user, ..req }, quota)))
+                }
+            }
+        });
+        let (req, quota) = match req {
+            Ok(Ok((req, quota))) => (
+                Request {
+                    client,
+                    client_cert,
+                    ..req
+                },
+                quota,
+            ),
+            // The body is still to come, so this is the last response
+            Ok(Err(refused)) => {
+                conn.get_mut().write_all(&refused.close().as_bytes())?;
+                return Ok(false);
+            }
+            Err(e) => {
+                conn.get_mut().write_all(&self.rejected(&e)?.as_bytes())?;
+                return Err(e);
+            }
+        };
+        let resp = self.respond(&req, quota)?;
+        let (resp, keep_alive) = self.finish(&req, resp, close);
+        conn.get_mut().write_all(&resp.as_bytes())?;
+        Ok(keep_alive)
+    }
+
+    // Between reading the head and the body. Access rules, signed links and
+    // logins are all decided from the head, as is the client's quota, so a
+    // client that's turned away gets its 401, 403 or 429 before any of the body
+    // is read, and the response
+    // returned here is the last on the connection. An upload through a signed
+    // link is held to the link's max_size the same way as to its mount's
+    // limit, so a bigger body is never read in.
+    pub fn check_head(
+        &self,
+        head: &mut RequestHead,
+        client: Option<IpAddr>,
+        client_cert: Option<ClientCert>,
+    ) -> Result<Admission> {
+        let mut preview = Request {
+            client,
+            client_cert,
+            ..head.preview()
+        };
+        let admission = self.admit(&mut preview)?;
+        let Some(signer) = &self.signer else {
+            return Ok(admission);
+        };
+        match signer.check_head(head, SystemTime::now()) {
+            Signed::Valid {
+                max_size: Some(max),
+            } => head.limit_body(max).map(|_| admission),
+            _ => Ok(admission),
+        }
+    }
 
-        let mut req_buffer = BufReader::new(s);
-        let req = Request::try_from(&mut req_buffer)?;
-        let arg: HandlerArg<'_, &U> = HandlerArg::new(&req, &mut s);
+    // Whether check_head should be kept off an async executor thread, which
+    // is where a password might have to be hashed
+    #[cfg(feature = "tokio")]
+    pub fn head_blocks(&self, head: &RequestHead) -> bool {
+        AuthRule::required(&self.auth, &head.preview()).is_some()
+    }
+
+    fn admit(&self, req: &mut Request) -> Result<Admission> {
+        // Denied requests don't use up any of the client's quota
+        if !AccessRule::permits(&self.access, req) {
+            return ErrorHandler::handle(ErrorHandlerArg::new(ClientError::Forbidden.into()))
+                .map(Admission::Refused);
+        }
+        let signed = match &self.signer {
+            Some(signer) => signer.check(req, SystemTime::now()),
+            None => Signed::Unsigned,
+        };
+        // A link that checks out stands in for a login, one that doesn't is
+        // turned away without asking for one
+        let refused = match signed {
+            // From the head, so a token out of its scope never gets to send a body
+            Signed::Unsigned => match AuthRule::check(&self.auth, &self.users, &self.tokens, req) {
+                Verdict::Open => None,
+                Verdict::Allowed(user) => {
+                    debug!("{} {:?} {}", user, req.method, req.path);
+                    req.user = Some(user);
+                    None
+                }
+                Verdict::Challenge(challenge) => {
+                    if req.get_header(Headers::Authorization).is_some() {
+                        info!("Failed login from {:?} for {}", req.client, req.path);
+                    }
+                    Some(Response::unauthorized(challenge.to_string()))
+                }
+                Verdict::Denied(user) => {
+                    info!("{} isn't allowed {:?} {}", user, req.method, req.path);
+                    Some(Err(ClientError::Forbidden.into()))
+                }
+            },
+            Signed::Valid { .. } => None,
+            Signed::Invalid => {
+                info!(
+                    "Bad or expired signature from {:?} for {}",
+                    req.client, req.path
+                );
+                Some(Err(ClientError::Forbidden.into()))
+            }
+        };
+        // Failed logins and bad signatures still count against the client's
+        // quota, which slows down guessing. A throttled upload is turned away
+        // before its body is read, like any other.
+        let quota = self.rate_limiter.check(req);
+        let refused = match quota {
+            Some(Quota {
+                retry_after: Some(retry_after),
+                ..
+            }) => Some(Response::too_many_requests(retry_after)),
+            _ => refused,
+        };
+        let Some(refused) = refused else {
+            return Ok(Admission::Admitted {
+                user: req.user.clone(),
+                quota,
+            });
+        };
+        let resp = match refused {
+            Ok(resp) => resp,
+            Err(e) => ErrorHandler::handle(ErrorHandlerArg::new(e))?,
+        };
+        Ok(Admission::Refused(with_quota(quota, resp)))
+    }
+
+    // Runs the handler for a request check_head let in, turning any error into
+    // its response
+    pub fn respond(&self, req: &Request, quota: Option<Quota>) -> Result<Response> {
+        let resp = match self.handle(req) {
+            Ok(resp) => resp,
+            Err(e) => ErrorHandler::handle(ErrorHandlerArg::new(e))?,
+        };
+        Ok(with_quota(quota, resp))
+    }
 
-        if let Err(e) = match Operation::from(&req) {
+    fn handle(&self, req: &Request) -> Result<Response> {
+        let arg = HandlerArg::new(req);
+
+        match Operation::from(req) {
             Operation::GetEcho => EchoHandler::handle(arg),
             Operation::GetFileContents | Operation::PostFileContents => {
-                let arg = FileHandlerArg::new(&req, &mut s, &self.dir);
+                let arg = FileHandlerArg::new(req, &self.dir);
                 FileHandler::handle(arg)
             }
             Operation::GetUserAgent => UserAgentHandler::handle(arg),
             Operation::GetEmpty => EmptyHandler::handle(arg),
-            Operation::Unsupported => ErrorHandler::handle(ErrorHandlerArg::new(
-                &mut s,
-                ServerError::NotImplemented.into(),
-            )),
-            _ => ErrorHandler::handle(ErrorHandlerArg::new(&mut s, ClientError::BadRequest.into())),
-        } {
-            ErrorHandler::handle(ErrorHandlerArg::new(&mut s, e))
-        } else {
-            Ok(())
+            Operation::PostUpgrade => match &self.control {
+                Some(control) if may_control(req.client) => {
+                    control.upgrade();
+                    Response::accepted()
+                }
+                Some(_) => Err(ClientError::Forbidden.into()),
+                None => Err(ClientError::NotFound.into()),
+            },
+            Operation::Unsupported => Err(ServerError::NotImplemented.into()),
+            _ => Err(ClientError::BadRequest.into()),
         }
     }
+
+    // Whether the handler for the request touches the filesystem, or a
+    // password has to be hashed for it, and so it shouldn't be run on an async
+    // executor thread
+    #[cfg(feature = "tokio")]
+    pub fn blocks(&self, req: &Request) -> bool {
+        matches!(
+            Operation::from(req),
+            Operation::GetFileContents | Operation::PostFileContents
+        )
+    }
+
+    // The response to a request that couldn't be parsed. We can't tell where
+    // the next request would start, so this is the last one for the connection
+    pub fn rejected(&self, e: &AppError) -> Result<Response> {
+        Ok(ErrorHandler::handle(ErrorHandlerArg::new(e.clone()))?.close())
+    }
+
+    // Adds Connection: close to the response unless both sides are happy to
+    // keep the connection open, returning whether it is
+    pub fn finish(&self, req: &Request, resp: Response, close: bool) -> (Response, bool) {
+        let keep_alive = req.keep_alive && !close;
+        let resp = if keep_alive { resp } else { resp.close() };
+        (resp, keep_alive)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+
+    mod may_control {
+        use crate::router::may_control;
+
+        #[test]
+        fn only_lets_local_clients_in() {
+            for local in [
+                None,
+                Some("127.0.0.1"),
+                Some("::1"),
+                Some("::ffff:127.0.0.1"),
+            ] {
+                assert!(
+                    may_control(local.map(|ip| ip.parse().unwrap())),
+                    "{:?}",
+                    local
+                );
+            }
+            for remote in ["10.0.0.1", "192.168.1.1", "2001:db8::1", "::ffff:10.0.0.1"] {
+                assert!(!may_control(Some(remote.parse().unwrap())), "{}", remote);
+            }
+        }
+    }
+}
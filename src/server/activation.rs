@@ -0,0 +1,143 @@
+use super::{Listener, TLS_FD_NAME};
+use crate::{errors::ConfigError, Result};
+use log::info;
+use socket2::{Socket, Type};
+use std::io::{self, ErrorKind};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Socket activation as systemd does it, see sd_listen_fds(3). The parent opens
+// the listening sockets and passes them down starting at fd 3, LISTEN_FDS says
+// how many there are and LISTEN_PID which process they were meant for. Any
+// named tls in LISTEN_FDNAMES (FileDescriptorName=tls) are served with TLS.
+const LISTEN_FDS_START: RawFd = 3;
+
+// The fds belong to whichever Server takes them first, any others in the same
+// process bind as normal
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+// None when nothing was passed down and the caller should bind for itself
+pub fn inherited_listeners() -> Result<Option<Vec<Listener>>> {
+    inherited(|key| std::env::var(key).ok(), std::process::id())
+}
+
+fn inherited<F>(lookup: F, pid: u32) -> Result<Option<Vec<Listener>>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let (Some(listen_pid), Some(listen_fds)) = (lookup("LISTEN_PID"), lookup("LISTEN_FDS")) else {
+        return Ok(None);
+    };
+    // Left in the environment for a process further up the tree
+    if listen_pid.trim().parse::<u32>().ok() != Some(pid) {
+        return Ok(None);
+    }
+    let count = listen_fds
+        .trim()
+        .parse::<RawFd>()
+        .map_err(|_| ConfigError::InvalidEnv("LISTEN_FDS".to_owned(), listen_fds.clone()))?;
+    if count <= 0 || TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+    info!("Using {} listener(s) passed in by the parent", count);
+    let names = lookup("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| adopt(fd, names.next() == Some(TLS_FD_NAME)))
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+fn adopt(fd: RawFd, tls: bool) -> Result<Listener> {
+    // Checks the fd is open before anything takes ownership of it, and keeps
+    // it from leaking into anything we spawn
+    // SAFETY: fcntl(2) on an fd number, a closed one just fails with EBADF
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: the activation protocol hands these fds to this process and
+    // nothing else in it knows about them
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    if socket.r#type()? != Type::STREAM {
+        return Err(not_supported(fd).into());
+    }
+    socket.set_nonblocking(true)?;
+    let addr = socket.local_addr()?;
+    if tls {
+        #[cfg(feature = "tls")]
+        if addr.as_socket().is_some() {
+            return Ok(Listener::Tls(socket.into()));
+        }
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("fd {} is named tls but can't be served with TLS", fd),
+        )
+        .into());
+    }
+    if addr.as_socket().is_some() {
+        Ok(Listener::Tcp(socket.into()))
+    } else if let Some(path) = addr.as_pathname() {
+        Ok(Listener::Unix {
+            path: path.to_owned(),
+            listener: OwnedFd::from(socket).into(),
+            owned: AtomicBool::new(false),
+        })
+    } else {
+        Err(not_supported(fd).into())
+    }
+}
+
+fn not_supported(fd: RawFd) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("fd {} is not a TCP or Unix stream socket", fd),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod inherited {
+        use crate::errors::{AppError, ConfigError};
+        use crate::server::activation::inherited;
+
+        fn env<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+            |key| {
+                vars.iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+            }
+        }
+
+        #[test]
+        fn falls_back_without_the_variables() {
+            assert!(inherited(env(&[]), 42).unwrap().is_none());
+            assert!(inherited(env(&[("LISTEN_FDS", "1")]), 42)
+                .unwrap()
+                .is_none());
+            assert!(
+                inherited(env(&[("LISTEN_PID", "42"), ("LISTEN_FDS", "0")]), 42)
+                    .unwrap()
+                    .is_none()
+            );
+        }
+
+        #[test]
+        fn ignores_fds_meant_for_another_process() {
+            let vars = [("LISTEN_PID", "41"), ("LISTEN_FDS", "2")];
+            assert!(inherited(env(&vars), 42).unwrap().is_none());
+        }
+
+        #[test]
+        fn rejects_a_bad_count() {
+            let vars = [("LISTEN_PID", "42"), ("LISTEN_FDS", "two")];
+            assert_eq!(
+                AppError::Config(ConfigError::InvalidEnv(
+                    "LISTEN_FDS".to_owned(),
+                    "two".to_owned()
+                )),
+                inherited(env(&vars), 42).unwrap_err()
+            );
+        }
+    }
+}
@@ -1,77 +1,524 @@
-use super::ThreadPool;
+use super::{
+    hand_off, inherited_listeners, notify_ready, wait_readable, ClientSlots, ConnectionGuard,
+    Connections, ListenAddr, Listener, Peer, ShutdownHandle, Stats, Stream, ThreadPool, Timed,
+    Timeouts, Waker,
+};
+#[cfg(feature = "reactor")]
+use super::{Parked, Reactor};
 use crate::dir::Dir;
+#[cfg(feature = "tls")]
+use crate::errors::ConfigError;
+use crate::handlers::{ErrorHandler, ErrorHandlerArg};
+use crate::http::{ClientError, ServerError};
 use crate::router::Router;
-use crate::{Config, Result};
-use std::net::TcpListener;
+use crate::{errors::AppError, Config, Result};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    Arc, Mutex, RwLock,
 };
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
 
-use log::{error, info};
+// Once everything has been force-closed the workers only need to notice, this
+// is just so a wedged one can't hold up the summary forever
+const FORCE_CLOSE_GRACE: Duration = Duration::from_secs(1);
 
 pub struct Server {
-    listener: TcpListener,
-    router: Arc<Router<Dir>>,
-    thread_pool: ThreadPool,
+    listeners: Vec<Listener>,
+    // Swapped on reload. Each connection holds on to the router it started
+    // with, so a reload only affects new ones.
+    router: RwLock<Arc<Router<Dir>>>,
+    config: Mutex<Config>,
+    thread_pool: Arc<ThreadPool>,
+    // Holds connections between requests when running in reactor mode
+    #[cfg(feature = "reactor")]
+    reactor: Option<Arc<Reactor>>,
     running: Arc<AtomicBool>,
+    upgrade: Arc<AtomicBool>,
+    reload: Arc<Mutex<Option<Config>>>,
+    waker: Arc<Waker>,
+    connections: Arc<Connections>,
+    // For --max_connections_per_ip
+    client_slots: Arc<ClientSlots>,
+    stats: Arc<Stats>,
 }
 
 impl Server {
     pub fn try_new(config: &Config) -> Result<Server> {
-        let listener = TcpListener::bind(&config.address)?;
-        listener.set_nonblocking(true)?;
+        // Listeners passed in through socket activation replace --listen,
+        // when asked for
+        let inherited = match config.socket_activation {
+            true => inherited_listeners()?,
+            false => None,
+        };
+        let listeners = match inherited {
+            Some(listeners) => {
+                info!(
+                    "Serving {} listener(s) passed in instead of {}",
+                    listeners.len(),
+                    config.listen.join(", ")
+                );
+                listeners
+            }
+            None => config
+                .listen
+                .iter()
+                .map(|address| Listener::bind(address, config.ipv6_only, config.socket_mode))
+                .collect::<Result<Vec<_>>>()?,
+        };
+        #[cfg(feature = "tls")]
+        check_tls(&listeners, config)?;
+        let stats = Arc::new(Stats::default());
+        let thread_pool = ThreadPool::new(config.threads, config.queue_depth, Arc::clone(&stats));
+        let server = Self {
+            listeners,
+            router: RwLock::new(Arc::new(Router::new(Dir::default()))),
+            config: Mutex::new(config.clone()),
+            thread_pool: Arc::new(thread_pool),
+            #[cfg(feature = "reactor")]
+            reactor: match config.reactor {
+                true => Some(Arc::new(Reactor::new(config.max_parked)?)),
+                false => None,
+            },
+            running: Arc::new(AtomicBool::new(true)),
+            upgrade: Arc::new(AtomicBool::new(false)),
+            reload: Arc::new(Mutex::new(None)),
+            waker: Arc::new(Waker::new()?),
+            connections: Arc::new(Connections::default()),
+            client_slots: Arc::new(ClientSlots::default()),
+            stats,
+        };
+        *server.router.write()? = Arc::new(server.new_router(config));
+        Ok(server)
+    }
+
+    fn new_router(&self, config: &Config) -> Router<Dir> {
         // I feel like trying to get rid of this clone would be overkill...
         // Clippy isn't annoyed with me about this
-        let router: Arc<Router<Dir>> = Arc::new(Router::new(config.directory.clone()));
-        let thread_pool = ThreadPool::new(8);
-        let running = Arc::new(AtomicBool::new(true));
-        Ok(Self {
-            listener,
-            router,
-            thread_pool,
-            running,
-        })
+        let router = Router::new(config.directory.clone())
+            .with_limits(config.limits.clone())
+            .with_access(&config.access)
+            .with_auth(&config.auth, &config.users)
+            .with_tokens(&config.tokens)
+            .with_signer(config.signer.clone())
+            .with_rate_limits(&config.rate_limits);
+        if config.admin {
+            router.with_control(self.shutdown_handle())
+        } else {
+            router
+        }
     }
-    pub fn start(&self) -> Result<()> {
-        let r = Arc::clone(&self.running);
 
-        ctrlc::set_handler(move || {
-            info!("Starting shutdown...");
-            r.store(false, Ordering::SeqCst);
-        })
-        .expect("Graceful shutdown failed!");
+    // Applies a new config to connections accepted from now on. Anything
+    // that was set up at startup (listeners, the thread pool) stays as it is
+    // until a restart or upgrade.
+    pub fn reload(&self, config: Config) -> Result<()> {
+        let mut current = self.config.lock()?;
+        #[cfg(feature = "tls")]
+        check_tls(&self.listeners, &config)?;
+        let fixed = [
+            ("listen", current.listen != config.listen),
+            ("ipv6_only", current.ipv6_only != config.ipv6_only),
+            ("socket_mode", current.socket_mode != config.socket_mode),
+            ("threads", current.threads != config.threads),
+            ("queue_depth", current.queue_depth != config.queue_depth),
+            ("reactor", current.reactor != config.reactor),
+            ("max_parked", current.max_parked != config.max_parked),
+        ];
+        for (option, _) in fixed.iter().filter(|(_, changed)| *changed) {
+            warn!(
+                "Ignoring the new {}, it only applies after a restart",
+                option
+            );
+        }
+        let router = Arc::new(self.new_router(&config));
+        *self.router.write()? = router;
+        if let Some(level) = config.log_level {
+            log::set_max_level(level);
+        }
+        *current = config;
+        info!("Reloaded config");
+        Ok(())
+    }
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+    // The address actually bound, which is only known here when the config
+    // asked for port 0. With more than one listener this is the first TCP one.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.local_addrs()?
+            .into_iter()
+            .find_map(|addr| match addr {
+                ListenAddr::Tcp(addr) => Some(addr),
+                ListenAddr::Unix(_) => None,
+                #[cfg(feature = "tls")]
+                ListenAddr::Tls(_) => None,
+            })
+            .ok_or_else(|| ServerError::Internal.into())
+    }
+    // Every bound address, in the order they were configured
+    pub fn local_addrs(&self) -> Result<Vec<ListenAddr>> {
+        Ok(self
+            .listeners
+            .iter()
+            .map(|l| l.local_addr())
+            .collect::<std::io::Result<Vec<_>>>()?)
+    }
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(
+            Arc::clone(&self.running),
+            Arc::clone(&self.upgrade),
+            Arc::clone(&self.reload),
+            Arc::clone(&self.waker),
+        )
+    }
+    // Serves until one of the shutdown handles is used, then drains
+    pub fn start(&self) -> Result<()> {
+        let addrs = self.local_addrs()?;
+        for addr in &addrs {
+            info!("Listening on {}", addr);
+        }
+        if let Some(path) = &self.config.lock()?.ready_file {
+            write_ready_file(path, &addrs)?;
+        }
+        notify_ready();
+        #[cfg(feature = "reactor")]
+        let reactor = self.start_reactor();
 
         while self.running.load(Ordering::SeqCst) {
-            match self.listener.accept() {
-                Ok((stream, addr)) => {
-                    info!("Connection from: {}", addr);
-                    let router: Arc<Router<Dir>> = Arc::clone(&self.router);
-                    self.thread_pool.execute(move || {
-                        if let Err(e) = router.route(&stream) {
-                            error!("Error handling request, {}", e);
-                        } else {
-                            info!("Request handled OK");
+            // Sleeps in poll until there is a connection to accept on one of
+            // the listeners or we are woken up to shut down
+            for i in wait_readable(&self.listeners, &self.waker)? {
+                self.accept(&self.listeners[i])?;
+            }
+            if let Some(config) = self.reload.lock()?.take() {
+                if let Err(e) = self.reload(config) {
+                    error!("Reload failed, keeping the old config: {}", e);
+                }
+            }
+            if self.upgrade.swap(false, Ordering::SeqCst) {
+                self.upgrade();
+            }
+        }
+
+        self.drain();
+        #[cfg(feature = "reactor")]
+        if let Some((reactor, thread)) = reactor {
+            reactor.stop();
+            if thread.join().is_err() {
+                error!("Reactor thread panicked");
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "reactor")]
+    fn start_reactor(&self) -> Option<(Arc<Reactor>, thread::JoinHandle<()>)> {
+        let reactor = Arc::clone(self.reactor.as_ref()?);
+        let pool = Arc::clone(&self.thread_pool);
+        let running = Arc::clone(&self.running);
+        let stats = Arc::clone(&self.stats);
+        let thread = thread::spawn({
+            let reactor = Arc::clone(&reactor);
+            move || {
+                if let Err(e) = reactor.run(&pool, &running, &stats) {
+                    error!("Reactor failed: {:?}", e);
+                }
+            }
+        });
+        Some((reactor, thread))
+    }
+
+    fn accept(&self, listener: &Listener) -> Result<()> {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                info!("Connection from: {}", addr);
+                // Straight away, so that anything we turn it away with below
+                // goes nowhere rather than out in the clear. The certificate
+                // is whichever was loaded last.
+                #[cfg(feature = "tls")]
+                let stream = match self.config.lock()?.tls.server_config.clone() {
+                    // Always set for tls: listeners, see check_tls
+                    Some(tls) if listener.is_tls() => match stream.into_tls(tls) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            error!("Unable to start TLS with {}: {:?}", addr, e);
+                            return Ok(());
                         }
-                    })?;
+                    },
+                    _ => stream,
+                };
+                let (permitted, max) = {
+                    let config = self.config.lock()?;
+                    (
+                        config.connections.permits(addr.ip()),
+                        config.max_connections_per_ip,
+                    )
+                };
+                // Dropped without a word, the client isn't meant to be here
+                if !permitted {
+                    info!("Closing connection from {}, its address is denied", addr);
+                    return Ok(());
                 }
-                // The listener is non-blocking, so if there are no connections
-                // waiting we can just sleep for a bit and try again
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    std::thread::sleep(Duration::from_millis(100));
-                    continue;
+                // Before anything else, so one client can't fill the queue
+                let Some(slot) = self.client_slots.claim(addr, max) else {
+                    warn!("{} already has {} connections, rejecting", addr, max);
+                    reject(&stream, addr, ClientError::TooManyRequests.into());
+                    return Ok(());
+                };
+                #[cfg(feature = "reactor")]
+                if let Some(reactor) = &self.reactor {
+                    // Only None if the drain deadline has already passed
+                    let Some(guard) = self.connections.register(&stream) else {
+                        self.stats.record_dropped(1);
+                        return Ok(());
+                    };
+                    if reactor.is_full() {
+                        warn!("Reactor is full, rejecting {}", addr);
+                        reject(&stream, addr, ServerError::ServiceUnavailable.into());
+                        return Ok(());
+                    }
+                    let router = Arc::clone(&*self.router.read()?);
+                    let timeouts = self.config.lock()?.timeouts;
+                    // Only this connection's problem, the rest carry on
+                    match Parked::new(stream, addr, guard, slot, router, timeouts) {
+                        Ok(conn) => reactor.park(conn),
+                        Err(e) => error!("Unable to park connection from {}: {:?}", addr, e),
+                    }
+                    return Ok(());
                 }
-                // If there is an error accepting a connection, we'll just
-                // print it and continue
-                Err(e) => {
-                    error!("Connection error: {:?}", e);
-                    continue;
+                // Better to tell the client to come back later than to
+                // let the backlog grow without bound
+                if self.thread_pool.is_full() {
+                    warn!("Thread pool is full, rejecting {}", addr);
+                    reject(&stream, addr, ServerError::ServiceUnavailable.into());
+                    return Ok(());
                 }
+                let router: Arc<Router<Dir>> = Arc::clone(&*self.router.read()?);
+                let timeouts = self.config.lock()?.timeouts;
+                let running = Arc::clone(&self.running);
+                let connections = Arc::clone(&self.connections);
+                let stats = Arc::clone(&self.stats);
+                let backlog = self.thread_pool.backlog();
+                self.thread_pool.execute(move || {
+                    // Given back once the connection is done with
+                    let _slot = slot;
+                    // Only None if the drain deadline has already passed
+                    let Some(conn) = connections.register(&stream) else {
+                        stats.record_dropped(1);
+                        return;
+                    };
+                    run_guarded(&stream, addr, &stats, || {
+                        serve(
+                            &router,
+                            &stream,
+                            addr.ip(),
+                            &conn,
+                            // Not while draining, and only while there are
+                            // workers to spare
+                            &|| running.load(Ordering::SeqCst) && !backlog.any(),
+                            timeouts,
+                            &stats,
+                        )
+                    });
+                })
+            }
+            // The listeners are still non-blocking so that a connection that
+            // goes away between poll and accept can't stall the loop
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(()),
+            // If there is an error accepting a connection, we'll just
+            // print it and continue
+            Err(e) => {
+                error!("Connection error: {:?}", e);
+                Ok(())
             }
         }
+    }
 
-        info!("Shutting down server");
-        Ok(())
+    // Hands the listeners to a new copy of the binary, then drains the same
+    // way a shutdown does. We carry on serving if the new one doesn't start.
+    fn upgrade(&self) {
+        match hand_off(&self.listeners) {
+            Ok(pid) => {
+                info!("Process {} has taken over the listeners, draining", pid);
+                for listener in &self.listeners {
+                    listener.disown();
+                }
+                self.running.store(false, Ordering::SeqCst);
+            }
+            Err(e) => error!("Upgrade failed, carrying on: {}", e),
+        }
+    }
+
+    // Like start, but also shuts down once signal returns. signal is run on
+    // its own thread, e.g. `server.run_until(move || { let _ = rx.recv(); })`
+    pub fn run_until<F>(&self, signal: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let handle = self.shutdown_handle();
+        thread::spawn(move || {
+            signal();
+            info!("Shutdown signalled");
+            handle.shutdown();
+        });
+        self.start()
+    }
+
+    // New connections are no longer being accepted by the time we get here.
+    // Requests that are already being handled get to finish (with the
+    // connection closed afterwards), idle keep-alive connections are closed
+    // now and anything still going at the deadline is cut off.
+    fn drain(&self) {
+        let shutdown_timeout = match self.config.lock() {
+            Ok(config) => config.shutdown_timeout,
+            Err(e) => e.into_inner().shutdown_timeout,
+        };
+        let idle = self.connections.close_idle();
+        info!(
+            "Draining connections, closed {} idle, waiting up to {:?} for the rest",
+            idle, shutdown_timeout
+        );
+        if !self.thread_pool.wait_idle(shutdown_timeout) {
+            let remaining = self.connections.close_all();
+            self.stats.record_dropped(remaining);
+            warn!(
+                "Drain deadline passed, force-closed {} connections",
+                remaining
+            );
+            self.thread_pool.wait_idle(FORCE_CLOSE_GRACE);
+        }
+        info!(
+            "Shutting down server ({} connections dropped, {} panics, {} worker respawns)",
+            self.stats.dropped(),
+            self.stats.panics(),
+            self.stats.respawns()
+        );
+    }
+}
+
+// Listeners passed down can be tls: ones without the config asking for any
+#[cfg(feature = "tls")]
+fn check_tls(listeners: &[Listener], config: &Config) -> Result<()> {
+    if listeners.iter().any(Listener::is_tls) && config.tls.server_config.is_none() {
+        return Err(ConfigError::MissingValue("tls_cert".to_owned()).into());
+    }
+    Ok(())
+}
+
+// Serves requests on the connection until either side wants it closed
+fn serve(
+    router: &Router<Dir>,
+    stream: &Stream,
+    client: Option<IpAddr>,
+    conn: &ConnectionGuard,
+    keep_alive: &dyn Fn() -> bool,
+    timeouts: Timeouts,
+    stats: &Stats,
+) -> Result<()> {
+    let timed = Timed::new(stream, timeouts)?;
+    let mut reader = BufReader::new(&timed);
+    let mut first = true;
+    loop {
+        // Mark the connection idle before checking whether we are draining, a
+        // drain that starts after the check will find it idle and close it
+        conn.set_idle(true);
+        // A connection we accepted before the drain still gets its first
+        // request answered, after that we only finish what's been sent
+        if !keep_alive() && !first && reader.buffer().is_empty() {
+            return Ok(());
+        }
+        // The first request is timed from the accept, the rest from when
+        // they start arriving
+        if first {
+            timed.reading_head();
+        } else {
+            timed.idle();
+        }
+        // Blocks until the client starts its next request or goes away
+        let idle_since = Instant::now();
+        loop {
+            match reader.fill_buf() {
+                Ok([]) => return Ok(()),
+                Ok(_) => break,
+                // An idle connection gives its worker up to anyone waiting
+                // for one, or it would only take --threads of them to stop
+                // the server answering anyone else
+                Err(e) if e.kind() == ErrorKind::TimedOut && !first && !keep_alive() => {
+                    return Ok(())
+                }
+                Err(e)
+                    if e.kind() == ErrorKind::TimedOut
+                        && !first
+                        && timeouts
+                            .keep_alive
+                            .map_or(true, |timeout| idle_since.elapsed() < timeout) => {}
+                // Nothing has been sent, so there's nobody to send a 408 to
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    stats.record_timeout();
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if !first {
+            timed.reading_head();
+        }
+        first = false;
+        conn.set_idle(false);
+        match router.route(&mut reader, !keep_alive(), client, Some(&timed)) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => {
+                if e == ClientError::RequestTimeout.into() {
+                    stats.record_timeout();
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+// One address per line. Written to a temporary file first so that anyone
+// watching for it never sees a partial list.
+fn write_ready_file(path: &PathBuf, addrs: &[ListenAddr]) -> Result<()> {
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    let contents: String = addrs.iter().map(|a| format!("{}\n", a)).collect();
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Runs f, answering with a 500 if it panics
+pub(super) fn run_guarded<F>(stream: &Stream, addr: Peer, stats: &Stats, f: F)
+where
+    F: FnOnce() -> Result<()>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => info!("Connection from {} handled OK", addr),
+        Ok(Err(e)) => error!("Error handling request, {}", e),
+        Err(_) => {
+            let total = stats.record_panic();
+            error!("Panic handling request from {} ({} so far)", addr, total);
+            // The response may already be half written, but a 500 is
+            // better than nothing
+            reject(stream, addr, ServerError::Internal.into());
+        }
+    }
+}
+
+pub(super) fn reject(mut stream: &Stream, addr: Peer, err: AppError) {
+    let res = ErrorHandler::handle(ErrorHandlerArg::new(err))
+        .and_then(|resp| Ok(stream.write_all(&resp.close().as_bytes())?));
+    if let Err(e) = res {
+        error!("Error answering {}, {}", addr, e);
     }
 }
@@ -0,0 +1,438 @@
+use super::{ClientSlot, ClientSlots, Peer, Stats, Timeouts};
+use crate::access::IpList;
+use crate::dir::Dir;
+use crate::handlers::{ErrorHandler, ErrorHandlerArg};
+use crate::http::{ClientError, Request, RequestHead, ServerError};
+use crate::router::{Admission, Router};
+use crate::{Config, Result};
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::{self, JoinError, JoinSet};
+use tokio::time::{Instant, Sleep};
+
+use log::{error, info, warn};
+
+// The server for embedding in tokio applications, behind the tokio feature.
+// Requests are parsed and answered on the runtime, apart from the handlers
+// that touch the filesystem, which are moved onto its blocking pool. The
+// listeners, reloading, upgrades and reactor mode all belong to Server, the
+// caller owns the runtime and the listener here.
+#[derive(Clone)]
+pub struct AsyncServer {
+    router: Arc<Router<Dir>>,
+    timeouts: Timeouts,
+    shutdown_timeout: Duration,
+    max_connections_per_ip: usize,
+    connections: IpList,
+    client_slots: Arc<ClientSlots>,
+    // Set once the server is shutting down
+    stopping: Arc<watch::Sender<bool>>,
+    stats: Arc<Stats>,
+}
+
+impl AsyncServer {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            router: Arc::new(
+                Router::new(config.directory.clone())
+                    .with_limits(config.limits.clone())
+                    .with_access(&config.access)
+                    .with_auth(&config.auth, &config.users)
+                    .with_tokens(&config.tokens)
+                    .with_signer(config.signer.clone())
+                    .with_rate_limits(&config.rate_limits),
+            ),
+            timeouts: config.timeouts,
+            shutdown_timeout: config.shutdown_timeout,
+            max_connections_per_ip: config.max_connections_per_ip,
+            connections: config.connections.clone(),
+            client_slots: Arc::default(),
+            stopping: Arc::new(watch::channel(false).0),
+            stats: Arc::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+
+    // Has run_until stop accepting, and connections close once they are done
+    // with the request they are on. Any clone of the server can call this.
+    pub fn shutdown(&self) {
+        self.stopping.send_replace(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.stopping.borrow()
+    }
+
+    // Serves connections from the listener until signal completes or shutdown
+    // is called, then drains them the same way Server does
+    pub async fn run_until<F>(&self, listener: TcpListener, signal: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        info!("Server listening on {}", listener.local_addr()?);
+        let mut stopping = self.stopping.subscribe();
+        let mut connections = JoinSet::new();
+        tokio::pin!(signal);
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((_, addr)) if !self.connections.permits(Some(addr.ip())) => {
+                        info!("Closing connection from {}, its address is denied", addr);
+                    }
+                    Ok((stream, addr)) => {
+                        let slot = self.client_slots.claim(Peer::Tcp(addr), self.max_connections_per_ip);
+                        connections.spawn(self.clone().connection(stream, addr, slot));
+                    }
+                    Err(e) => error!("Error accepting connection: {:?}", e),
+                },
+                // Finished connections are reaped as we go, otherwise their
+                // results would pile up until shutdown
+                Some(res) = connections.join_next() => self.reap(res),
+                _ = &mut signal => {
+                    info!("Shutdown signalled");
+                    break;
+                }
+                _ = stopped(&mut stopping) => break,
+            }
+        }
+        self.shutdown();
+        info!(
+            "Draining {} connections, waiting up to {:?}",
+            connections.len(),
+            self.shutdown_timeout
+        );
+        let drained = tokio::time::timeout(self.shutdown_timeout, async {
+            while let Some(res) = connections.join_next().await {
+                self.reap(res);
+            }
+        })
+        .await;
+        if drained.is_err() {
+            let remaining = connections.len();
+            connections.abort_all();
+            self.stats.record_dropped(remaining);
+            warn!(
+                "Drain deadline passed, force-closed {} connections",
+                remaining
+            );
+        }
+        info!(
+            "Shutting down server ({} connections dropped, {} panics)",
+            self.stats.dropped(),
+            self.stats.panics()
+        );
+        Ok(())
+    }
+
+    // Serves a connection run_until accepted, unless its client already had
+    // as many open as it is allowed
+    async fn connection(self, mut stream: TcpStream, addr: SocketAddr, slot: Option<ClientSlot>) {
+        let Some(_slot) = slot else {
+            warn!("{} already has too many connections, rejecting", addr);
+            let resp =
+                ErrorHandler::handle(ErrorHandlerArg::new(ClientError::TooManyRequests.into()));
+            if let Ok(resp) = resp {
+                let _ = resp.close().write_async(&mut stream).await;
+            }
+            return;
+        };
+        match self.serve_from(stream, Some(addr.ip())).await {
+            Ok(()) => info!("Connection from {} closed", addr),
+            Err(e) => warn!("Connection from {} failed: {}", addr, e),
+        }
+    }
+
+    // Serves requests on the connection until either side wants it closed, or
+    // the server is shutting down
+    pub async fn serve<S>(&self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.serve_from(stream, None).await
+    }
+
+    // The same as serve, with the client's address for rate limiting
+    pub async fn serve_from<S>(&self, stream: S, client: Option<IpAddr>) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut conn = BufReader::new(TimedIo::new(stream, self.timeouts.write));
+        let mut stopping = self.stopping.subscribe();
+        let mut first = true;
+        loop {
+            // The first request is timed from the accept, the rest from when
+            // they start arriving
+            let (wait, head) = match first {
+                true => (
+                    deadline(self.timeouts.header),
+                    deadline(self.timeouts.header),
+                ),
+                false => (deadline(self.timeouts.keep_alive), None),
+            };
+            // Waiting for the next request is the one place a connection can
+            // be dropped on shutdown without cutting anything off
+            tokio::select! {
+                read = until(wait, conn.fill_buf()) => match read {
+                    Ok(Ok([])) => return Ok(()),
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => return Err(e.into()),
+                    // Nothing has been sent, so there's nobody to send a 408 to
+                    Err(_) => {
+                        self.stats.record_timeout();
+                        return Ok(());
+                    }
+                },
+                _ = stopped(&mut stopping) => return Ok(()),
+            }
+            let head = match first {
+                true => head,
+                false => deadline(self.timeouts.header),
+            };
+            first = false;
+            let close = self.is_shutdown();
+            match self.route(&mut conn, close, head, client).await {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(e) => {
+                    if e == ClientError::RequestTimeout.into() {
+                        self.stats.record_timeout();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // Router::route, reading and writing asynchronously. The head has to be
+    // in by the deadline, the body only has to keep moving.
+    async fn route<S>(
+        &self,
+        conn: &mut BufReader<TimedIo<S>>,
+        close: bool,
+        head: Option<Instant>,
+        client: Option<IpAddr>,
+    ) -> Result<bool>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        conn.get_mut().set_read_timeout(None);
+        let req = match until(head, Request::read_head_async(conn, self.router.limits())).await {
+            Ok(Ok(head)) => match self.check_head(head, client).await {
+                Ok((head, Admission::Admitted { user, quota })) => {
+                    conn.get_mut().set_read_timeout(self.timeouts.body);
+                    head.read_body_async(conn)
+                        .await
+                        .map(|req| (Request { user, ..req }, quota))
+                }
+                // The body is still to come, so this is the last response
+                Ok((_, Admission::Refused(refused))) => {
+                    refused.close().write_async(conn.get_mut()).await?;
+                    return Ok(false);
+                }
+                Err(e) => Err(e),
+            },
+            Ok(Err(e)) | Err(e) => Err(e),
+        };
+        let (mut req, quota) = match req {
+            Ok((req, quota)) => (Request { client, ..req }, quota),
+            Err(e) => {
+                self.router
+                    .rejected(&e)?
+                    .write_async(conn.get_mut())
+                    .await?;
+                return Err(e);
+            }
+        };
+        let resp = if self.router.blocks(&req) {
+            let router = Arc::clone(&self.router);
+            let blocking = task::spawn_blocking(move || {
+                let resp = router.respond(&req, quota);
+                (req, resp)
+            });
+            match blocking.await {
+                Ok((done, resp)) => {
+                    req = done;
+                    resp?
+                }
+                // The request went with the handler, so there's no telling
+                // whether the client wanted to keep the connection
+                Err(e) => {
+                    let panics = self.stats.record_panic();
+                    error!("Handler panicked ({} so far): {}", panics, e);
+                    ErrorHandler::handle(ErrorHandlerArg::new(ServerError::Internal.into()))?
+                        .close()
+                        .write_async(conn.get_mut())
+                        .await?;
+                    return Ok(false);
+                }
+            }
+        } else {
+            self.router.respond(&req, quota)?
+        };
+        let (resp, keep_alive) = self.router.finish(&req, resp, close);
+        resp.write_async(conn.get_mut()).await?;
+        Ok(keep_alive)
+    }
+
+    // Router::check_head, on a blocking thread when it might hash a password
+    async fn check_head(
+        &self,
+        mut head: RequestHead,
+        client: Option<IpAddr>,
+    ) -> Result<(RequestHead, Admission)> {
+        if !self.router.head_blocks(&head) {
+            let admission = self.router.check_head(&mut head, client, None)?;
+            return Ok((head, admission));
+        }
+        let router = Arc::clone(&self.router);
+        let blocking = task::spawn_blocking(move || {
+            let admission = router.check_head(&mut head, client, None);
+            admission.map(|admission| (head, admission))
+        });
+        match blocking.await {
+            Ok(checked) => checked,
+            Err(e) => {
+                let panics = self.stats.record_panic();
+                error!("Login check panicked ({} so far): {}", panics, e);
+                Err(ServerError::Internal.into())
+            }
+        }
+    }
+
+    fn reap(&self, res: std::result::Result<(), JoinError>) {
+        if let Err(e) = res {
+            if e.is_panic() {
+                let panics = self.stats.record_panic();
+                error!("Connection panicked ({} so far): {}", panics, e);
+            }
+        }
+    }
+}
+
+// Completes once the server is shutting down
+async fn stopped(stopping: &mut watch::Receiver<bool>) {
+    // Only errors when the sender has gone, which means the same thing
+    let _ = stopping.wait_for(|stopping| *stopping).await;
+}
+
+fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|timeout| Instant::now() + timeout)
+}
+
+// Runs f, giving up with a RequestTimeout if it isn't done by the deadline
+async fn until<F: Future>(deadline: Option<Instant>, f: F) -> Result<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, f)
+            .await
+            .map_err(|_| ClientError::RequestTimeout.into()),
+        None => Ok(f.await),
+    }
+}
+
+// A stream whose reads and writes fail with TimedOut once they have been
+// waiting for longer than their timeout, the same as Timed does for blocking
+// sockets
+struct TimedIo<S> {
+    inner: S,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    // Started when a read or write first has to wait, cleared once it is done
+    read_sleep: Option<Pin<Box<Sleep>>>,
+    write_sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> TimedIo<S> {
+    fn new(inner: S, write_timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            read_timeout: None,
+            write_timeout,
+            read_sleep: None,
+            write_sleep: None,
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+        self.read_sleep = None;
+    }
+}
+
+// Called when the inner stream is still pending, fails once it has been for
+// too long
+fn expire<T>(
+    sleep: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Option<Duration>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<T>> {
+    let Some(timeout) = timeout else {
+        return Poll::Pending;
+    };
+    let timer = sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+    if timer.as_mut().poll(cx).is_pending() {
+        return Poll::Pending;
+    }
+    *sleep = None;
+    Poll::Ready(Err(ErrorKind::TimedOut.into()))
+}
+
+// Whatever the inner stream returns once it stops waiting
+fn done<T>(sleep: &mut Option<Pin<Box<Sleep>>>, res: T) -> Poll<T> {
+    *sleep = None;
+    Poll::Ready(res)
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimedIo<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(res) => done(&mut this.read_sleep, res),
+            Poll::Pending => expire(&mut this.read_sleep, this.read_timeout, cx),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimedIo<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(res) => done(&mut this.write_sleep, res),
+            Poll::Pending => expire(&mut this.write_sleep, this.write_timeout, cx),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_flush(cx) {
+            Poll::Ready(res) => done(&mut this.write_sleep, res),
+            Poll::Pending => expire(&mut this.write_sleep, this.write_timeout, cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_shutdown(cx) {
+            Poll::Ready(res) => done(&mut this.write_sleep, res),
+            Poll::Pending => expire(&mut this.write_sleep, this.write_timeout, cx),
+        }
+    }
+}
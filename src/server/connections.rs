@@ -0,0 +1,231 @@
+use super::{Peer, Stream};
+use crate::rate_limit::network;
+use log::warn;
+use std::collections::HashMap;
+use std::net::{IpAddr, Shutdown};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+// Keeps a handle on every connection a worker is serving so that shutdown can
+// close the idle ones straight away and force-close the rest once the drain
+// deadline has passed.
+#[derive(Debug, Default)]
+pub struct Connections {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: u64,
+    open: HashMap<u64, Tracked>,
+    // Set once everything has been force-closed, anything registering after
+    // that is turned away
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    stream: Stream,
+    idle: bool,
+}
+
+impl Connections {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // The map is still consistent if a holder panicked, none of the
+        // updates below can fail half way
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Returns None once the connections have been force-closed
+    pub fn register(self: &Arc<Self>, stream: &Stream) -> Option<ConnectionGuard> {
+        let stream = match stream.try_clone() {
+            Ok(stream) => stream,
+            Err(e) => {
+                // Serve it anyway, we just won't be able to close it early
+                warn!("Unable to track connection: {:?}", e);
+                return Some(ConnectionGuard {
+                    id: None,
+                    connections: Arc::clone(self),
+                });
+            }
+        };
+        let mut inner = self.lock();
+        if inner.closed {
+            return None;
+        }
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.open.insert(
+            id,
+            Tracked {
+                stream,
+                idle: false,
+            },
+        );
+        Some(ConnectionGuard {
+            id: Some(id),
+            connections: Arc::clone(self),
+        })
+    }
+
+    // Stops reads on connections that are waiting for their next request, the
+    // worker sees EOF and lets go of it. Returns how many were closed.
+    pub fn close_idle(&self) -> usize {
+        let inner = self.lock();
+        inner
+            .open
+            .values()
+            .filter(|c| c.idle)
+            .filter(|c| c.stream.shutdown(Shutdown::Read).is_ok())
+            .count()
+    }
+
+    // Returns how many connections were still open
+    pub fn close_all(&self) -> usize {
+        let mut inner = self.lock();
+        inner.closed = true;
+        for c in inner.open.values() {
+            let _ = c.stream.shutdown(Shutdown::Both);
+        }
+        inner.open.len()
+    }
+}
+
+pub struct ConnectionGuard {
+    // None when the connection couldn't be tracked
+    id: Option<u64>,
+    connections: Arc<Connections>,
+}
+
+impl ConnectionGuard {
+    pub fn set_idle(&self, idle: bool) {
+        if let Some(id) = self.id {
+            if let Some(c) = self.connections.lock().open.get_mut(&id) {
+                c.idle = idle;
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.connections.lock().open.remove(&id);
+        }
+    }
+}
+
+// Counts the connections open from each client network, the same one the
+// rate limits count against, so that one client can't take every worker
+#[derive(Debug, Default)]
+pub struct ClientSlots {
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ClientSlots {
+    // Returns None if the client already has max connections open. A max of
+    // 0 means no limit, and Unix socket clients aren't counted.
+    pub fn claim(self: &Arc<Self>, peer: Peer, max: usize) -> Option<ClientSlot> {
+        let ip = peer.ip().filter(|_| max > 0).map(network);
+        if let Some(ip) = ip {
+            let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+            let count = open.entry(ip).or_default();
+            if *count >= max {
+                return None;
+            }
+            *count += 1;
+        }
+        Some(ClientSlot {
+            ip,
+            slots: Arc::clone(self),
+        })
+    }
+}
+
+// Held for as long as the connection is open
+#[derive(Debug)]
+pub struct ClientSlot {
+    ip: Option<IpAddr>,
+    slots: Arc<ClientSlots>,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        if let Some(ip) = self.ip {
+            let mut open = self
+                .slots
+                .open
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if let Some(count) = open.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    open.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod connections {
+        use crate::server::connections::{ClientSlots, Connections};
+        use crate::server::{Peer, Stream};
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+        use std::sync::Arc;
+
+        #[test]
+        fn closes_idle_then_everything() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let _a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let _b = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let idle = Stream::Tcp(listener.accept().unwrap().0);
+            let busy = Stream::Tcp(listener.accept().unwrap().0);
+
+            let connections = Arc::new(Connections::default());
+            let idle_guard = connections.register(&idle).unwrap();
+            let _busy_guard = connections.register(&busy).unwrap();
+            idle_guard.set_idle(true);
+
+            assert_eq!(1, connections.close_idle());
+            assert_eq!(0, (&idle).read(&mut [0; 8]).unwrap());
+            drop(idle_guard);
+
+            assert_eq!(1, connections.close_all());
+            assert!(connections.register(&busy).is_none());
+        }
+
+        #[test]
+        fn caps_connections_per_client() {
+            let slots = Arc::new(ClientSlots::default());
+            let a = Peer::Tcp("10.0.0.1:1000".parse().unwrap());
+            let b = Peer::Tcp("10.0.0.2:1000".parse().unwrap());
+            let first = slots.claim(a, 2).unwrap();
+            let _second = slots.claim(a, 2).unwrap();
+            assert!(slots.claim(a, 2).is_none());
+            assert!(slots.claim(b, 2).is_some());
+            assert!(slots.claim(Peer::Unix, 2).is_some());
+            assert!(slots.claim(a, 0).is_some());
+            drop(first);
+            assert!(slots.claim(a, 2).is_some());
+        }
+
+        #[test]
+        fn counts_an_ipv6_network_as_one_client() {
+            let slots = Arc::new(ClientSlots::default());
+            let a = Peer::Tcp("[2001:db8::1]:1000".parse().unwrap());
+            let b = Peer::Tcp("[2001:db8::2]:1000".parse().unwrap());
+            let c = Peer::Tcp("[2001:db8:0:1::1]:1000".parse().unwrap());
+            let _first = slots.claim(a, 1).unwrap();
+            assert!(slots.claim(b, 1).is_none());
+            assert!(slots.claim(c, 1).is_some());
+            // An IPv4 client of a dual stack listener is the same client
+            let v4 = Peer::Tcp("10.0.0.1:1000".parse().unwrap());
+            let mapped = Peer::Tcp("[::ffff:10.0.0.1]:1000".parse().unwrap());
+            let _v4 = slots.claim(v4, 1).unwrap();
+            assert!(slots.claim(mapped, 1).is_none());
+        }
+    }
+}
@@ -0,0 +1,460 @@
+#[cfg(feature = "tls")]
+use super::tls::TlsStream;
+use crate::client_cert::ClientCert;
+use crate::Result;
+use log::{info, warn};
+#[cfg(feature = "tls")]
+use rustls::ServerConfig;
+use socket2::{Domain, Socket, Type};
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::Duration;
+
+// Same as the default std uses
+const BACKLOG: i32 = 128;
+// --listen values starting with this are paths to a Unix domain socket
+pub const UNIX_PREFIX: &str = "unix:";
+// And these are TCP addresses to serve HTTPS on, needing the tls feature
+pub const TLS_PREFIX: &str = "tls:";
+// Passed down listeners with this name are served the same way
+pub const TLS_FD_NAME: &str = "tls";
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(feature = "tls")]
+    Tls(TcpListener),
+    // Socket files are only cleaned up by whoever created them, not when the
+    // listener was inherited or has been handed on
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+        owned: AtomicBool,
+    },
+}
+
+// What a listener is bound to, displayed the same way it would be passed to
+// --listen
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    #[cfg(feature = "tls")]
+    Tls(SocketAddr),
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+            #[cfg(feature = "tls")]
+            Self::Tls(addr) => write!(f, "{}{}", TLS_PREFIX, addr),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(TlsStream),
+}
+
+// Who is on the other end of a stream. Unix socket peers don't have an
+// address worth showing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl Peer {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(addr) => Some(addr.ip()),
+            Self::Unix => None,
+        }
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix => write!(f, "unix socket"),
+        }
+    }
+}
+
+impl Listener {
+    // Binds by hand rather than through TcpListener::bind, IPV6_V6ONLY has to
+    // be set before the socket is bound
+    pub fn bind(address: &str, ipv6_only: bool, socket_mode: Option<u32>) -> Result<Listener> {
+        if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+            return bind_unix(Path::new(path), socket_mode);
+        }
+        #[cfg(feature = "tls")]
+        if let Some(address) = address.strip_prefix(TLS_PREFIX) {
+            return Ok(Listener::Tls(bind_resolved(address, ipv6_only)?));
+        }
+        Ok(Listener::Tcp(bind_resolved(address, ipv6_only)?))
+    }
+
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Self::Tcp(l) => Ok(ListenAddr::Tcp(l.local_addr()?)),
+            Self::Unix { path, .. } => Ok(ListenAddr::Unix(path.clone())),
+            #[cfg(feature = "tls")]
+            Self::Tls(l) => Ok(ListenAddr::Tls(l.local_addr()?)),
+        }
+    }
+
+    // For LISTEN_FDNAMES, so that whoever the listener is handed to knows
+    // how to serve it
+    pub fn fd_name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "tls")]
+            Self::Tls(_) => TLS_FD_NAME,
+            _ => "http",
+        }
+    }
+
+    // Leaves the socket file behind for whoever the listener was handed to
+    pub fn disown(&self) {
+        if let Self::Unix { owned, .. } = self {
+            owned.store(false, Ordering::SeqCst);
+        }
+    }
+
+    // Whether what it accepts has to be handed to Stream::into_tls
+    #[cfg(feature = "tls")]
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Self::Tls(_))
+    }
+
+    // The listener is non-blocking, the stream handed back never is
+    pub fn accept(&self) -> io::Result<(Stream, Peer)> {
+        let (stream, peer) = match self {
+            Self::Tcp(l) => {
+                let (s, addr) = l.accept()?;
+                (Stream::Tcp(s), Peer::Tcp(addr))
+            }
+            Self::Unix { listener, .. } => (Stream::Unix(listener.accept()?.0), Peer::Unix),
+            #[cfg(feature = "tls")]
+            Self::Tls(l) => {
+                let (s, addr) = l.accept()?;
+                (Stream::Tcp(s), Peer::Tcp(addr))
+            }
+        };
+        // Some platforms hand out sockets that inherit the listener's
+        // non-blocking flag
+        stream.set_nonblocking(false)?;
+        Ok((stream, peer))
+    }
+}
+
+impl AsFd for Listener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Self::Tcp(l) => l.as_fd(),
+            Self::Unix { listener, .. } => listener.as_fd(),
+            #[cfg(feature = "tls")]
+            Self::Tls(l) => l.as_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix { path, owned, .. } = self {
+            if !owned.load(Ordering::SeqCst) {
+                return;
+            }
+            if let Err(e) = fs::remove_file(&*path) {
+                warn!("Unable to remove socket {}: {:?}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl Stream {
+    // For a connection from a tls: listener, nothing is read or written until
+    // the stream is first used
+    #[cfg(feature = "tls")]
+    pub fn into_tls(self, config: Arc<ServerConfig>) -> io::Result<Stream> {
+        match self {
+            Self::Tcp(s) => Ok(Self::Tls(TlsStream::new(s, config)?)),
+            _ => Err(ErrorKind::InvalidInput.into()),
+        }
+    }
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Self::Tcp(s) => Ok(Self::Tcp(s.try_clone()?)),
+            Self::Unix(s) => Ok(Self::Unix(s.try_clone()?)),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => Ok(Self::Tls(s.clone())),
+        }
+    }
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.shutdown(how),
+            Self::Unix(s) => s.shutdown(how),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.shutdown(how),
+        }
+    }
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.set_nonblocking(nonblocking),
+            Self::Unix(s) => s.set_nonblocking(nonblocking),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.set_read_timeout(timeout),
+            Self::Unix(s) => s.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.set_read_timeout(timeout),
+        }
+    }
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.set_write_timeout(timeout),
+            Self::Unix(s) => s.set_write_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.set_write_timeout(timeout),
+        }
+    }
+    pub fn client_cert(&self) -> Option<ClientCert> {
+        match self {
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.client_cert(),
+            _ => None,
+        }
+    }
+}
+
+impl AsFd for Stream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Self::Tcp(s) => s.as_fd(),
+            Self::Unix(s) => s.as_fd(),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.as_fd(),
+        }
+    }
+}
+
+// Implemented on the reference, like the std streams, so a connection can be
+// read through a BufReader and written to at the same time
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => (&*s).read(buf),
+            Stream::Unix(s) => (&*s).read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(s) => (&*s).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => (&*s).write(buf),
+            Stream::Unix(s) => (&*s).write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(s) => (&*s).write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => (&*s).flush(),
+            Stream::Unix(s) => (&*s).flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(s) => (&*s).flush(),
+        }
+    }
+}
+
+fn bind_resolved(address: &str, ipv6_only: bool) -> Result<TcpListener> {
+    let mut last_err = None;
+    for addr in address.to_socket_addrs()? {
+        match bind_tcp(addr, ipv6_only) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| io::Error::other(format!("{} did not resolve", address)))
+        .into())
+}
+
+fn bind_tcp(addr: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+fn bind_unix(path: &Path, socket_mode: Option<u32>) -> Result<Listener> {
+    remove_stale_socket(path)?;
+    let listener = match socket_mode {
+        Some(mode) => bind_unix_with_mode(path, mode)?,
+        None => UnixListener::bind(path)?,
+    };
+    listener.set_nonblocking(true)?;
+    Ok(Listener::Unix {
+        listener,
+        path: path.to_owned(),
+        owned: AtomicBool::new(true),
+    })
+}
+
+// Binds in a directory only we can get into and moves the socket into place
+// once it has its mode, so nobody can connect while it still has the umask's
+// permissions. The directory is next to the socket so the rename can't cross
+// filesystems.
+fn bind_unix_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a path to a file", path.display()),
+        ));
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    let private = parent.join(format!(".http-{}", std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let temp = private.join(name);
+    let bound = UnixListener::bind(&temp).and_then(|listener| {
+        fs::set_permissions(&temp, fs::Permissions::from_mode(mode))?;
+        fs::rename(&temp, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&temp);
+    let _ = fs::remove_dir(&private);
+    bound
+}
+
+// A socket file left behind by a server that didn't shut down cleanly would
+// make bind fail. It's only removed if nothing is listening on it, and
+// anything that isn't a socket is left alone.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!("{} is already being listened on", path.display()),
+        )),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            info!("Removing stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+        // Anything else, such as not being allowed to connect, doesn't say
+        // whether someone is still listening
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("can't tell whether {} is in use: {}", path.display(), e),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod listener {
+        use crate::server::listener::{ListenAddr, Listener};
+        use std::net::TcpStream;
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        #[test]
+        fn binds_ipv4_and_ipv6() {
+            let v4 = Listener::bind("127.0.0.1:0", false, None).unwrap();
+            assert!(matches!(v4.local_addr().unwrap(), ListenAddr::Tcp(a) if a.is_ipv4()));
+            // Not every sandbox has IPv6 loopback
+            if let Ok(v6) = Listener::bind("[::1]:0", true, None) {
+                assert!(matches!(v6.local_addr().unwrap(), ListenAddr::Tcp(a) if a.is_ipv6()));
+            }
+        }
+
+        #[test]
+        fn dual_stack_takes_ipv4() {
+            let Ok(listener) = Listener::bind("[::]:0", false, None) else {
+                return;
+            };
+            let ListenAddr::Tcp(addr) = listener.local_addr().unwrap() else {
+                panic!("expected a TCP listener");
+            };
+            let port = addr.port();
+            assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+        }
+
+        #[test]
+        fn ipv6_only_refuses_ipv4() {
+            let Ok(listener) = Listener::bind("[::]:0", true, None) else {
+                return;
+            };
+            let ListenAddr::Tcp(addr) = listener.local_addr().unwrap() else {
+                panic!("expected a TCP listener");
+            };
+            let port = addr.port();
+            assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+        }
+
+        #[test]
+        fn binds_unix_sockets() {
+            let path = std::env::temp_dir().join(format!("http-test-{}.sock", std::process::id()));
+            // Left behind by a server that died
+            drop(UnixListener::bind(&path).unwrap());
+            let address = format!("unix:{}", path.display());
+
+            let listener = Listener::bind(&address, false, Some(0o600)).unwrap();
+            assert_eq!(address, listener.local_addr().unwrap().to_string());
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(0o600, mode & 0o777);
+            assert!(UnixStream::connect(&path).is_ok());
+            // Bound somewhere private first, which is cleaned up
+            let private = path.with_file_name(format!(".http-{}", std::process::id()));
+            assert!(!private.exists());
+
+            // Someone is listening now, so it isn't stale
+            assert!(Listener::bind(&address, false, None).is_err());
+
+            drop(listener);
+            assert!(!path.exists());
+        }
+    }
+}
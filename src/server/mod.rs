@@ -1,5 +1,40 @@
+mod activation;
 mod app_server;
+#[cfg(feature = "tokio")]
+mod async_server;
+mod connections;
+mod listener;
+#[cfg(feature = "reactor")]
+mod reactor;
+mod shutdown;
+mod stats;
 mod thread_pool;
+mod timeouts;
+#[cfg(feature = "tls")]
+mod tls;
+mod upgrade;
+mod waker;
 
+use activation::inherited_listeners;
 pub use app_server::Server;
+#[cfg(feature = "reactor")]
+use app_server::{reject, run_guarded};
+#[cfg(feature = "tokio")]
+pub use async_server::AsyncServer;
+#[cfg(any(feature = "reactor", feature = "tokio"))]
+use connections::ClientSlot;
+use connections::{ClientSlots, ConnectionGuard, Connections};
+pub use listener::ListenAddr;
+pub(crate) use listener::Peer;
+use listener::{Listener, Stream, TLS_FD_NAME};
+pub(crate) use listener::{TLS_PREFIX, UNIX_PREFIX};
+#[cfg(feature = "reactor")]
+use reactor::{Parked, Reactor};
+pub use shutdown::ShutdownHandle;
+pub use stats::Stats;
 use thread_pool::ThreadPool;
+pub(crate) use timeouts::from_secs;
+use timeouts::Timed;
+pub use timeouts::Timeouts;
+use upgrade::{hand_off, notify_ready};
+use waker::{wait_readable, Waker};
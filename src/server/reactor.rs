@@ -0,0 +1,468 @@
+use super::{
+    reject, run_guarded, ClientSlot, ConnectionGuard, Peer, Stats, Stream, ThreadPool, Timed,
+    Timeouts, Waker,
+};
+use crate::dir::Dir;
+use crate::http::{ClientError, ServerError};
+use crate::router::Router;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::io::{self, BufReader, Cursor, ErrorKind, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+// Reactor mode, behind the reactor feature. Instead of every keep-alive
+// connection holding on to a worker while it waits for its next request, the
+// waiting is done here, by one thread watching all of them with epoll. Once the
+// head of a request has arrived the connection goes to the thread pool to be
+// routed as usual, and comes back afterwards if it is being kept alive.
+const MAX_EVENTS: usize = 256;
+const READ_CHUNK: usize = 8 * 1024;
+// epoll token for the waker, connections count up from 0
+const WAKER: u64 = u64::MAX;
+
+// A connection waiting for the rest of a request head
+pub struct Parked {
+    stream: Stream,
+    peer: Peer,
+    guard: ConnectionGuard,
+    slot: ClientSlot,
+    // Kept from accept so that a reload only affects new connections
+    router: Arc<Router<Dir>>,
+    timeouts: Timeouts,
+    // Read but not yet routed
+    buf: Vec<u8>,
+    // When the connection started waiting for its next request
+    since: Instant,
+    // When the head being read started, the first one is timed from the
+    // accept the same as in serve
+    head_since: Option<Instant>,
+}
+
+impl Parked {
+    pub fn new(
+        stream: Stream,
+        peer: Peer,
+        guard: ConnectionGuard,
+        slot: ClientSlot,
+        router: Arc<Router<Dir>>,
+        timeouts: Timeouts,
+    ) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        guard.set_idle(true);
+        let now = Instant::now();
+        Ok(Self {
+            stream,
+            peer,
+            guard,
+            slot,
+            router,
+            timeouts,
+            buf: Vec::new(),
+            since: now,
+            head_since: Some(now),
+        })
+    }
+
+    // When the connection will have waited too long, if ever
+    fn expires(&self) -> Option<Instant> {
+        match self.head_since {
+            Some(since) => self.timeouts.header.map(|timeout| since + timeout),
+            None => self.timeouts.keep_alive.map(|timeout| self.since + timeout),
+        }
+    }
+}
+
+pub struct Reactor {
+    epoll: OwnedFd,
+    waker: Waker,
+    // Handed over from other threads, the reactor thread picks them up when
+    // woken and is the only one touching the epoll set
+    incoming: Mutex<Vec<Parked>>,
+    // How many the reactor thread was watching last time round, and how many
+    // it may
+    parked: AtomicUsize,
+    max_parked: usize,
+    stopped: AtomicBool,
+}
+
+impl Reactor {
+    pub fn new(max_parked: usize) -> io::Result<Self> {
+        // SAFETY: epoll_create1(2) takes no pointers, the fd is owned below
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd was just created and nothing else owns it
+        let epoll = unsafe { OwnedFd::from_raw_fd(fd) };
+        let reactor = Self {
+            epoll,
+            waker: Waker::new()?,
+            incoming: Mutex::new(Vec::new()),
+            parked: AtomicUsize::new(0),
+            max_parked,
+            stopped: AtomicBool::new(false),
+        };
+        reactor.ctl(libc::EPOLL_CTL_ADD, &reactor.waker, WAKER)?;
+        Ok(reactor)
+    }
+
+    // Safe to call from any thread
+    pub fn park(&self, conn: Parked) {
+        self.incoming
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(conn);
+        self.wake();
+    }
+
+    // Whether another connection would take us past max_parked. Checked
+    // before parking rather than in park, so the caller still has the
+    // connection to turn away.
+    pub fn is_full(&self) -> bool {
+        let incoming = self
+            .incoming
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len();
+        incoming + self.parked.load(Ordering::SeqCst) >= self.max_parked
+    }
+
+    // Closes everything still parked and has run return
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        if let Err(e) = self.waker.wake() {
+            error!("Error waking the reactor: {:?}", e);
+        }
+    }
+
+    // Runs until stop is called, dispatching complete request heads to pool
+    pub fn run(
+        self: &Arc<Self>,
+        pool: &ThreadPool,
+        running: &Arc<AtomicBool>,
+        stats: &Arc<Stats>,
+    ) -> io::Result<()> {
+        let mut parked: HashMap<u64, Parked> = HashMap::new();
+        let mut next_token = 0;
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        // The soonest any parked connection times out
+        let mut next_expiry: Option<Instant> = None;
+        loop {
+            let timeout = match next_expiry {
+                // Rounded up so we don't wake just before it and spin
+                Some(at) => at
+                    .saturating_duration_since(Instant::now())
+                    .as_millis()
+                    .saturating_add(1)
+                    .min(libc::c_int::MAX as u128) as libc::c_int,
+                None => -1,
+            };
+            // SAFETY: events is valid for MAX_EVENTS entries for the duration
+            // of the call
+            let n = unsafe {
+                libc::epoll_wait(
+                    self.epoll.as_raw_fd(),
+                    events.as_mut_ptr(),
+                    MAX_EVENTS as libc::c_int,
+                    timeout,
+                )
+            };
+            if n == -1 {
+                let e = io::Error::last_os_error();
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            for event in &events[..n as usize] {
+                let token = event.u64;
+                if token == WAKER {
+                    self.waker.drain()?;
+                    continue;
+                }
+                let Some(conn) = parked.get_mut(&token) else {
+                    continue;
+                };
+                match fill(conn) {
+                    Ok(false) => {
+                        next_expiry = soonest(next_expiry, conn.expires());
+                        continue;
+                    }
+                    Ok(true) => {}
+                    // Gone away, or the drain shut down its read side
+                    Err(_) => {
+                        parked.remove(&token);
+                        continue;
+                    }
+                }
+                if let Some(conn) = parked.remove(&token) {
+                    self.ctl(libc::EPOLL_CTL_DEL, &conn.stream, token)?;
+                    self.dispatch(conn, pool, running, stats);
+                }
+            }
+            if next_expiry.is_some_and(|at| at <= Instant::now()) {
+                next_expiry = self.expire(&mut parked, stats)?;
+            }
+            if self.stopped.load(Ordering::SeqCst) {
+                info!(
+                    "Reactor stopping, closing {} idle connections",
+                    parked.len()
+                );
+                return Ok(());
+            }
+            let incoming =
+                std::mem::take(&mut *self.incoming.lock().unwrap_or_else(PoisonError::into_inner));
+            for conn in incoming {
+                // Pipelined requests can arrive with the previous one
+                if head_complete(&conn.buf) {
+                    self.dispatch(conn, pool, running, stats);
+                    continue;
+                }
+                let token = next_token;
+                next_token += 1;
+                if let Err(e) = self.ctl(libc::EPOLL_CTL_ADD, &conn.stream, token) {
+                    error!("Unable to watch connection from {}: {:?}", conn.peer, e);
+                    continue;
+                }
+                next_expiry = soonest(next_expiry, conn.expires());
+                parked.insert(token, conn);
+            }
+            self.parked.store(parked.len(), Ordering::SeqCst);
+        }
+    }
+
+    // Closes the connections that have waited too long, answering the ones
+    // part way through a head with a 408. Returns when the next one is due.
+    fn expire(
+        &self,
+        parked: &mut HashMap<u64, Parked>,
+        stats: &Stats,
+    ) -> io::Result<Option<Instant>> {
+        let now = Instant::now();
+        let expired: Vec<u64> = parked
+            .iter()
+            .filter(|(_, conn)| conn.expires().is_some_and(|at| at <= now))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            let Some(conn) = parked.remove(&token) else {
+                continue;
+            };
+            self.ctl(libc::EPOLL_CTL_DEL, &conn.stream, token)?;
+            stats.record_timeout();
+            if !conn.buf.is_empty() {
+                warn!("Timed out reading a request head from {}", conn.peer);
+                reject(&conn.stream, conn.peer, ClientError::RequestTimeout.into());
+            }
+        }
+        Ok(parked.values().filter_map(Parked::expires).min())
+    }
+
+    fn dispatch(
+        self: &Arc<Self>,
+        conn: Parked,
+        pool: &ThreadPool,
+        running: &Arc<AtomicBool>,
+        stats: &Arc<Stats>,
+    ) {
+        if pool.is_full() {
+            warn!("Thread pool is full, rejecting {}", conn.peer);
+            reject(
+                &conn.stream,
+                conn.peer,
+                ServerError::ServiceUnavailable.into(),
+            );
+            return;
+        }
+        conn.guard.set_idle(false);
+        let reactor = Arc::clone(self);
+        let running = Arc::clone(running);
+        let stats = Arc::clone(stats);
+        let res = pool.execute(move || {
+            let (stream, peer) = (conn.stream.try_clone(), conn.peer);
+            let Ok(stream) = stream else {
+                return;
+            };
+            run_guarded(&stream, peer, &stats, || {
+                resume(conn, &reactor, &running, &stats)?;
+                Ok(())
+            });
+        });
+        if let Err(e) = res {
+            error!("Unable to hand a request to the thread pool: {}", e);
+        }
+    }
+
+    fn ctl(&self, op: libc::c_int, fd: &impl AsFd, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+            u64: token,
+        };
+        // SAFETY: event is valid for the duration of the call and fd is open
+        let res = unsafe {
+            libc::epoll_ctl(
+                self.epoll.as_raw_fd(),
+                op,
+                fd.as_fd().as_raw_fd(),
+                &mut event,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// Reads whatever has arrived, returning whether there is now a full request
+// head. Errors when the client has gone away.
+fn fill(conn: &mut Parked) -> io::Result<bool> {
+    let mut chunk = [0; READ_CHUNK];
+    loop {
+        match (&conn.stream).read(&mut chunk) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => conn.buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    // The next request has started, from here it is timed as a head
+    if !conn.buf.is_empty() && conn.head_since.is_none() {
+        conn.head_since = Some(Instant::now());
+    }
+    if head_complete(&conn.buf) {
+        return Ok(true);
+    }
+    // A head that still isn't finished when it is bigger than the limits
+    // allow for any path won't be accepted by the parser either
+    if conn.buf.len() > conn.router.limits().max_head() {
+        warn!("Request head from {} is too big", conn.peer);
+        reject(&conn.stream, conn.peer, ClientError::HeadersTooLarge.into());
+        return Err(ErrorKind::InvalidData.into());
+    }
+    Ok(false)
+}
+
+fn head_complete(buf: &[u8]) -> bool {
+    buf.windows(4).any(|w| w == b"\r\n\r\n")
+}
+
+// Routes the request the reactor read the head of, on a worker. The body, if
+// there is one, is read straight from the socket.
+fn resume(
+    conn: Parked,
+    reactor: &Reactor,
+    running: &AtomicBool,
+    stats: &Stats,
+) -> crate::Result<()> {
+    let Parked {
+        stream,
+        peer,
+        guard,
+        slot,
+        router,
+        timeouts,
+        buf,
+        ..
+    } = conn;
+    stream.set_nonblocking(false)?;
+    let timed = Timed::new(&stream, timeouts)?;
+    timed.reading_head();
+    let mut reader = BufReader::new(Resumed {
+        read: Cursor::new(buf).chain(&timed),
+        timed: &timed,
+    });
+    let draining = !running.load(Ordering::SeqCst);
+    let keep_alive = match router.route(&mut reader, draining, peer.ip(), Some(&timed)) {
+        Ok(keep_alive) => keep_alive,
+        Err(e) => {
+            if e == ClientError::RequestTimeout.into() {
+                stats.record_timeout();
+            }
+            return Err(e);
+        }
+    };
+    info!("Request from {} handled OK", peer);
+    if !keep_alive || !running.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    // Anything read past this request belongs to the next one
+    let mut rest = reader.buffer().to_vec();
+    let (cursor, _) = reader.into_inner().read.into_inner();
+    let pos = cursor.position() as usize;
+    rest.extend_from_slice(&cursor.into_inner()[pos..]);
+    // Better to close an idle connection than let them pile up, the client
+    // can always reconnect
+    if reactor.is_full() {
+        info!("Reactor is full, closing the connection from {}", peer);
+        return Ok(());
+    }
+    let mut conn = Parked::new(stream, peer, guard, slot, router, timeouts)?;
+    // Otherwise waiting for the next request to start
+    if rest.is_empty() {
+        conn.head_since = None;
+    }
+    conn.buf = rest;
+    // A TLS session can already have taken the start of the next request off
+    // the socket, and epoll won't wake us for that
+    #[cfg(feature = "tls")]
+    if matches!(conn.stream, Stream::Tls(_)) && fill(&mut conn).is_err() {
+        return Ok(());
+    }
+    reactor.park(conn);
+    Ok(())
+}
+
+// What the router sees of a resumed connection: what the reactor already
+// read, then the socket
+struct Resumed<'a> {
+    read: io::Chain<Cursor<Vec<u8>>, &'a Timed<'a>>,
+    timed: &'a Timed<'a>,
+}
+
+impl Read for Resumed<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read.read(buf)
+    }
+}
+
+impl Write for Resumed<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.timed.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.timed.flush()
+    }
+}
+
+// The earlier of two expiries, where None is never
+fn soonest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod reactor {
+        use crate::server::reactor::head_complete;
+
+        #[test]
+        fn waits_for_the_whole_head() {
+            assert!(!head_complete(b""));
+            assert!(!head_complete(b"GET / HTTP/1.1\r\nHost: a\r\n"));
+            assert!(head_complete(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"));
+            assert!(head_complete(b"POST /files/a HTTP/1.1\r\n\r\npartial body"));
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use super::Waker;
+use crate::{Config, Result};
+use log::{error, info, warn};
+use signal_hook::{
+    consts::{SIGHUP, SIGUSR2, TERM_SIGNALS},
+    iterator::Signals,
+    low_level,
+};
+use std::process;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::thread;
+
+// Stops the server it came from, starting the same drain as a signal would.
+// Cheap to clone and fine to hand to other threads.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    running: Arc<AtomicBool>,
+    upgrade: Arc<AtomicBool>,
+    // Picked up by the accept loop, only the latest one counts
+    reload: Arc<Mutex<Option<Config>>>,
+    waker: Arc<Waker>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(
+        running: Arc<AtomicBool>,
+        upgrade: Arc<AtomicBool>,
+        reload: Arc<Mutex<Option<Config>>>,
+        waker: Arc<Waker>,
+    ) -> Self {
+        Self {
+            running,
+            upgrade,
+            reload,
+            waker,
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Err(e) = self.waker.wake() {
+            error!("Error waking the accept loop: {:?}", e);
+        }
+    }
+
+    // Asks the server to hand its listeners to a new copy of the binary and
+    // drain once that is accepting. It keeps serving if the new one fails.
+    pub fn upgrade(&self) {
+        self.upgrade.store(true, Ordering::SeqCst);
+        if let Err(e) = self.waker.wake() {
+            error!("Error waking the accept loop: {:?}", e);
+        }
+    }
+
+    // Has the server switch to config for new connections, see Server::reload
+    pub fn reload(&self, config: Config) {
+        match self.reload.lock() {
+            Ok(mut pending) => *pending = Some(config),
+            Err(e) => *e.into_inner() = Some(config),
+        }
+        if let Err(e) = self.waker.wake() {
+            error!("Error waking the accept loop: {:?}", e);
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        !self.running.load(Ordering::SeqCst)
+    }
+
+    // Shuts down on SIGINT or SIGTERM, and stops straight away on a second
+    // one rather than waiting for the drain. The server leaves signals alone
+    // unless this is called, so embedding applications keep control of them.
+    pub fn shutdown_on_signals(&self) -> Result<()> {
+        let mut signals = Signals::new(TERM_SIGNALS)?;
+        let handle = self.clone();
+        thread::spawn(move || {
+            // Counted here rather than going by is_shutdown, so a server
+            // draining after an upgrade still drains on its first signal
+            let mut signalled = false;
+            for signal in signals.forever() {
+                if !signalled {
+                    info!("Starting shutdown (signal {})...", signal);
+                    handle.shutdown();
+                    signalled = true;
+                    continue;
+                }
+                warn!("Stopping without draining (signal {})", signal);
+                // Dies the way it would have without the handler, and only
+                // returns if it couldn't
+                if let Err(e) = low_level::emulate_default_handler(signal) {
+                    error!("Error stopping on signal {}: {:?}", signal, e);
+                }
+                process::exit(1);
+            }
+        });
+        Ok(())
+    }
+
+    // Upgrades on SIGUSR2, opt in for the same reason as shutdown_on_signals
+    pub fn upgrade_on_signal(&self) -> Result<()> {
+        let mut signals = Signals::new([SIGUSR2])?;
+        let handle = self.clone();
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                info!("Starting upgrade (signal {})...", SIGUSR2);
+                handle.upgrade();
+            }
+        });
+        Ok(())
+    }
+
+    // Reloads on SIGHUP with whatever load returns, e.g. Config::reload. A
+    // config that fails to load is logged and the old one kept.
+    pub fn reload_on_signal<F>(&self, load: F) -> Result<()>
+    where
+        F: Fn() -> Result<Config> + Send + 'static,
+    {
+        let mut signals = Signals::new([SIGHUP])?;
+        let handle = self.clone();
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                info!("Reloading config (signal {})...", SIGHUP);
+                match load() {
+                    Ok(config) => handle.reload(config),
+                    Err(e) => error!("Invalid config, keeping the old one: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Counters shared between the acceptor and the workers, cheap enough to bump
+// on every event and read from anywhere
+#[derive(Debug, Default)]
+pub struct Stats {
+    panics: AtomicUsize,
+    respawns: AtomicUsize,
+    dropped: AtomicUsize,
+    timeouts: AtomicUsize,
+}
+
+impl Stats {
+    pub fn panics(&self) -> usize {
+        self.panics.load(Ordering::SeqCst)
+    }
+    pub fn respawns(&self) -> usize {
+        self.respawns.load(Ordering::SeqCst)
+    }
+    // Connections cut off by shutdown before they were done
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+    // Connections closed for being too slow, a lot of these suggests someone
+    // is trying to tie the server up
+    pub fn timeouts(&self) -> usize {
+        self.timeouts.load(Ordering::SeqCst)
+    }
+    // Returns the new total so callers can log it
+    pub(crate) fn record_panic(&self) -> usize {
+        self.panics.fetch_add(1, Ordering::SeqCst) + 1
+    }
+    pub(crate) fn record_respawn(&self) -> usize {
+        self.respawns.fetch_add(1, Ordering::SeqCst) + 1
+    }
+    pub(crate) fn record_timeout(&self) -> usize {
+        self.timeouts.fetch_add(1, Ordering::SeqCst) + 1
+    }
+    pub(crate) fn record_dropped(&self, count: usize) -> usize {
+        self.dropped.fetch_add(count, Ordering::SeqCst) + count
+    }
+}
@@ -1,7 +1,10 @@
+use super::Stats;
 use crate::Result;
-use log::{error, info};
-use std::sync::{mpsc, Arc, Mutex};
+use log::{error, info, warn};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Condvar, Mutex, PoisonError};
 use std::thread;
+use std::time::{Duration, Instant};
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
@@ -10,42 +13,175 @@ enum Message {
     Terminate,
 }
 
+type Receiver = Arc<Mutex<mpsc::Receiver<Message>>>;
+
+// Jobs that are either running or waiting in the queue. A condvar rather than
+// an atomic so that shutdown can sleep until the pool is idle.
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl InFlight {
+    fn get(&self) -> usize {
+        *self.count.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    fn inc(&self) {
+        *self.count.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+    }
+    fn dec(&self) {
+        let mut count = self.count.lock().unwrap_or_else(PoisonError::into_inner);
+        *count -= 1;
+        if *count == 0 {
+            self.idle.notify_all();
+        }
+    }
+    fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut count = self.count.lock().unwrap_or_else(PoisonError::into_inner);
+        while *count > 0 {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                return false;
+            };
+            count = self
+                .idle
+                .wait_timeout(count, remaining)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        true
+    }
+}
+
+// Whether any connection is waiting for a worker, for workers holding on to
+// a keep-alive connection to check without keeping the pool alive
+#[derive(Clone)]
+pub struct Backlog {
+    in_flight: Arc<InFlight>,
+    size: usize,
+}
+
+impl Backlog {
+    pub fn any(&self) -> bool {
+        self.in_flight.get() > self.size
+    }
+}
+
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    workers: Mutex<Vec<Worker>>,
+    sender: mpsc::SyncSender<Message>,
+    // Kept so that dead workers can be replaced
+    receiver: Receiver,
+    in_flight: Arc<InFlight>,
+    size: usize,
+    capacity: usize,
+    stats: Arc<Stats>,
 }
 
 impl ThreadPool {
-    pub fn new(size: usize) -> Self {
+    pub fn new(size: usize, queue_depth: usize, stats: Arc<Stats>) -> Self {
         assert!(size > 0, "The thread pool requires a count greater than 0");
 
-        let (sender, receiver) = mpsc::channel();
+        // Every worker can be busy with a job while queue_depth more wait, the
+        // channel is bounded to match so it never holds more than that
+        let capacity = size + queue_depth;
+        let (sender, receiver) = mpsc::sync_channel(capacity);
         let receiver = Arc::new(Mutex::new(receiver));
+        let in_flight = Arc::new(InFlight::default());
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(
+                id,
+                Arc::clone(&receiver),
+                Arc::clone(&in_flight),
+                Arc::clone(&stats),
+            ));
         }
 
-        Self { workers, sender }
+        Self {
+            workers: Mutex::new(workers),
+            sender,
+            receiver,
+            in_flight,
+            size,
+            capacity,
+            stats,
+        }
+    }
+
+    // There is a single acceptor feeding the pool, so if this is false the
+    // next execute won't have to wait
+    pub fn is_full(&self) -> bool {
+        self.in_flight.get() >= self.capacity
+    }
+
+    pub fn backlog(&self) -> Backlog {
+        Backlog {
+            in_flight: Arc::clone(&self.in_flight),
+            size: self.size,
+        }
+    }
+
+    // Returns false if there were still jobs in flight when the timeout ran out
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        self.in_flight.wait_idle(timeout)
     }
 
     pub fn execute<F: FnOnce() + Send + 'static>(&self, f: F) -> Result<()> {
+        self.respawn_dead_workers()?;
         let job = Box::new(f);
+        self.in_flight.inc();
         self.sender.send(Message::NewJob(job))?;
         Ok(())
     }
+
+    // Jobs are run inside catch_unwind so this shouldn't happen, but if a
+    // worker does die the pool would otherwise quietly shrink until nothing is
+    // left to serve requests
+    fn respawn_dead_workers(&self) -> Result<()> {
+        let mut workers = self.workers.lock()?;
+        for worker in workers.iter_mut() {
+            if !worker.thread.as_ref().is_some_and(|t| t.is_finished()) {
+                continue;
+            }
+            if let Some(thread) = worker.thread.take() {
+                match thread.join() {
+                    Ok(Ok(())) => warn!("Worker {} exited unexpectedly", worker.id),
+                    Ok(Err(e)) => error!("Worker {} failed: {}", worker.id, e),
+                    Err(e) => error!("Worker {} died: {:?}", worker.id, e),
+                }
+            }
+            let total = self.stats.record_respawn();
+            warn!(
+                "Respawning worker {} ({} respawns so far)",
+                worker.id, total
+            );
+            *worker = Worker::new(
+                worker.id,
+                Arc::clone(&self.receiver),
+                Arc::clone(&self.in_flight),
+                Arc::clone(&self.stats),
+            );
+        }
+        Ok(())
+    }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        for _ in &self.workers {
+        let workers = self
+            .workers
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for _ in workers.iter() {
             self.sender
                 .send(Message::Terminate)
                 .unwrap_or_else(|e| error!("Error sending termination message: {:?}", e));
         }
 
-        for worker in &mut self.workers {
+        for worker in workers.iter_mut() {
             if let Some(thread) = worker.thread.take() {
                 info!("Attempting to complete worker {}", worker.id);
                 if let Err(e) = thread.join() {
@@ -63,7 +199,7 @@ pub struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Self {
+    fn new(id: usize, receiver: Receiver, in_flight: Arc<InFlight>, stats: Arc<Stats>) -> Self {
         let thread = thread::spawn(move || -> Result<()> {
             loop {
                 let message = receiver.lock()?.recv()?;
@@ -71,7 +207,13 @@ impl Worker {
                 match message {
                     Message::NewJob(job) => {
                         info!("Worker {} got a job; executing.", id);
-                        job();
+                        // The server catches panics itself so it can answer
+                        // with a 500, this is the backstop for everything else
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            let total = stats.record_panic();
+                            error!("Worker {} caught a panic ({} so far)", id, total);
+                        }
+                        in_flight.dec();
                     }
                     Message::Terminate => {
                         info!("Worker {} terminating.", id);
@@ -88,3 +230,43 @@ impl Worker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    mod thread_pool {
+        use crate::server::{Stats, ThreadPool};
+        use std::sync::{mpsc, Arc};
+        use std::time::Duration;
+
+        #[test]
+        fn fills_up_to_workers_plus_queue_depth() {
+            let pool = ThreadPool::new(1, 1, Arc::new(Stats::default()));
+            let (release, wait) = mpsc::channel::<()>();
+            let (started, running) = mpsc::channel::<()>();
+            pool.execute(move || {
+                started.send(()).unwrap();
+                wait.recv().unwrap();
+            })
+            .unwrap();
+            running.recv().unwrap();
+            assert!(!pool.is_full());
+            pool.execute(|| {}).unwrap();
+            assert!(pool.is_full());
+            release.send(()).unwrap();
+        }
+
+        #[test]
+        fn survives_panicking_jobs() {
+            let stats = Arc::new(Stats::default());
+            let pool = ThreadPool::new(1, 0, Arc::clone(&stats));
+            pool.execute(|| panic!("job panicked")).unwrap();
+            let (done, wait) = mpsc::channel::<()>();
+            pool.execute(move || done.send(()).unwrap()).unwrap();
+            wait.recv().unwrap();
+            assert!(pool.wait_idle(Duration::from_secs(5)));
+            assert_eq!(1, stats.panics());
+            assert_eq!(0, stats.respawns());
+        }
+    }
+}
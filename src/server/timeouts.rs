@@ -0,0 +1,178 @@
+use super::Stream;
+use crate::client_cert::ClientCert;
+use crate::constants::{
+    BODY_TIMEOUT_SECS, HEADER_TIMEOUT_SECS, KEEP_ALIVE_TIMEOUT_SECS, WRITE_TIMEOUT_SECS,
+};
+use crate::router::ConnInfo;
+use std::cell::Cell;
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+// How long a client gets for each part of a connection. None means no limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    // For the whole head, from its first byte, so a client trickling it in a
+    // byte at a time doesn't get any longer
+    pub header: Option<Duration>,
+    // Between reads of the body, uploads can take as long as they like as
+    // long as they keep moving
+    pub body: Option<Duration>,
+    // Between writes of the response
+    pub write: Option<Duration>,
+    // Waiting for the next request on a persistent connection
+    pub keep_alive: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            header: Some(Duration::from_secs(HEADER_TIMEOUT_SECS)),
+            body: Some(Duration::from_secs(BODY_TIMEOUT_SECS)),
+            write: Some(Duration::from_secs(WRITE_TIMEOUT_SECS)),
+            keep_alive: Some(Duration::from_secs(KEEP_ALIVE_TIMEOUT_SECS)),
+        }
+    }
+}
+
+// How often a worker waiting on an idle connection wakes to see whether another
+// connection needs it more
+const IDLE_CHECK: Duration = Duration::from_secs(1);
+
+// A blocking stream with whichever timeout applies to what is being read.
+// Everything it reads that runs out of time fails with TimedOut.
+pub struct Timed<'a> {
+    stream: &'a Stream,
+    timeouts: Timeouts,
+    // Checked before every read, for the head
+    deadline: Cell<Option<Instant>>,
+    // Applied to every read, for everything else
+    per_read: Cell<Option<Duration>>,
+}
+
+impl<'a> Timed<'a> {
+    pub fn new(stream: &'a Stream, timeouts: Timeouts) -> io::Result<Self> {
+        stream.set_write_timeout(timeouts.write)?;
+        Ok(Self {
+            stream,
+            timeouts,
+            deadline: Cell::new(None),
+            per_read: Cell::new(None),
+        })
+    }
+
+    // Waiting for a request on a connection that has already had one
+    // Reads time out every IDLE_CHECK at most, the caller works out whether
+    // keep_alive has run out
+    pub fn idle(&self) {
+        self.deadline.set(None);
+        self.per_read.set(Some(
+            self.timeouts
+                .keep_alive
+                .map_or(IDLE_CHECK, |timeout| timeout.min(IDLE_CHECK)),
+        ));
+    }
+
+    pub fn reading_head(&self) {
+        self.deadline
+            .set(self.timeouts.header.map(|timeout| Instant::now() + timeout));
+        self.per_read.set(None);
+    }
+}
+
+impl ConnInfo for Timed<'_> {
+    fn reading_body(&self) {
+        self.deadline.set(None);
+        self.per_read.set(self.timeouts.body);
+    }
+    fn client_cert(&self) -> Option<ClientCert> {
+        self.stream.client_cert()
+    }
+}
+
+impl Read for &Timed<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline.get() {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => Some(left),
+                _ => return Err(ErrorKind::TimedOut.into()),
+            },
+            None => self.per_read.get(),
+        };
+        self.stream.set_read_timeout(timeout)?;
+        let mut stream = self.stream;
+        stream.read(buf).map_err(timed_out)
+    }
+}
+
+impl Write for &Timed<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stream = self.stream;
+        stream.write(buf).map_err(timed_out)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        let mut stream = self.stream;
+        stream.flush().map_err(timed_out)
+    }
+}
+
+// A socket timeout shows up as WouldBlock on Unix
+fn timed_out(e: io::Error) -> io::Error {
+    match e.kind() {
+        ErrorKind::WouldBlock => ErrorKind::TimedOut.into(),
+        _ => e,
+    }
+}
+
+// Timeouts are given in whole seconds, with 0 turning one off
+pub(crate) fn from_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod timeouts {
+        use crate::router::ConnInfo;
+        use crate::server::listener::Stream;
+        use crate::server::timeouts::{Timed, Timeouts};
+        use std::io::{ErrorKind, Read, Write};
+        use std::os::unix::net::UnixStream;
+        use std::time::{Duration, Instant};
+
+        #[test]
+        fn gives_the_head_a_deadline() {
+            let (server, mut client) = UnixStream::pair().unwrap();
+            let stream = Stream::Unix(server);
+            let timed = Timed::new(
+                &stream,
+                Timeouts {
+                    header: Some(Duration::from_millis(200)),
+                    body: Some(Duration::from_millis(100)),
+                    ..Timeouts::default()
+                },
+            )
+            .unwrap();
+
+            // Trickled in, each read is well inside the per read timeout but
+            // the head as a whole isn't
+            timed.reading_head();
+            let start = Instant::now();
+            let mut buf = [0; 1];
+            let err = loop {
+                client.write_all(b"a").unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+                if let Err(e) = (&timed).read(&mut buf) {
+                    break e;
+                }
+            };
+            assert_eq!(ErrorKind::TimedOut, err.kind());
+            assert!(start.elapsed() < Duration::from_secs(1));
+
+            // The body only has to keep moving
+            timed.reading_body();
+            let mut rest = Vec::new();
+            let err = (&timed).read_to_end(&mut rest).unwrap_err();
+            assert_eq!(ErrorKind::TimedOut, err.kind());
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::client_cert::ClientCert;
+use rustls::{ServerConfig, ServerConnection};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::Duration;
+
+// A TCP connection from a tls: listener. The handshake happens on the first
+// read, so it's done by whichever worker picks the connection up and is timed
+// like the rest of the request head.
+//
+// Clones share the session, the same way clones of a TcpStream share the
+// socket. Only one thread reads or writes at a time, the lock is there so the
+// stream can be read and written through shared references like the others.
+#[derive(Debug, Clone)]
+pub struct TlsStream {
+    session: Arc<Session>,
+}
+
+#[derive(Debug)]
+struct Session {
+    tcp: TcpStream,
+    conn: Mutex<ServerConnection>,
+    // Parsed from the client's certificate once the handshake is done, it
+    // can't change after that
+    client_cert: OnceLock<Option<ClientCert>>,
+}
+
+impl TlsStream {
+    pub fn new(tcp: TcpStream, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(Self {
+            session: Arc::new(Session {
+                tcp,
+                conn: Mutex::new(conn),
+                client_cert: OnceLock::new(),
+            }),
+        })
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.session.tcp.shutdown(how)
+    }
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.session.tcp.set_nonblocking(nonblocking)
+    }
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.session.tcp.set_read_timeout(timeout)
+    }
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.session.tcp.set_write_timeout(timeout)
+    }
+
+    // Only set when --tls_client_ca is, and the client sent a certificate
+    // that checked out against it
+    pub fn client_cert(&self) -> Option<ClientCert> {
+        if let Some(cert) = self.session.client_cert.get() {
+            return cert.clone();
+        }
+        let conn = self.lock();
+        if conn.is_handshaking() {
+            return None;
+        }
+        let cert = conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| ClientCert::from_der(cert));
+        self.session.client_cert.get_or_init(|| cert).clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ServerConnection> {
+        // A panic part way through a request leaves nothing half updated that
+        // rustls wouldn't report as an error on the next call
+        self.session
+            .conn
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl AsFd for TlsStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.session.tcp.as_fd()
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut conn = self.lock();
+        let mut tcp = &self.session.tcp;
+        match rustls::Stream::new(&mut *conn, &mut tcp).read(buf) {
+            // Plenty of clients close without a close_notify. HTTP knows
+            // where its messages end, so that's no different to a plain close.
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+            res => res,
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    // Unlike rustls::Stream this reports errors sending the records, so a
+    // write timeout isn't lost
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.lock();
+        let n = conn.writer().write(buf)?;
+        send(&mut conn, &self.session.tcp)?;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        send(&mut self.lock(), &self.session.tcp)
+    }
+}
+
+// Before the handshake there's nothing that can be sent yet, whatever was
+// written is dropped with the connection if it never finishes
+fn send(conn: &mut ServerConnection, mut tcp: &TcpStream) -> io::Result<()> {
+    while conn.wants_write() && !conn.is_handshaking() {
+        if conn.write_tls(&mut tcp)? == 0 {
+            return Err(ErrorKind::WriteZero.into());
+        }
+    }
+    Ok(())
+}
+
+// Once the last clone has gone, lets the client know the response wasn't cut
+// short. Best effort, the socket may already be gone or non-blocking.
+impl Drop for Session {
+    fn drop(&mut self) {
+        let conn = self.conn.get_mut().unwrap_or_else(PoisonError::into_inner);
+        if conn.is_handshaking() {
+            return;
+        }
+        conn.send_close_notify();
+        let _ = send(conn, &self.tcp);
+    }
+}
@@ -0,0 +1,219 @@
+use super::Listener;
+use log::warn;
+use std::ffi::{CString, OsString};
+use std::io::{self, ErrorKind};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Zero downtime upgrades. The running server starts a fresh copy of its binary
+// and passes the listeners down the same way socket activation does, then
+// waits for it to report READY=1 like a systemd notify service would. Both are
+// accepting on the same sockets until then, so nothing gets refused while the
+// new process starts up. Rather than a NOTIFY_SOCKET path that anything could
+// write to, the new process gets its end of a socketpair, right after the
+// listeners, and UPGRADE_NOTIFY_FD says which fd that is.
+const READY: &[u8] = b"READY=1";
+const NOTIFY_FD: &str = "UPGRADE_NOTIFY_FD";
+// Plenty for a process that doesn't have to bind anything
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+// How often the wait for READY=1 checks the new process hasn't died
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Cleared from the environment passed down, the upgrade sets its own
+const PROTOCOL_VARS: &[&str] = &[
+    "LISTEN_PID",
+    "LISTEN_FDS",
+    "LISTEN_FDNAMES",
+    "NOTIFY_SOCKET",
+    NOTIFY_FD,
+];
+
+// The fd is only good for one notification
+static NOTIFIED: AtomicBool = AtomicBool::new(false);
+
+// Starts the new process and waits until it is accepting, returning its pid.
+// On error the new process is gone and the caller should carry on serving.
+pub fn hand_off(listeners: &[Listener]) -> io::Result<u32> {
+    let (notify, theirs) = UnixDatagram::pair()?;
+    let pid = spawn(listeners, theirs.as_raw_fd())?;
+    // Only the new process should hold its end
+    drop(theirs);
+    wait_ready(&notify, pid).map(|_| pid as u32)
+}
+
+// Tells whoever started us that we are accepting, when they asked to know
+pub fn notify_ready() {
+    if NOTIFIED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if let Some(notify) = notify_fd() {
+        if let Err(e) = notify.send(READY) {
+            warn!(
+                "Unable to notify the old process that we are ready: {:?}",
+                e
+            );
+        }
+        return;
+    }
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = UnixDatagram::unbound().and_then(|s| s.send_to(READY, &path)) {
+        warn!("Unable to notify {:?} that we are ready: {:?}", path, e);
+    }
+}
+
+// Our end of the socketpair, when an upgrade started us. Like the listeners
+// it's only taken when LISTEN_PID says it was meant for this process.
+fn notify_fd() -> Option<UnixDatagram> {
+    let fd: RawFd = std::env::var(NOTIFY_FD).ok()?.trim().parse().ok()?;
+    let listen_pid: u32 = std::env::var("LISTEN_PID").ok()?.trim().parse().ok()?;
+    if listen_pid != std::process::id() || fd < 3 {
+        return None;
+    }
+    // SAFETY: fcntl(2) on an fd number, a closed one just fails with EBADF
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        warn!("{} names fd {}, which isn't open", NOTIFY_FD, fd);
+        return None;
+    }
+    // SAFETY: the upgrade hands this fd to this process and nothing else in
+    // it knows about it
+    Some(unsafe { UnixDatagram::from_raw_fd(fd) })
+}
+
+fn spawn(listeners: &[Listener], notify: RawFd) -> io::Result<libc::pid_t> {
+    // Everything the child needs is allocated before the fork, after it only
+    // async-signal-safe calls are allowed
+    let exe = cstring(current_exe()?)?;
+    // Taking the listeners is opt in, and the new process has to. Added once
+    // so that args don't grow with every upgrade.
+    let activate = OsString::from("--socket_activation=true");
+    let args = std::env::args_os()
+        .filter(|arg| *arg != activate)
+        .chain([activate.clone()])
+        .map(cstring)
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut env = std::env::vars_os()
+        .filter(|(key, _)| !PROTOCOL_VARS.iter().any(|var| key == var))
+        .map(|(mut key, val)| {
+            key.push("=");
+            key.push(val);
+            cstring(key)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    env.push(cstring(format!("LISTEN_FDS={}", listeners.len()).into())?);
+    let names: Vec<&str> = listeners.iter().map(Listener::fd_name).collect();
+    env.push(cstring(
+        format!("LISTEN_FDNAMES={}", names.join(":")).into(),
+    )?);
+    env.push(cstring(
+        format!("{}={}", NOTIFY_FD, 3 + listeners.len()).into(),
+    )?);
+    // The child's pid is only known after the fork, it writes it over the
+    // zeros. Leading zeros still parse.
+    let mut listen_pid = *b"LISTEN_PID=0000000000\0";
+    let listen_pid = listen_pid.as_mut_ptr();
+
+    let fds: Vec<RawFd> = listeners
+        .iter()
+        .map(|l| l.as_fd().as_raw_fd())
+        .chain([notify])
+        .collect();
+    let mut moved: Vec<RawFd> = vec![0; fds.len()];
+    let argv: Vec<*const libc::c_char> = args
+        .iter()
+        .map(|a| a.as_ptr())
+        .chain([std::ptr::null()])
+        .collect();
+    let envp: Vec<*const libc::c_char> = env
+        .iter()
+        .map(|e| e.as_ptr())
+        .chain([listen_pid as *const libc::c_char, std::ptr::null()])
+        .collect();
+
+    // SAFETY: between fork and execve the child only touches memory that was
+    // allocated beforehand and makes async-signal-safe calls
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => unsafe {
+            let mut pid = libc::getpid() as u32;
+            for i in (11..21).rev() {
+                *listen_pid.add(i) = b'0' + (pid % 10) as u8;
+                pid /= 10;
+            }
+            // Moved out of the way first so that none of them gets
+            // overwritten before it has been copied into place. The copies
+            // are close-on-exec, only the ones dup2 puts at 3.. get through.
+            for (i, fd) in fds.iter().enumerate() {
+                moved[i] = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 100);
+                if moved[i] == -1 {
+                    libc::_exit(127);
+                }
+            }
+            for (i, fd) in moved.iter().enumerate() {
+                if libc::dup2(*fd, 3 + i as RawFd) == -1 {
+                    libc::_exit(127);
+                }
+            }
+            libc::execve(exe.as_ptr(), argv.as_ptr(), envp.as_ptr());
+            libc::_exit(127)
+        },
+        pid => Ok(pid),
+    }
+}
+
+fn wait_ready(notify: &UnixDatagram, pid: libc::pid_t) -> io::Result<()> {
+    notify.set_read_timeout(Some(POLL_INTERVAL))?;
+    let deadline = Instant::now() + UPGRADE_TIMEOUT;
+    let mut buf = [0; 256];
+    while Instant::now() < deadline {
+        match notify.recv(&mut buf) {
+            Ok(n) if buf[..n].split(|b| *b == b'\n').any(|line| line == READY) => return Ok(()),
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) => {}
+            Err(e) => return Err(e),
+        }
+        // Gone before it was ready, most likely it couldn't start with the
+        // config it was given
+        let mut status = 0;
+        // SAFETY: waitpid(2) on the child we started
+        if unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } == pid {
+            return Err(io::Error::other(format!(
+                "process {} exited before it was ready (status {})",
+                pid, status
+            )));
+        }
+    }
+    // SAFETY: kill(2) on the child we started
+    unsafe { libc::kill(pid, libc::SIGTERM) };
+    // It drains like any other shutdown, reaped in the background so it
+    // doesn't hang around as a zombie
+    thread::spawn(move || unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) });
+    Err(io::Error::new(
+        ErrorKind::TimedOut,
+        format!("process {} wasn't ready in time", pid),
+    ))
+}
+
+// The binary has usually been replaced by the time anyone upgrades, which
+// Linux reports by tacking " (deleted)" on to the old path
+fn current_exe() -> io::Result<OsString> {
+    let exe = std::env::current_exe()?.into_os_string();
+    let bytes = exe.into_vec();
+    let bytes = match bytes.strip_suffix(b" (deleted)") {
+        Some(stripped) => stripped.to_vec(),
+        None => bytes,
+    };
+    Ok(OsString::from_vec(bytes))
+}
+
+fn cstring(s: OsString) -> io::Result<CString> {
+    CString::new(s.into_vec()).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+}
@@ -0,0 +1,112 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::net::UnixStream;
+
+// A self-pipe. The accept loop polls the read end alongside the listener, so
+// writing a byte to the other end gets it out of poll straight away without
+// having to fall back to sleeping and checking a flag.
+#[derive(Debug)]
+pub struct Waker {
+    reader: UnixStream,
+    writer: UnixStream,
+}
+
+impl Waker {
+    pub fn new() -> io::Result<Self> {
+        let (reader, writer) = UnixStream::pair()?;
+        reader.set_nonblocking(true)?;
+        writer.set_nonblocking(true)?;
+        Ok(Self { reader, writer })
+    }
+
+    pub fn wake(&self) -> io::Result<()> {
+        match (&self.writer).write(&[1]) {
+            // A full buffer means a wakeup is already pending
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    // Clears pending wakeups, for anyone polling the waker themselves
+    pub fn drain(&self) -> io::Result<()> {
+        let mut buf = [0; 64];
+        loop {
+            match (&self.reader).read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl AsFd for Waker {
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        self.reader.as_fd()
+    }
+}
+
+// Blocks until at least one of the listeners is readable, returning which ones
+// are. Comes back empty when the waker has been woken, or a signal interrupted
+// the wait, so that the caller gets to check whether it should still be
+// running.
+pub fn wait_readable<T: AsFd>(listeners: &[T], waker: &Waker) -> io::Result<Vec<usize>> {
+    let mut fds: Vec<libc::pollfd> = listeners
+        .iter()
+        .map(|l| l.as_fd().as_raw_fd())
+        .chain(std::iter::once(waker.reader.as_raw_fd()))
+        .map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    // SAFETY: fds is a valid array of pollfd for the duration of the call and
+    // the length passed matches it
+    let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+    if res < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == ErrorKind::Interrupted {
+            return Ok(Vec::new());
+        }
+        return Err(e);
+    }
+    if fds[listeners.len()].revents != 0 {
+        waker.drain()?;
+        return Ok(Vec::new());
+    }
+    Ok(fds[..listeners.len()]
+        .iter()
+        .enumerate()
+        .filter(|(_, fd)| fd.revents != 0)
+        .map(|(i, _)| i)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod waker {
+        use crate::server::waker::{wait_readable, Waker};
+        use std::net::{TcpListener, TcpStream};
+
+        #[test]
+        fn wakes_up_poll() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let waker = Waker::new().unwrap();
+            waker.wake().unwrap();
+            assert!(wait_readable(&[listener], &waker).unwrap().is_empty());
+        }
+
+        #[test]
+        fn reports_pending_connections() {
+            let quiet = TcpListener::bind("127.0.0.1:0").unwrap();
+            let busy = TcpListener::bind("127.0.0.1:0").unwrap();
+            let waker = Waker::new().unwrap();
+            let _client = TcpStream::connect(busy.local_addr().unwrap()).unwrap();
+            assert_eq!(vec![1], wait_readable(&[quiet, busy], &waker).unwrap());
+        }
+    }
+}
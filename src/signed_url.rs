@@ -0,0 +1,249 @@
+use crate::access::served_method;
+use crate::http::{Method, Request, RequestHead};
+use crate::router::Route;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt::{Debug, Formatter};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Short keys can be brute forced from a single link
+const MIN_KEY_LEN: usize = 32;
+
+// A link to sign, see Command::Sign
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    pub method: Method,
+    pub path: String,
+    // From when it's signed
+    pub expires_in: Duration,
+    // Only for uploads, the most the body can be
+    pub max_size: Option<u64>,
+}
+
+impl Link {
+    // Links are only honoured under /files
+    pub fn new(path: &str) -> Option<Link> {
+        let name = path.strip_prefix("/files/")?;
+        if name.is_empty() || name.contains(['/', '?', '#']) {
+            return None;
+        }
+        Some(Link {
+            method: Method::Get,
+            path: path.to_owned(),
+            expires_in: Duration::from_secs(3600),
+            max_size: None,
+        })
+    }
+
+    pub fn set_method(&mut self, method: &str) -> Option<()> {
+        self.method = served_method(method)?;
+        Some(())
+    }
+}
+
+// What a request's signature got it
+#[derive(Debug, PartialEq)]
+pub enum Signed {
+    // No signature, so it's up to the auth rules
+    Unsigned,
+    Valid { max_size: Option<u64> },
+    // Forged, tampered with, expired or for another method
+    Invalid,
+}
+
+// Signs links to files that work without logging in, with an expiry and an
+// HMAC over the method, path, expiry and size limit in the query string
+#[derive(Clone)]
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+// The key stays out of the logs
+impl Debug for UrlSigner {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "UrlSigner")
+    }
+}
+
+impl UrlSigner {
+    pub fn new(key: &str) -> Option<UrlSigner> {
+        (key.len() >= MIN_KEY_LEN).then(|| UrlSigner {
+            key: key.as_bytes().to_vec(),
+        })
+    }
+
+    // The path and query string to hand out
+    pub fn sign(&self, link: &Link, now: SystemTime) -> String {
+        let expires = unix_secs(now) + link.expires_in.as_secs();
+        let mut query = format!("expires={}", expires);
+        if let Some(max_size) = link.max_size {
+            query.push_str(&format!("&max_size={}", max_size));
+        }
+        let mac = self.mac(&link.method, &link.path, expires, link.max_size);
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}?{}&signature={}", link.path, query, signature)
+    }
+
+    pub fn check(&self, req: &Request, now: SystemTime) -> Signed {
+        self.check_target(
+            &req.method,
+            &req.route,
+            &req.path,
+            req.query.as_deref(),
+            now,
+        )
+    }
+
+    // The same before the body has been read, so that an upload can be held
+    // to its max_size as it arrives
+    pub fn check_head(&self, head: &RequestHead, now: SystemTime) -> Signed {
+        self.check_target(head.method(), head.route(), head.path(), head.query(), now)
+    }
+
+    fn check_target(
+        &self,
+        method: &Method,
+        route: &Route,
+        path: &str,
+        query: Option<&str>,
+        now: SystemTime,
+    ) -> Signed {
+        if *route != Route::Files {
+            return Signed::Unsigned;
+        }
+        let Some(query) = query else {
+            return Signed::Unsigned;
+        };
+        let (mut expires, mut max_size, mut signature) = (None, None, None);
+        let mut extra = false;
+        for param in query.split('&') {
+            let (slot, val) = match param.split_once('=') {
+                Some(("expires", val)) if expires.is_none() => (&mut expires, val),
+                Some(("max_size", val)) if max_size.is_none() => (&mut max_size, val),
+                Some(("signature", val)) if signature.is_none() => (&mut signature, val),
+                _ => {
+                    extra = true;
+                    continue;
+                }
+            };
+            *slot = Some(val);
+        }
+        let Some(signature) = signature else {
+            return Signed::Unsigned;
+        };
+        // Anything else wouldn't be signed, and each can only be given once
+        if extra {
+            return Signed::Invalid;
+        }
+        let expires = expires.and_then(|e| e.parse::<u64>().ok());
+        let max_size = match max_size.map(|m| m.parse::<u64>()) {
+            Some(Ok(max_size)) => Some(max_size),
+            Some(Err(_)) => return Signed::Invalid,
+            None => None,
+        };
+        match (expires, URL_SAFE_NO_PAD.decode(signature)) {
+            (Some(expires), Ok(signature))
+                if expires > unix_secs(now)
+                    && self
+                        .mac(method, path, expires, max_size)
+                        .verify_slice(&signature)
+                        .is_ok() =>
+            {
+                Signed::Valid { max_size }
+            }
+            _ => Signed::Invalid,
+        }
+    }
+
+    fn mac(&self, method: &Method, path: &str, expires: u64, max_size: Option<u64>) -> HmacSha256 {
+        let max_size = max_size.map(|m| m.to_string()).unwrap_or_default();
+        // Any key length works for HMAC
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes any key");
+        mac.update(format!("{}\n{}\n{}\n{}", method, path, expires, max_size).as_bytes());
+        mac
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod signed_url {
+        use crate::http::Request;
+        use crate::signed_url::{Link, Signed, UrlSigner};
+        use std::io::BufReader;
+        use std::time::{Duration, SystemTime};
+
+        #[test]
+        fn signs_and_checks_links() {
+            let signer = UrlSigner::new("0123456789abcdef0123456789abcdef").unwrap();
+            let now = SystemTime::now();
+            let check = |method: &str, target: &str, secs: u64| {
+                let req = format!("{} {} HTTP/1.1\r\n\r\n", method, target);
+                let req = Request::try_from(&mut BufReader::new(req.as_bytes())).unwrap();
+                signer.check(&req, now + Duration::from_secs(secs))
+            };
+
+            let download = signer.sign(&Link::new("/files/report.pdf").unwrap(), now);
+            assert!(download.starts_with("/files/report.pdf?expires="));
+            assert_eq!(Signed::Valid { max_size: None }, check("GET", &download, 0));
+            assert_eq!(Signed::Invalid, check("GET", &download, 3600));
+            assert_eq!(Signed::Invalid, check("POST", &download, 0));
+            assert_eq!(
+                Signed::Invalid,
+                check("GET", &download.replace("report", "other"), 0)
+            );
+            assert_eq!(
+                Signed::Invalid,
+                check("GET", &format!("{}&max_size=1", download), 0)
+            );
+            assert_eq!(Signed::Unsigned, check("GET", "/files/report.pdf", 0));
+            assert_eq!(Signed::Unsigned, check("GET", "/files/report.pdf?v=2", 0));
+            assert_eq!(Signed::Unsigned, check("GET", "/echo/a?signature=x", 0));
+            assert_eq!(
+                Signed::Invalid,
+                check("GET", &format!("{}&v=2", download), 0)
+            );
+
+            let mut upload = Link::new("/files/inbox").unwrap();
+            upload.set_method("POST").unwrap();
+            upload.max_size = Some(1024);
+            let upload = signer.sign(&upload, now);
+            assert!(upload.contains("&max_size=1024&"));
+            assert_eq!(
+                Signed::Valid {
+                    max_size: Some(1024)
+                },
+                check("POST", &upload, 0)
+            );
+            assert_eq!(
+                Signed::Invalid,
+                check("POST", &upload.replace("max_size=1024", "max_size=2048"), 0)
+            );
+            // Signed with another key
+            let other = UrlSigner::new("fedcba9876543210fedcba9876543210").unwrap();
+            assert_eq!(
+                Signed::Invalid,
+                other.check(
+                    &Request::try_from(&mut BufReader::new(
+                        format!("POST {} HTTP/1.1\r\n\r\n", upload).as_bytes()
+                    ))
+                    .unwrap(),
+                    now
+                )
+            );
+
+            assert!(UrlSigner::new("too short").is_none());
+            for bad in ["/echo/a", "/files/", "/files/a/b"] {
+                assert_eq!(None, Link::new(bad), "{}", bad);
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use crate::errors::ConfigError;
+use rustls::crypto::ring::default_provider;
+use rustls::server::WebPkiClientVerifier;
+use rustls::version::{TLS12, TLS13};
+use rustls::{RootCertStore, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// What tls: listeners serve with. The PEM files are only read by build, which
+// runs whenever the config is, so a reload picks up a renewed certificate.
+#[derive(Debug, Clone, Default)]
+pub struct Tls {
+    // The server's certificate first, then any intermediates
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    // Both TLS 1.2 and 1.3 when empty
+    pub versions: Vec<&'static SupportedProtocolVersion>,
+    // Every suite rustls considers safe when empty
+    pub ciphers: Vec<SupportedCipherSuite>,
+    // CAs that client certificates are checked against, clients aren't asked
+    // for one without it
+    pub client_ca: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+    // Set by build, each connection takes whichever is current when it's
+    // accepted
+    pub server_config: Option<Arc<ServerConfig>>,
+}
+
+// Whether clients have to send a certificate when there's a --tls_client_ca
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ClientAuth {
+    #[default]
+    Required,
+    // Asked for, but clients without one can still connect. A certificate
+    // that doesn't check out still fails the handshake.
+    Optional,
+}
+
+impl Tls {
+    pub fn set_client_auth(&mut self, client_auth: &str) -> Option<()> {
+        self.client_auth = match client_auth {
+            "required" => ClientAuth::Required,
+            "optional" => ClientAuth::Optional,
+            _ => return None,
+        };
+        Some(())
+    }
+
+    pub fn set_versions(&mut self, versions: &str) -> Option<()> {
+        self.versions = versions
+            .split(',')
+            .map(|version| match version.trim() {
+                "1.2" => Some(&TLS12),
+                "1.3" => Some(&TLS13),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        Some(())
+    }
+
+    // By their IANA names, e.g. TLS13_AES_256_GCM_SHA384
+    pub fn set_ciphers(&mut self, ciphers: &str) -> Option<()> {
+        let supported = default_provider().cipher_suites;
+        self.ciphers = ciphers
+            .split(',')
+            .map(|name| {
+                supported
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite()) == name.trim())
+                    .copied()
+            })
+            .collect::<Option<_>>()?;
+        Some(())
+    }
+
+    // Reads the certificate and key and checks they go with the versions and
+    // ciphers. Only needed when something listens with TLS.
+    pub fn build(&mut self) -> Result<(), ConfigError> {
+        let cert = self
+            .cert
+            .as_ref()
+            .ok_or_else(|| ConfigError::MissingValue("tls_cert".to_owned()))?;
+        let key = self
+            .key
+            .as_ref()
+            .ok_or_else(|| ConfigError::MissingValue("tls_key".to_owned()))?;
+        let certs = read_certs(cert)?;
+        let key_der = PrivateKeyDer::from_pem_file(key).map_err(|e| bad_file(key, e))?;
+
+        let mut provider = default_provider();
+        if !self.ciphers.is_empty() {
+            provider.cipher_suites.clone_from(&self.ciphers);
+        }
+        let provider = Arc::new(provider);
+        let versions = match self.versions.as_slice() {
+            [] => rustls::ALL_VERSIONS,
+            versions => versions,
+        };
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_protocol_versions(versions)
+            .map_err(|e| ConfigError::Tls(e.to_string()))?;
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(client_ca)? {
+                    roots.add(cert).map_err(|e| bad_file(client_ca, e))?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = match self.client_auth {
+                    ClientAuth::Required => verifier,
+                    ClientAuth::Optional => verifier.allow_unauthenticated(),
+                };
+                let verifier = verifier
+                    .build()
+                    .map_err(|e| ConfigError::Tls(e.to_string()))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut server_config = builder
+            .with_single_cert(certs, key_der)
+            .map_err(|e| ConfigError::Tls(e.to_string()))?;
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        self.server_config = Some(Arc::new(server_config));
+        Ok(())
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, ConfigError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| bad_file(path, e))?;
+    if certs.is_empty() {
+        return Err(bad_file(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn bad_file(path: &Path, e: impl ToString) -> ConfigError {
+    ConfigError::BadConfigFile(path.display().to_string(), e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod tls {
+        use crate::errors::ConfigError;
+        use crate::tls::Tls;
+        use rcgen::generate_simple_self_signed;
+        use std::path::PathBuf;
+
+        // A fresh self-signed certificate and its key, as PEM files
+        fn pem_files(name: &str) -> (PathBuf, PathBuf) {
+            let dir = std::env::temp_dir();
+            let cert = dir.join(format!("http-{}-{}.crt", name, std::process::id()));
+            let key = dir.join(format!("http-{}-{}.key", name, std::process::id()));
+            let generated = generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+            std::fs::write(&cert, generated.cert.pem()).unwrap();
+            std::fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+            (cert, key)
+        }
+
+        #[test]
+        fn builds_from_pem_files() {
+            let (cert, key) = pem_files("tls-build");
+            let mut tls = Tls {
+                cert: Some(cert.clone()),
+                ..Tls::default()
+            };
+            assert_eq!(
+                Err(ConfigError::MissingValue("tls_key".to_owned())),
+                tls.build()
+            );
+            tls.key = Some(key.clone());
+            tls.build().unwrap();
+            assert!(tls.server_config.is_some());
+
+            tls.set_versions("1.3").unwrap();
+            tls.set_ciphers("TLS13_AES_256_GCM_SHA384, TLS13_CHACHA20_POLY1305_SHA256")
+                .unwrap();
+            assert_eq!(2, tls.ciphers.len());
+            tls.build().unwrap();
+            // None of the ciphers are for 1.2
+            tls.set_versions("1.2").unwrap();
+            assert!(matches!(tls.build(), Err(ConfigError::Tls(_))));
+            assert_eq!(None, tls.set_versions("1.1"));
+            assert_eq!(None, tls.set_ciphers("TLS_RSA_WITH_RC4_128_MD5"));
+
+            // Someone else's key
+            let (_, other) = pem_files("tls-other");
+            let mut tls = Tls {
+                cert: Some(cert.clone()),
+                key: Some(other.clone()),
+                ..Tls::default()
+            };
+            assert!(matches!(tls.build(), Err(ConfigError::Tls(_))));
+            tls.key = Some(cert.clone());
+            assert!(matches!(tls.build(), Err(ConfigError::BadConfigFile(..))));
+            for path in [cert, key, other] {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
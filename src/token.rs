@@ -0,0 +1,155 @@
+use crate::access::served_method;
+use crate::http::{Method, Request};
+use sha2::{Digest, Sha256};
+use std::fmt::{Debug, Formatter};
+use subtle::ConstantTimeEq;
+
+// Anything shorter could be guessed
+const MIN_SECRET_LEN: usize = 16;
+
+// A static bearer token for machines, only good for some methods and paths.
+// The name stands in for a user in auth rules, logs and rate limits.
+#[derive(Clone, PartialEq)]
+pub struct Token {
+    pub name: String,
+    // Only a hash of the secret is kept, so it can be compared without
+    // giving away its length
+    digest: [u8; 32],
+    // Every method when empty
+    pub methods: Vec<Method>,
+    // Globs, where * matches within a path segment and ** across them
+    pub paths: Vec<String>,
+}
+
+// The secret stays out of the logs
+impl Debug for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Token")
+            .field("name", &self.name)
+            .field("methods", &self.methods)
+            .field("paths", &self.paths)
+            .finish()
+    }
+}
+
+impl Token {
+    // From NAME:secret=SECRET,method=M,path=GLOB,... where method and path
+    // can be repeated. At least one path is needed.
+    pub fn parse(spec: &str) -> Option<Token> {
+        let (name, options) = spec.split_once(':')?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return None;
+        }
+        let (mut secret, mut methods, mut paths) = (None, Vec::new(), Vec::new());
+        for option in options.split(',') {
+            let (option, val) = option.split_once('=')?;
+            let val = val.trim();
+            match option.trim() {
+                "secret" if val.len() >= MIN_SECRET_LEN => secret = Some(val),
+                "method" => methods.push(served_method(val)?),
+                "path" if val.starts_with('/') => paths.push(val.to_owned()),
+                _ => return None,
+            }
+        }
+        if paths.is_empty() {
+            return None;
+        }
+        Some(Token {
+            name: name.to_owned(),
+            digest: Sha256::digest(secret?).into(),
+            methods,
+            paths,
+        })
+    }
+
+    // The token with this secret, if any. Every token is compared, so how long
+    // it takes doesn't say which one matched or how close the guess was.
+    pub fn find<'a>(tokens: &'a [Token], secret: &str) -> Option<&'a Token> {
+        let digest = Sha256::digest(secret);
+        let mut found = None;
+        for token in tokens {
+            if bool::from(token.digest.ct_eq(digest.as_slice())) {
+                found = Some(token);
+            }
+        }
+        found
+    }
+
+    pub fn permits(&self, req: &Request) -> bool {
+        (self.methods.is_empty() || self.methods.contains(&req.method))
+            && self
+                .paths
+                .iter()
+                .any(|glob| glob_matches(glob.as_bytes(), req.path.as_bytes()))
+    }
+}
+
+fn glob_matches(glob: &[u8], path: &[u8]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_matches(rest, &path[i..])),
+        // Stops at the end of the segment
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != b'/')
+            .any(|i| glob_matches(rest, &path[i..])),
+        [c, rest @ ..] => path.first() == Some(c) && glob_matches(rest, &path[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod token {
+        use crate::http::Request;
+        use crate::token::{glob_matches, Token};
+        use std::io::BufReader;
+
+        #[test]
+        fn matches_globs() {
+            let matches = |glob: &str, path: &str| glob_matches(glob.as_bytes(), path.as_bytes());
+            assert!(matches("/files/build-*", "/files/build-42.tar"));
+            assert!(matches("/files/build-*", "/files/build-"));
+            assert!(!matches("/files/build-*", "/files/build-1/../secret"));
+            assert!(!matches("/files/build-*", "/files/other"));
+            assert!(matches("/files/**", "/files/a/b/c"));
+            assert!(matches("/*/a", "/files/a"));
+            assert!(!matches("/*/a", "/files/b/a"));
+            assert!(matches("/files/*.log", "/files/ci.log"));
+            assert!(!matches("/files/a", "/files/ab"));
+        }
+
+        #[test]
+        fn scopes_tokens() {
+            let tokens: Vec<Token> = [
+                "build:secret=0123456789abcdef,method=POST,path=/files/build-*",
+                "reader:secret=fedcba9876543210,method=GET,path=/files/**,path=/echo/*",
+            ]
+            .iter()
+            .map(|spec| Token::parse(spec).unwrap())
+            .collect();
+            let req = |req: &str| Request::try_from(&mut BufReader::new(req.as_bytes())).unwrap();
+
+            let build = Token::find(&tokens, "0123456789abcdef").unwrap();
+            assert_eq!("build", build.name);
+            assert!(build.permits(&req("POST /files/build-1 HTTP/1.1\r\n\r\n")));
+            assert!(!build.permits(&req("GET /files/build-1 HTTP/1.1\r\n\r\n")));
+            assert!(!build.permits(&req("POST /files/release HTTP/1.1\r\n\r\n")));
+            let reader = Token::find(&tokens, "fedcba9876543210").unwrap();
+            assert!(reader.permits(&req("GET /files/a/b HTTP/1.1\r\n\r\n")));
+            assert!(!reader.permits(&req("POST /files/a HTTP/1.1\r\n\r\n")));
+            assert_eq!(None, Token::find(&tokens, "0123456789abcdeF"));
+            assert_eq!(None, Token::find(&tokens, ""));
+
+            for bad in [
+                "short:secret=tooshort,path=/",
+                "nopath:secret=0123456789abcdef",
+                "nosecret:path=/files/*",
+                ":secret=0123456789abcdef,path=/",
+                "put:secret=0123456789abcdef,method=PUT,path=/",
+            ] {
+                assert_eq!(None, Token::parse(bad), "{}", bad);
+            }
+        }
+    }
+}
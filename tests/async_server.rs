@@ -0,0 +1,129 @@
+#![cfg(feature = "tokio")]
+
+use http_server_rust::{AsyncServer, Command, Config};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+fn config() -> Config {
+    let dir = format!("/http-async-{}", std::process::id());
+    let Ok(Command::Run(config)) = Config::default().parse([format!("--target_dir={}", dir)])
+    else {
+        panic!("expected a config");
+    };
+    config
+}
+
+// Reads one response with a Content-Length off a kept-alive connection
+async fn response(stream: &mut TcpStream) -> String {
+    let mut resp = Vec::new();
+    let mut byte = [0];
+    while !resp.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).await.unwrap();
+        resp.push(byte[0]);
+    }
+    let head = String::from_utf8(resp.clone()).unwrap();
+    let len: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await.unwrap();
+    resp.extend(body);
+    String::from_utf8(resp).unwrap()
+}
+
+#[tokio::test]
+async fn serves_a_tokio_listener_until_signalled() {
+    let server = AsyncServer::new(&config());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (stop, stopped) = oneshot::channel::<()>();
+    // Spawned rather than awaited here, which also checks it is Send
+    let running = tokio::spawn({
+        let server = server.clone();
+        async move {
+            server
+                .run_until(listener, async {
+                    let _ = stopped.await;
+                })
+                .await
+        }
+    });
+
+    // Several requests on one connection, the file ones on the blocking pool
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(b"POST /files/async HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello")
+        .await
+        .unwrap();
+    assert!(response(&mut stream)
+        .await
+        .starts_with("HTTP/1.1 201 Created"));
+    stream
+        .write_all(b"GET /files/async HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    assert!(response(&mut stream).await.ends_with("\r\n\r\nhello"));
+    stream
+        .write_all(b"GET /echo/inline HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    assert!(response(&mut stream).await.ends_with("\r\n\r\ninline"));
+
+    // Left idle, so it is closed as soon as the server stops
+    stop.send(()).unwrap();
+    running.await.unwrap().unwrap();
+    assert!(server.is_shutdown());
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(0, server.stats().dropped());
+}
+
+#[tokio::test]
+async fn serves_a_single_stream() {
+    let server = AsyncServer::new(&config());
+    let (client, conn) = tokio::io::duplex(1024);
+    let serving = tokio::spawn({
+        let server = server.clone();
+        async move { server.serve(conn).await }
+    });
+
+    let (mut read, mut write) = tokio::io::split(client);
+    write
+        .write_all(b"GET /user-agent HTTP/1.1\r\nUser-Agent: tokio\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = String::new();
+    read.read_to_string(&mut resp).await.unwrap();
+    assert!(resp.contains("Connection: close\r\n"));
+    assert!(resp.ends_with("\r\n\r\ntokio"));
+    serving.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn times_out_a_slow_head() {
+    let Ok(Command::Run(config)) = config().parse(["--header_timeout=1"]) else {
+        panic!("expected a config");
+    };
+    let server = AsyncServer::new(&config);
+    let (client, conn) = tokio::io::duplex(1024);
+    let serving = tokio::spawn({
+        let server = server.clone();
+        async move { server.serve(conn).await }
+    });
+
+    let (mut read, mut write) = tokio::io::split(client);
+    write
+        .write_all(b"GET /echo/slow HTTP/1.1\r\nHost: ")
+        .await
+        .unwrap();
+    let mut resp = String::new();
+    read.read_to_string(&mut resp).await.unwrap();
+    assert!(resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    assert!(serving.await.unwrap().is_err());
+    assert_eq!(1, server.stats().timeouts());
+}
@@ -0,0 +1,336 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn wait_for(path: &Path) -> String {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if let Ok(s) = std::fs::read_to_string(path) {
+            return s;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("{} never showed up", path.display());
+}
+
+// The processes started by pid, found through /proc
+fn children_of(pid: u32) -> Vec<u32> {
+    let mut children = Vec::new();
+    for entry in std::fs::read_dir("/proc").unwrap().flatten() {
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        // The command name is in parens and can contain spaces
+        let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+        if fields[0] != "Z" && fields[1] == pid.to_string() {
+            children.push(stat.split(' ').next().unwrap().parse().unwrap());
+        }
+    }
+    children
+}
+
+fn is_running(pid: u32) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => !stat[stat.rfind(')').unwrap() + 2..].starts_with('Z'),
+        Err(_) => false,
+    }
+}
+
+fn wait_until(what: &str, f: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if f() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("timed out waiting for {}", what);
+}
+
+fn terminate(mut child: Child) {
+    // SAFETY: plain kill(2) on a pid we own
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    assert!(child.wait().unwrap().success());
+}
+
+fn get<S: Read + Write>(mut stream: S, path: &str) -> String {
+    write!(stream, "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path).unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+// Starts the binary the way systemd would, with the listeners from fd 3 on
+fn spawn_activated(listeners: &[RawFd], args: &[String]) -> Child {
+    let fds = listeners.to_vec();
+    // Allocated up front, nothing between fork and exec should
+    let mut moved = Vec::with_capacity(fds.len());
+    let mut command = Command::new("sh");
+    // LISTEN_PID has to be the server's own pid, exec keeps the shell's
+    command
+        .arg("-c")
+        .arg(r#"LISTEN_PID=$$ exec "$0" "$@""#)
+        .arg(env!("CARGO_BIN_EXE_http-server-rust"))
+        .args(args)
+        .env("LISTEN_FDS", fds.len().to_string());
+    // SAFETY: only fcntl(2) and dup2(2) between fork and exec, both of which
+    // are async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            // Moved out of the way first so none of them gets overwritten
+            // before it has been copied into place
+            for fd in &fds {
+                let high = libc::fcntl(*fd, libc::F_DUPFD, 100);
+                if high == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                moved.push(high);
+            }
+            for (i, fd) in moved.iter().enumerate() {
+                if libc::dup2(*fd, 3 + i as RawFd) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        })
+    };
+    command.spawn().unwrap()
+}
+
+#[test]
+fn writes_the_bound_address_to_the_ready_file() {
+    let ready = std::env::temp_dir().join(format!("http-server-ready-{}", std::process::id()));
+    let _ = std::fs::remove_file(&ready);
+    let child = Command::new(env!("CARGO_BIN_EXE_http-server-rust"))
+        .arg("--address=127.0.0.1:0")
+        .arg(format!("--ready_file={}", ready.display()))
+        .spawn()
+        .unwrap();
+
+    let address = wait_for(&ready);
+    let stream = TcpStream::connect(address.trim()).unwrap();
+    assert!(get(stream, "/echo/ready").ends_with("\r\n\r\nready"));
+
+    terminate(child);
+    let _ = std::fs::remove_file(&ready);
+}
+
+#[test]
+fn serves_listeners_passed_in_by_the_parent() {
+    let id = std::process::id();
+    let ready = std::env::temp_dir().join(format!("http-server-activated-{}", id));
+    let socket = std::env::temp_dir().join(format!("http-server-activated-{}.sock", id));
+    let _ = std::fs::remove_file(&ready);
+    let _ = std::fs::remove_file(&socket);
+    let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+    let unix = UnixListener::bind(&socket).unwrap();
+
+    // Left alone unless asked for
+    let child = spawn_activated(
+        &[tcp.as_raw_fd(), unix.as_raw_fd()],
+        &[
+            format!("--ready_file={}", ready.display()),
+            "--listen=127.0.0.1:0".to_owned(),
+        ],
+    );
+    let addresses = wait_for(&ready);
+    assert_ne!(format!("{}\n", tcp.local_addr().unwrap()), addresses);
+    assert!(!addresses.contains("unix:"));
+    terminate(child);
+    let _ = std::fs::remove_file(&ready);
+
+    let child = spawn_activated(
+        &[tcp.as_raw_fd(), unix.as_raw_fd()],
+        &[
+            format!("--ready_file={}", ready.display()),
+            "--socket_activation=true".to_owned(),
+        ],
+    );
+
+    let addresses = wait_for(&ready);
+    let expected = format!("{}\nunix:{}\n", tcp.local_addr().unwrap(), socket.display());
+    assert_eq!(expected, addresses);
+    let tcp_stream = TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+    assert!(get(tcp_stream, "/echo/tcp").ends_with("\r\n\r\ntcp"));
+    let unix_stream = UnixStream::connect(&socket).unwrap();
+    assert!(get(unix_stream, "/echo/unix").ends_with("\r\n\r\nunix"));
+
+    terminate(child);
+    // The socket file belongs to whoever created it
+    assert!(socket.exists());
+    let _ = std::fs::remove_file(&ready);
+    let _ = std::fs::remove_file(&socket);
+}
+
+// Half a request, which the process that accepted it has to finish before it
+// can exit
+fn half_a_request(address: &str, echo: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(stream, "GET /echo/{} HTTP/1.1\r\n", echo).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    stream
+}
+
+fn finish(mut stream: TcpStream) -> String {
+    stream.write_all(b"\r\n").unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+#[test]
+fn hands_the_listeners_to_a_new_process() {
+    let id = std::process::id();
+    let ready = std::env::temp_dir().join(format!("http-server-upgrade-ready-{}", id));
+    let socket = std::env::temp_dir().join(format!("http-server-upgrade-{}.sock", id));
+    let _ = std::fs::remove_file(&ready);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_http-server-rust"))
+        .arg("--address=127.0.0.1:0")
+        .arg(format!("--listen=unix:{}", socket.display()))
+        .arg(format!("--ready_file={}", ready.display()))
+        .arg("--admin=true")
+        .arg("--threads=4")
+        .spawn()
+        .unwrap();
+    let address = wait_for(&ready).lines().next().unwrap().to_owned();
+
+    let in_flight = half_a_request(&address, "old");
+
+    std::fs::remove_file(&ready).unwrap();
+    // SAFETY: plain kill(2) on a pid we own
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGUSR2) };
+    wait_for(&ready);
+    let upgraded = children_of(child.id());
+    assert_eq!(1, upgraded.len());
+    // Each listener came across once, at 3 and 4, and nowhere else
+    let fds: Vec<String> = std::fs::read_dir(format!("/proc/{}/fd", upgraded[0]))
+        .unwrap()
+        .map(|fd| std::fs::read_link(fd.unwrap().path()).unwrap())
+        .map(|link| link.display().to_string())
+        .collect();
+    for fd in [3, 4] {
+        let link = std::fs::read_link(format!("/proc/{}/fd/{}", upgraded[0], fd)).unwrap();
+        let link = link.display().to_string();
+        assert!(link.starts_with("socket:"), "{}", link);
+        assert_eq!(1, fds.iter().filter(|l| **l == link).count(), "{:?}", fds);
+    }
+
+    // Both the TCP and the Unix listener came across
+    let stream = TcpStream::connect(&address).unwrap();
+    assert!(get(stream, "/echo/new").ends_with("\r\n\r\nnew"));
+    let stream = UnixStream::connect(&socket).unwrap();
+    assert!(get(stream, "/echo/unix").ends_with("\r\n\r\nunix"));
+
+    assert!(finish(in_flight).ends_with("\r\n\r\nold"));
+    assert!(child.wait().unwrap().success());
+
+    // And again through the admin endpoint
+    let in_flight = half_a_request(&address, "first");
+    std::fs::remove_file(&ready).unwrap();
+    let mut stream = TcpStream::connect(&address).unwrap();
+    write!(
+        stream,
+        "POST /admin/upgrade HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 202 Accepted\r\n"));
+    wait_for(&ready);
+    let latest = children_of(upgraded[0]);
+    assert_eq!(1, latest.len());
+    assert!(finish(in_flight).ends_with("\r\n\r\nfirst"));
+    wait_until("the first upgrade to exit", || !is_running(upgraded[0]));
+    let stream = TcpStream::connect(&address).unwrap();
+    assert!(get(stream, "/echo/latest").ends_with("\r\n\r\nlatest"));
+
+    // SAFETY: kill(2) on the process the test ended up with
+    unsafe { libc::kill(latest[0] as libc::pid_t, libc::SIGTERM) };
+    wait_until("the last upgrade to exit", || !is_running(latest[0]));
+    let _ = std::fs::remove_file(&ready);
+    let _ = std::fs::remove_file(&socket);
+}
+
+#[test]
+fn stops_without_draining_on_a_second_signal() {
+    let ready = std::env::temp_dir().join(format!("http-server-stop-ready-{}", std::process::id()));
+    let _ = std::fs::remove_file(&ready);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_http-server-rust"))
+        .arg("--address=127.0.0.1:0")
+        .arg(format!("--ready_file={}", ready.display()))
+        .arg("--shutdown_timeout=60")
+        .arg("--header_timeout=0")
+        .spawn()
+        .unwrap();
+    let address = wait_for(&ready);
+    let _in_flight = half_a_request(address.trim(), "never");
+
+    // SAFETY: plain kill(2) on a pid we own
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    // Draining, and would be for another minute
+    thread::sleep(Duration::from_millis(200));
+    assert!(child.try_wait().unwrap().is_none());
+    let started = Instant::now();
+    // SAFETY: as above
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let status = child.wait().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(Some(libc::SIGINT), status.signal());
+    let _ = std::fs::remove_file(&ready);
+}
+
+#[test]
+fn reloads_the_config_file_on_sighup() {
+    let id = std::process::id();
+    let ready = std::env::temp_dir().join(format!("http-server-reload-ready-{}", id));
+    let config = std::env::temp_dir().join(format!("http-server-reload-{}.conf", id));
+    let _ = std::fs::remove_file(&ready);
+    for name in ["before", "after"] {
+        let dir = format!("/tmp/http-server-reload-{}-{}", id, name);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(format!("{}/file", dir), name).unwrap();
+    }
+    let serve = |name: &str| {
+        let target_dir = format!("/http-server-reload-{}-{}", id, name);
+        std::fs::write(
+            &config,
+            format!("target_dir = {}\nthreads = 2\n", target_dir),
+        )
+        .unwrap();
+    };
+    serve("before");
+    let child = Command::new(env!("CARGO_BIN_EXE_http-server-rust"))
+        .arg("--address=127.0.0.1:0")
+        .arg(format!("--config={}", config.display()))
+        .arg(format!("--ready_file={}", ready.display()))
+        .spawn()
+        .unwrap();
+    let address = wait_for(&ready).trim().to_owned();
+    let fetch = || get(TcpStream::connect(&address).unwrap(), "/files/file");
+    assert!(fetch().ends_with("\r\n\r\nbefore"));
+
+    serve("after");
+    // SAFETY: plain kill(2) on a pid we own
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGHUP) };
+    wait_until("the reload", || fetch().ends_with("\r\n\r\nafter"));
+
+    // A broken config is ignored
+    std::fs::write(&config, "threads = none\n").unwrap();
+    // SAFETY: as above
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGHUP) };
+    thread::sleep(Duration::from_millis(100));
+    assert!(fetch().ends_with("\r\n\r\nafter"));
+
+    terminate(child);
+    let _ = std::fs::remove_file(&ready);
+    let _ = std::fs::remove_file(&config);
+}
@@ -0,0 +1,991 @@
+use http_server_rust::{Command, Config, Link, ListenAddr, Server, UrlSigner};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+fn config() -> Config {
+    Config {
+        listen: vec!["127.0.0.1:0".to_owned()],
+        threads: 2,
+        ..Config::default()
+    }
+}
+
+fn get(address: SocketAddr, path: &str) -> String {
+    request(TcpStream::connect(address).unwrap(), path)
+}
+
+// POSTs a head promising a big body and nothing else, for requests that should
+// be turned away before the server waits for the body
+fn post_head(address: SocketAddr, target: &str, headers: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nContent-Length: 1000000\r\n{}\r\n",
+        target, headers
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+fn request<S: Read + Write>(mut stream: S, path: &str) -> String {
+    write!(stream, "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path).unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+#[test]
+fn stops_with_a_shutdown_handle() {
+    let server = Server::try_new(&config()).unwrap();
+    let address = server.local_addr().unwrap();
+    assert_ne!(0, address.port());
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+
+    assert!(get(address, "/echo/hello").ends_with("\r\n\r\nhello"));
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+    assert!(handle.is_shutdown());
+    assert!(TcpStream::connect(address).is_err());
+}
+
+#[test]
+fn serves_every_listener() {
+    let server = Server::try_new(&Config {
+        listen: vec!["127.0.0.1:0".to_owned(), "127.0.0.1:0".to_owned()],
+        ..config()
+    })
+    .unwrap();
+    let addresses = server.local_addrs().unwrap();
+    assert_eq!(2, addresses.len());
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+
+    for address in addresses {
+        let ListenAddr::Tcp(address) = address else {
+            panic!("expected a TCP listener");
+        };
+        assert!(get(address, "/echo/both").ends_with("\r\n\r\nboth"));
+    }
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn serves_a_unix_socket() {
+    let path = std::env::temp_dir().join(format!("http-server-{}.sock", std::process::id()));
+    let server = Server::try_new(&Config {
+        listen: vec!["127.0.0.1:0".to_owned(), format!("unix:{}", path.display())],
+        ..config()
+    })
+    .unwrap();
+    assert_eq!(
+        ListenAddr::Unix(path.clone()),
+        server.local_addrs().unwrap()[1]
+    );
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+
+    let stream = UnixStream::connect(&path).unwrap();
+    assert!(request(stream, "/echo/unix").ends_with("\r\n\r\nunix"));
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+    // The socket file goes with the server
+    assert!(!path.exists());
+}
+
+#[test]
+fn runs_several_servers_until_signalled() {
+    let (stop, signal) = mpsc::channel::<()>();
+    let signal = Arc::new(Mutex::new(signal));
+    let mut addresses = Vec::new();
+    let mut running = Vec::new();
+    for _ in 0..8 {
+        let server = Server::try_new(&config()).unwrap();
+        addresses.push(server.local_addr().unwrap());
+        let signal = Arc::clone(&signal);
+        running.push(thread::spawn(move || {
+            server.run_until(move || {
+                let _ = signal.lock().unwrap().recv();
+            })
+        }));
+    }
+
+    for address in &addresses {
+        assert!(get(*address, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    for _ in &addresses {
+        stop.send(()).unwrap();
+    }
+    for r in running {
+        r.join().unwrap().unwrap();
+    }
+}
+
+#[test]
+fn hides_the_admin_endpoints_by_default() {
+    let server = Server::try_new(&config()).unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "POST /admin/upgrade HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+// A config serving a fresh directory under /tmp holding a file called name
+fn serving(name: &str, contents: &str) -> Config {
+    let dir = format!("/http-reload-{}-{}", std::process::id(), contents);
+    let Ok(Command::Run(config)) = config().parse([format!("--target_dir={}", dir)]) else {
+        panic!("expected a config");
+    };
+    std::fs::write(format!("/tmp{}/{}", dir, name), contents).unwrap();
+    config
+}
+
+#[test]
+fn reloads_for_new_connections_only() {
+    let server = Arc::new(Server::try_new(&serving("file", "before")).unwrap());
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn({
+        let server = Arc::clone(&server);
+        move || server.start()
+    });
+
+    let mut kept = TcpStream::connect(address).unwrap();
+    let keep_alive = "GET /files/file HTTP/1.1\r\n\r\n";
+    kept.write_all(keep_alive.as_bytes()).unwrap();
+    let mut resp = [0; 512];
+    let n = kept.read(&mut resp).unwrap();
+    assert!(String::from_utf8_lossy(&resp[..n]).ends_with("before"));
+
+    server.reload(serving("file", "after")).unwrap();
+    assert!(get(address, "/files/file").ends_with("\r\n\r\nafter"));
+    // Still on the router it was accepted with
+    kept.write_all(keep_alive.as_bytes()).unwrap();
+    let n = kept.read(&mut resp).unwrap();
+    assert!(String::from_utf8_lossy(&resp[..n]).ends_with("before"));
+
+    drop(kept);
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn turns_away_requests_over_the_limits() {
+    let Ok(Command::Run(config)) = config().parse([
+        "--max_body=4",
+        "--max_request_line=64",
+        "--mount_limits=/files:max_body=1024",
+    ]) else {
+        panic!("expected a config");
+    };
+    let server = Server::try_new(&config).unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+
+    let send = |req: &str| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(req.as_bytes()).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    };
+    let upload = |path: &str| {
+        format!(
+            "POST {} HTTP/1.1\r\nContent-Length: 100\r\nConnection: close\r\n\r\n{}",
+            path,
+            "a".repeat(100)
+        )
+    };
+    let limited = format!("limited-{}", std::process::id());
+    assert!(send(&upload(&format!("/files/{}", limited))).starts_with("HTTP/1.1 201"));
+    std::fs::remove_file(format!("/tmp/{}", limited)).unwrap();
+    // Answered before the body is read, and the connection isn't reused
+    let resp = send(&upload("/echo/abc"));
+    assert!(resp.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    assert!(resp.contains("Connection: close\r\n"));
+    let resp = send(&format!("GET /echo/{} HTTP/1.1\r\n\r\n", "a".repeat(64)));
+    assert!(resp.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+    let headers = "X-Padding: a\r\n".repeat(200);
+    let resp = send(&format!("GET / HTTP/1.1\r\n{}\r\n", headers));
+    assert!(resp.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn never_reuses_a_connection_after_a_framing_error() {
+    let server = Server::try_new(&config()).unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+
+    let send = |req: &[u8]| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(req).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    };
+    // A front end going by the Content-Length would see one request, going
+    // by the chunked body would leave a second one behind
+    let resp = send(
+        b"POST /echo/first HTTP/1.1\r\nContent-Length: 36\r\nTransfer-Encoding: chunked\r\n\r\n\
+          0\r\n\r\nGET /echo/smuggled HTTP/1.1\r\n\r\n",
+    );
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(resp.contains("Connection: close\r\n"));
+    assert!(!resp.contains("smuggled"));
+    // Chunked bodies themselves are fine, and the connection carries on
+    let resp = send(
+        b"GET /echo/first HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+          3\r\nabc\r\n0\r\n\r\nGET /echo/second HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(resp.contains("\r\n\r\nfirst"));
+    assert!(resp.ends_with("\r\n\r\nsecond"));
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn rate_limits_clients() {
+    let Ok(Command::Run(config)) = config().parse([
+        "--rate_limit=/echo:rate=0.1,burst=2",
+        "--max_connections_per_ip=2",
+    ]) else {
+        panic!("expected a config");
+    };
+    let server = Server::try_new(&config).unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+
+    let first = get(address, "/echo/a");
+    assert!(first.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(first.contains("RateLimit-Limit: 2\r\nRateLimit-Remaining: 1\r\n"));
+    assert!(get(address, "/echo/a").starts_with("HTTP/1.1 200 OK\r\n"));
+    let limited = get(address, "/echo/a");
+    assert!(limited.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+    assert!(limited.contains("Retry-After: 10\r\n"));
+    assert!(limited.contains("RateLimit-Remaining: 0\r\n"));
+    // Without waiting for a body it isn't going to use
+    let limited = post_head(address, "/echo/a", "");
+    assert!(limited.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+    assert!(limited.contains("Connection: close\r\n"));
+    // Other paths aren't limited
+    assert!(get(address, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // Two connections held open, the third is turned away at accept
+    let held: Vec<TcpStream> = (0..2)
+        .map(|_| TcpStream::connect(address).unwrap())
+        .collect();
+    // Answered without reading anything, so nothing is sent that could
+    // turn the close into a reset
+    let mut third = String::new();
+    TcpStream::connect(address)
+        .unwrap()
+        .read_to_string(&mut third)
+        .unwrap();
+    assert!(third.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+    drop(held);
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn checks_client_addresses() {
+    let Ok(Command::Run(allowed)) = config().parse(["--access=/echo:allow=10.0.0.0/8"]) else {
+        panic!("expected a config");
+    };
+    let server = Arc::new(Server::try_new(&allowed).unwrap());
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn({
+        let server = Arc::clone(&server);
+        move || server.start()
+    });
+
+    assert!(get(address, "/echo/a").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    assert!(get(address, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+    // Without waiting for a body it isn't going to use
+    let resp = post_head(address, "/echo/a", "");
+    assert!(resp.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    assert!(resp.contains("Connection: close\r\n"));
+
+    // Denied connections are closed without a response, again without
+    // sending anything that could turn the close into a reset
+    let Ok(Command::Run(denied)) = config().parse(["--deny_connections=127.0.0.0/8,::1"]) else {
+        panic!("expected a config");
+    };
+    server.reload(denied).unwrap();
+    let mut resp = String::new();
+    TcpStream::connect(address)
+        .unwrap()
+        .read_to_string(&mut resp)
+        .unwrap();
+    assert_eq!("", resp);
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn matches_rules_on_the_canonical_path() {
+    let Ok(Command::Run(config)) = config().parse(["--access=/files:method=POST,allow=10.0.0.0/8"])
+    else {
+        panic!("expected a config");
+    };
+    let server = Server::try_new(&config).unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+
+    let name = format!("http-canonical-{}", std::process::id());
+    let post = |target: &str| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+            target
+        )
+        .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    };
+    for target in [
+        format!("/files/{}", name),
+        format!("//files/{}", name),
+        format!("/files//{}", name),
+    ] {
+        assert!(
+            post(&target).starts_with("HTTP/1.1 403 Forbidden\r\n"),
+            "{}",
+            target
+        );
+    }
+    assert!(post(&format!("/echo/../files/{}", name)).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(!std::path::Path::new("/tmp").join(&name).exists());
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn asks_clients_to_log_in() {
+    let users = std::env::temp_dir().join(format!("http-users-{}", std::process::id()));
+    // erin:test and frank:test
+    std::fs::write(
+        &users,
+        "erin:{SHA}qUqP5cyxm6YcTAhz05Hph5gvu9M=\n\
+         frank:$5$saltstring$uzdVN3XcAOYKRlFzn8F5/iMpo99aMD8sXQTYY9P10Z8\n",
+    )
+    .unwrap();
+    let Ok(Command::Run(config)) = config().parse([
+        format!("--htpasswd={}", users.display()),
+        "--auth=/echo:realm=Echoes,user=erin".to_owned(),
+        "--auth=/files".to_owned(),
+    ]) else {
+        panic!("expected a config");
+    };
+    let server = Server::try_new(&config).unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+    let login = |credentials: &str| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "GET /echo/a HTTP/1.1\r\nAuthorization: Basic {}\r\nConnection: close\r\n\r\n",
+            credentials
+        )
+        .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    };
+
+    let challenged = get(address, "/echo/a");
+    assert!(challenged.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    assert!(challenged.contains("WWW-Authenticate: Basic realm=\"Echoes\", charset=\"UTF-8\"\r\n"));
+    assert!(login("ZXJpbjp0ZXN0").starts_with("HTTP/1.1 200 OK\r\n"));
+    // erin:wrong
+    assert!(login("ZXJpbjp3cm9uZw==").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    // frank:test, who has a password but isn't let in here
+    assert!(login("ZnJhbms6dGVzdA==").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    assert!(get(address, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+    // However the path is spelt
+    for path in ["/files/f", "//files/f", "/files//f", "//echo/a"] {
+        assert!(
+            get(address, path).starts_with("HTTP/1.1 401 Unauthorized\r\n"),
+            "{}",
+            path
+        );
+    }
+    // Decided from the head, without waiting for a body that never comes
+    let refused = post_head(address, "/files/f", "");
+    assert!(refused.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    assert!(refused.contains("Connection: close\r\n"));
+    let refused = post_head(
+        address,
+        "/echo/a",
+        "Authorization: Basic ZnJhbms6dGVzdA==\r\n",
+    );
+    assert!(refused.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&users);
+}
+
+#[test]
+fn holds_tokens_to_their_scope() {
+    let dir = format!("/http-tokens-{}", std::process::id());
+    let Ok(Command::Run(config)) = config().parse([
+        format!("--target_dir={}", dir),
+        "--token=build:secret=0123456789abcdef,method=POST,path=/files/build-*".to_owned(),
+        "--auth=/files:method=POST".to_owned(),
+    ]) else {
+        panic!("expected a config");
+    };
+    let server = Server::try_new(&config).unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+    let send = |method: &str, path: &str, token: &str| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: 2\r\n\
+             Connection: close\r\n\r\nok",
+            method, path, token
+        )
+        .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    };
+
+    let created = send("POST", "/files/build-1", "0123456789abcdef");
+    assert!(
+        created.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{}",
+        created
+    );
+    assert_eq!(
+        "ok",
+        std::fs::read_to_string(format!("/tmp{}/build-1", dir)).unwrap()
+    );
+    assert!(send("POST", "/files/release", "0123456789abcdef")
+        .starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    assert!(
+        send("GET", "/files/build-1", "0123456789abcdef").starts_with("HTTP/1.1 403 Forbidden\r\n")
+    );
+    let invalid = send("POST", "/files/build-2", "0123456789abcdeg");
+    assert!(invalid.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    assert!(invalid.contains("WWW-Authenticate: Bearer error=\"invalid_token\"\r\n"));
+    assert!(std::fs::metadata(format!("/tmp{}/build-2", dir)).is_err());
+    // Checked from the head, without waiting for a body that never comes
+    let bearer = |token: &str| format!("Authorization: Bearer {}\r\n", token);
+    assert!(
+        post_head(address, "/files/release", &bearer("0123456789abcdef"))
+            .starts_with("HTTP/1.1 403 Forbidden\r\n")
+    );
+    assert!(
+        post_head(address, "/files/build-3", &bearer("0123456789abcdeg"))
+            .starts_with("HTTP/1.1 401 Unauthorized\r\n")
+    );
+    assert!(post_head(address, "/files/build-3", "").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    // Reading needs no login at all
+    assert!(get(address, "/files/build-1").ends_with("\r\n\r\nok"));
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn honours_signed_links() {
+    let dir = format!("/http-signed-{}", std::process::id());
+    let key = "0123456789abcdef0123456789abcdef";
+    let Ok(Command::Run(config)) = config().parse([
+        format!("--target_dir={}", dir),
+        format!("--signing_key={}", key),
+        "--auth=/files".to_owned(),
+    ]) else {
+        panic!("expected a config");
+    };
+    let signer = UrlSigner::new(key).unwrap();
+    let server = Server::try_new(&config).unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+    let send = |method: &str, target: &str, body: &str| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            target,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    };
+
+    let mut link = Link::new("/files/inbox").unwrap();
+    link.set_method("POST").unwrap();
+    link.max_size = Some(4);
+    let upload = signer.sign(&link, SystemTime::now());
+    assert!(send("POST", &upload, "too big").starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    // Turned away on what it says it will send, without waiting for any of it
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n",
+        upload
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    // And a chunked one as soon as it runs over
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\ndef\r\n",
+        upload
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    assert!(send("POST", &upload, "ok").starts_with("HTTP/1.1 201 Created\r\n"));
+    assert_eq!(
+        "ok",
+        std::fs::read_to_string(format!("/tmp{}/inbox", dir)).unwrap()
+    );
+
+    let download = signer.sign(&Link::new("/files/inbox").unwrap(), SystemTime::now());
+    assert!(send("GET", &download, "").ends_with("\r\n\r\nok"));
+    // Tampered with, expired or unsigned
+    let tampered = download.replace("inbox", "other");
+    assert!(send("GET", &tampered, "").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    let expired = signer.sign(
+        &Link::new("/files/inbox").unwrap(),
+        SystemTime::now() - Duration::from_secs(7200),
+    );
+    assert!(send("GET", &expired, "").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    assert!(send("GET", "/files/inbox", "").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn gives_up_idle_connections_when_workers_are_needed() {
+    let server = Server::try_new(&Config {
+        threads: 1,
+        ..config()
+    })
+    .unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+
+    // Kept alive, and holding the only worker while it waits
+    let mut idle = TcpStream::connect(address).unwrap();
+    idle.write_all(b"GET /echo/idle HTTP/1.1\r\n\r\n").unwrap();
+    let mut resp = [0; 512];
+    let n = idle.read(&mut resp).unwrap();
+    assert!(String::from_utf8_lossy(&resp[..n]).ends_with("\r\n\r\nidle"));
+
+    // Answered long before the keep-alive timeout
+    let start = std::time::Instant::now();
+    assert!(get(address, "/echo/next").ends_with("\r\n\r\nnext"));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(0, idle.read(&mut resp).unwrap());
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+fn times_out_slow_and_idle_clients(reactor: bool) {
+    let Ok(Command::Run(config)) = config().parse(["--header_timeout=1", "--keep_alive_timeout=1"])
+    else {
+        panic!("expected a config");
+    };
+    let server = Server::try_new(&Config { reactor, ..config }).unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let stats = server.stats();
+    let running = thread::spawn(move || server.start());
+
+    // Half a head and then nothing
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .write_all(b"GET /echo/slow HTTP/1.1\r\nHost: ")
+        .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    assert!(resp.contains("Connection: close\r\n"));
+
+    // Kept alive after one request, then closed without a word once idle
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .write_all(b"GET /echo/idle HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let mut resp = [0; 512];
+    let n = stream.read(&mut resp).unwrap();
+    assert!(String::from_utf8_lossy(&resp[..n]).ends_with("\r\n\r\nidle"));
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(2, stats.timeouts());
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn times_out_slow_and_idle_clients_on_workers() {
+    times_out_slow_and_idle_clients(false);
+}
+
+#[cfg(feature = "reactor")]
+#[test]
+fn times_out_slow_and_idle_clients_in_reactor_mode() {
+    times_out_slow_and_idle_clients(true);
+}
+
+#[cfg(feature = "reactor")]
+#[test]
+fn holds_many_idle_connections_in_reactor_mode() {
+    let server = Server::try_new(&Config {
+        reactor: true,
+        queue_depth: 4,
+        ..config()
+    })
+    .unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+
+    // Far more keep-alive connections than workers, each of which would hold
+    // on to a worker in the default mode
+    let mut streams: Vec<TcpStream> = (0..200)
+        .map(|_| TcpStream::connect(address).unwrap())
+        .collect();
+    for _ in 0..2 {
+        for (i, stream) in streams.iter_mut().enumerate() {
+            let req = format!("GET /echo/{} HTTP/1.1\r\n\r\n", i);
+            stream.write_all(req.as_bytes()).unwrap();
+            let mut resp = [0; 512];
+            let n = stream.read(&mut resp).unwrap();
+            assert!(String::from_utf8_lossy(&resp[..n]).ends_with(&format!("\r\n\r\n{}", i)));
+        }
+    }
+
+    // Two pipelined requests, the second with a body
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .write_all(
+            b"GET /echo/first HTTP/1.1\r\n\r\n\
+              GET /echo/second HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbody",
+        )
+        .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.contains("\r\n\r\nfirst"));
+    assert!(resp.ends_with("\r\n\r\nsecond"));
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[cfg(feature = "reactor")]
+#[test]
+fn turns_away_connections_when_the_reactor_is_full() {
+    let server = Server::try_new(&Config {
+        reactor: true,
+        max_parked: 2,
+        ..config()
+    })
+    .unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.start());
+
+    // Accepted in order, so these two are waiting before the third arrives
+    let idle: Vec<TcpStream> = (0..2)
+        .map(|_| TcpStream::connect(address).unwrap())
+        .collect();
+    let mut stream = TcpStream::connect(address).unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+    drop(idle);
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+// A fresh self-signed certificate for localhost, returning it with the files
+// it and its key were written to
+#[cfg(feature = "tls")]
+fn certificate(name: &str) -> (String, String, Vec<u8>) {
+    let dir = std::env::temp_dir();
+    let cert = dir.join(format!("http-{}-{}.crt", name, std::process::id()));
+    let key = dir.join(format!("http-{}-{}.key", name, std::process::id()));
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    std::fs::write(&cert, generated.cert.pem()).unwrap();
+    std::fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+    (
+        cert.display().to_string(),
+        key.display().to_string(),
+        generated.cert.der().to_vec(),
+    )
+}
+
+// GETs path over TLS from a client that only trusts the given certificate
+#[cfg(feature = "tls")]
+fn get_https(address: SocketAddr, path: &str, trusted: &[u8]) -> std::io::Result<String> {
+    let head = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+    https(address, &head, trusted, None)
+}
+
+// A client certificate and its key, as DER
+#[cfg(feature = "tls")]
+type Identity = (Vec<u8>, Vec<u8>);
+
+// Sends a request over TLS, with a client certificate and its key if given
+#[cfg(feature = "tls")]
+fn https(
+    address: SocketAddr,
+    req: &str,
+    trusted: &[u8],
+    client: Option<&Identity>,
+) -> std::io::Result<String> {
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from(trusted.to_vec())).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    let config = match client {
+        Some((cert, key)) => config
+            .with_client_auth_cert(
+                vec![CertificateDer::from(cert.clone())],
+                PrivateKeyDer::try_from(key.clone()).unwrap(),
+            )
+            .unwrap(),
+        None => config.with_no_client_auth(),
+    };
+    let name = ServerName::try_from("localhost").unwrap();
+    let conn = ClientConnection::new(Arc::new(config), name).unwrap();
+    let mut stream = StreamOwned::new(conn, TcpStream::connect(address)?);
+    stream.write_all(req.as_bytes())?;
+    let mut resp = String::new();
+    // Fails without a close_notify at the end
+    stream.read_to_string(&mut resp)?;
+    Ok(resp)
+}
+
+#[cfg(feature = "tls")]
+fn serves_https(reactor: bool) {
+    let tls_config = |cert: &str, key: &str| {
+        let Ok(Command::Run(config)) = config().parse([
+            "--listen=127.0.0.1:0".to_owned(),
+            "--listen=tls:127.0.0.1:0".to_owned(),
+            format!("--tls_cert={}", cert),
+            format!("--tls_key={}", key),
+        ]) else {
+            panic!("expected a config");
+        };
+        Config { reactor, ..config }
+    };
+    let (cert, key, old) = certificate("https-old");
+    let server = Arc::new(Server::try_new(&tls_config(&cert, &key)).unwrap());
+    let addresses = server.local_addrs().unwrap();
+    let (ListenAddr::Tcp(plain), ListenAddr::Tls(secure)) = (&addresses[0], &addresses[1]) else {
+        panic!("expected a TCP and a TLS listener");
+    };
+    let (plain, secure) = (*plain, *secure);
+    let handle = server.shutdown_handle();
+    let running = thread::spawn({
+        let server = Arc::clone(&server);
+        move || server.start()
+    });
+
+    let resp = get_https(secure, "/echo/secret", &old).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.ends_with("\r\n\r\nsecret"));
+    assert!(get(plain, "/echo/plain").ends_with("\r\n\r\nplain"));
+    // Plain HTTP to the TLS listener gets at most an alert back
+    let mut stream = TcpStream::connect(secure).unwrap();
+    stream
+        .write_all(b"GET /echo/plain HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let mut resp = Vec::new();
+    let _ = stream.read_to_end(&mut resp);
+    assert!(!resp.starts_with(b"HTTP/1.1"));
+
+    // A renewed certificate is served once reloaded
+    let (new_cert, new_key, new) = certificate("https-new");
+    server.reload(tls_config(&new_cert, &new_key)).unwrap();
+    assert!(get_https(secure, "/echo/renewed", &new)
+        .unwrap()
+        .ends_with("\r\n\r\nrenewed"));
+    assert!(get_https(secure, "/echo/renewed", &old).is_err());
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+    for path in [cert, key, new_cert, new_key] {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn serves_https_on_workers() {
+    serves_https(false);
+}
+
+#[cfg(all(feature = "tls", feature = "reactor"))]
+#[test]
+fn serves_https_in_reactor_mode() {
+    serves_https(true);
+}
+
+// A CA, and a client certificate from it for each common name
+#[cfg(feature = "tls")]
+fn client_certificates(names: &[&str]) -> (rcgen::Certificate, Vec<Identity>) {
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+
+    let ca_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Test CA");
+    let ca = params.self_signed(&ca_key).unwrap();
+    let clients = names
+        .iter()
+        .map(|name| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.distinguished_name.push(DnType::CommonName, *name);
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            (cert.der().to_vec(), key.serialize_der())
+        })
+        .collect();
+    (ca, clients)
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn checks_client_certificates() {
+    let (cert, key, trusted) = certificate("mtls-server");
+    let (ca, clients) = client_certificates(&["ci-uploader", "intruder"]);
+    let (_, strangers) = client_certificates(&["ci-uploader"]);
+    let ca_file = std::env::temp_dir().join(format!("http-mtls-ca-{}.crt", std::process::id()));
+    std::fs::write(&ca_file, ca.pem()).unwrap();
+    let mtls_config = |client_auth: &str| {
+        let Ok(Command::Run(config)) = config().parse([
+            "--listen=tls:127.0.0.1:0".to_owned(),
+            format!("--tls_cert={}", cert),
+            format!("--tls_key={}", key),
+            format!("--tls_client_ca={}", ca_file.display()),
+            format!("--tls_client_auth={}", client_auth),
+            "--access=/files:method=POST,cert=CN=ci-uploader".to_owned(),
+        ]) else {
+            panic!("expected a config");
+        };
+        config
+    };
+    let server = Server::try_new(&mtls_config("optional")).unwrap();
+    let ListenAddr::Tls(address) = server.local_addrs().unwrap()[0] else {
+        panic!("expected a TLS listener");
+    };
+    let handle = server.shutdown_handle();
+    let server = Arc::new(server);
+    let running = thread::spawn({
+        let server = Arc::clone(&server);
+        move || server.start()
+    });
+
+    let upload =
+        "POST /files/mtls-upload HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+    let (uploader, intruder) = (&clients[0], &clients[1]);
+    let resp = https(address, upload, &trusted, Some(uploader)).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 201 Created\r\n"), "{}", resp);
+    let resp = https(address, upload, &trusted, Some(intruder)).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    let resp = https(address, upload, &trusted, None).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    // Optional, so anything else is open to clients without one
+    assert!(get_https(address, "/echo/anyone", &trusted)
+        .unwrap()
+        .ends_with("\r\n\r\nanyone"));
+    // The right name from the wrong CA doesn't get past the handshake
+    assert!(https(address, upload, &trusted, Some(&strangers[0])).is_err());
+
+    server.reload(mtls_config("required")).unwrap();
+    assert!(get_https(address, "/echo/anyone", &trusted).is_err());
+    let head = "GET /echo/ci HTTP/1.1\r\nConnection: close\r\n\r\n";
+    assert!(https(address, head, &trusted, Some(intruder))
+        .unwrap()
+        .ends_with("\r\n\r\nci"));
+
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+    let _ = std::fs::remove_file("/tmp/mtls-upload");
+    for path in [cert, key, ca_file.display().to_string()] {
+        let _ = std::fs::remove_file(path);
+    }
+}